@@ -294,6 +294,8 @@ pub fn parse_profile(file: &mut Read) -> MavProfile {
                     }
                     MavXmlElement::Entry => {
                         entry = Default::default();
+                        // Entries without a value attribute follow on from the previous one
+                        entry.value = mavenum.entries.last().map(|e| e.value + 1).unwrap_or(0);
                     }
                     MavXmlElement::Param => {
                         paramid = None;
@@ -459,6 +461,227 @@ pub fn extra_crc(msg: &MavMessage) -> u8 {
     ((crcval & 0xFF) ^ (crcval >> 8)) as u8
 }
 
+/// Field names for the parameters of each `MAV_CMD`, from param 1 to 7.
+///
+/// Parameters without a name here are called `param<n>`, or left out when their description
+/// marks them as empty or reserved.
+const COMMAND_PARAMS: &'static [(&'static str, [&'static str; 7])] = &[
+    ("MAV_CMD_NAV_WAYPOINT", ["hold_time", "acceptance_radius", "pass_through", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_LOITER_UNLIM", ["", "", "radius", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_LOITER_TURNS", ["turns", "", "radius", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_LOITER_TIME", ["time", "", "radius", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_LAND", ["abort_alt", "", "", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_TAKEOFF", ["minimum_pitch", "", "", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_LAND_LOCAL", ["target_number", "offset", "descend_rate", "yaw_angle", "y", "x", "z"]),
+    ("MAV_CMD_NAV_TAKEOFF_LOCAL", ["minimum_pitch", "", "ascend_rate", "yaw_angle", "y", "x", "z"]),
+    ("MAV_CMD_NAV_FOLLOW", ["following_logic", "ground_speed", "radius", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_CONTINUE_AND_CHANGE_ALT", ["", "", "", "", "", "", "alt"]),
+    ("MAV_CMD_NAV_LOITER_TO_ALT", ["heading_required", "radius", "", "", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_ROI", ["roi_mode", "mission_index", "roi_index", "", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_PATHPLANNING", ["local_planning", "global_planning", "", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_SPLINE_WAYPOINT", ["hold_time", "", "", "", "lat", "lon", "alt"]),
+    ("MAV_CMD_NAV_GUIDED_ENABLE", ["enable", "", "", "", "", "", ""]),
+    ("MAV_CMD_CONDITION_DELAY", ["delay", "", "", "", "", "", ""]),
+    ("MAV_CMD_CONDITION_CHANGE_ALT", ["rate", "", "", "", "", "", "alt"]),
+    ("MAV_CMD_CONDITION_DISTANCE", ["distance", "", "", "", "", "", ""]),
+    ("MAV_CMD_CONDITION_YAW", ["angle", "speed", "direction", "relative", "", "", ""]),
+    ("MAV_CMD_DO_SET_MODE", ["mode", "custom_mode", "custom_sub_mode", "", "", "", ""]),
+    ("MAV_CMD_DO_JUMP", ["sequence_number", "repeat_count", "", "", "", "", ""]),
+    ("MAV_CMD_DO_CHANGE_SPEED", ["speed_type", "speed", "throttle", "", "", "", ""]),
+    ("MAV_CMD_DO_SET_HOME", ["use_current", "", "", "", "lat", "lon", "alt"]),
+    ("MAV_CMD_DO_SET_PARAMETER", ["parameter_number", "parameter_value", "", "", "", "", ""]),
+    ("MAV_CMD_DO_SET_RELAY", ["relay_number", "setting", "", "", "", "", ""]),
+    ("MAV_CMD_DO_REPEAT_RELAY", ["relay_number", "cycle_count", "cycle_time", "", "", "", ""]),
+    ("MAV_CMD_DO_SET_SERVO", ["servo_number", "pwm", "", "", "", "", ""]),
+    ("MAV_CMD_DO_REPEAT_SERVO", ["servo_number", "pwm", "cycle_count", "cycle_time", "", "", ""]),
+    ("MAV_CMD_DO_FLIGHTTERMINATION", ["terminate", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_LAND_START", ["", "", "", "", "lat", "lon", ""]),
+    ("MAV_CMD_DO_RALLY_LAND", ["break_alt", "landing_speed", "", "", "", "", ""]),
+    ("MAV_CMD_DO_GO_AROUND", ["alt", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_CONTROL_VIDEO", ["camera_id", "transmission", "transmission_mode", "recording", "", "", ""]),
+    ("MAV_CMD_DO_SET_ROI", ["roi_mode", "mission_index", "roi_index", "", "lat", "lon", "alt"]),
+    ("MAV_CMD_DO_DIGICAM_CONFIGURE",
+     ["mode", "shutter_speed", "aperture", "iso", "exposure_type", "command_identity", "engine_cutoff_time"]),
+    ("MAV_CMD_DO_DIGICAM_CONTROL",
+     ["session_control", "zoom_position", "zoom_step", "focus_lock", "shoot", "command_identity", ""]),
+    ("MAV_CMD_DO_MOUNT_CONFIGURE", ["mount_mode", "stabilize_roll", "stabilize_pitch", "stabilize_yaw", "", "", ""]),
+    ("MAV_CMD_DO_MOUNT_CONTROL", ["pitch", "roll", "yaw", "", "", "", "mount_mode"]),
+    ("MAV_CMD_DO_SET_CAM_TRIGG_DIST", ["distance", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_FENCE_ENABLE", ["enable", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_PARACHUTE", ["action", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_INVERTED_FLIGHT", ["inverted", "", "", "", "", "", ""]),
+    ("MAV_CMD_DO_MOUNT_CONTROL_QUAT", ["q1", "q2", "q3", "q4", "", "", ""]),
+    ("MAV_CMD_DO_GUIDED_MASTER", ["system_id", "component_id", "", "", "", "", ""]),
+    ("MAV_CMD_DO_GUIDED_LIMITS", ["timeout", "alt_min", "alt_max", "horizontal_move_limit", "", "", ""]),
+    ("MAV_CMD_PREFLIGHT_CALIBRATION",
+     ["gyro", "magnetometer", "ground_pressure", "radio", "accelerometer", "compass_motor", ""]),
+    ("MAV_CMD_PREFLIGHT_SET_SENSOR_OFFSETS",
+     ["sensor", "x_offset", "y_offset", "z_offset", "offset4", "offset5", "offset6"]),
+    ("MAV_CMD_PREFLIGHT_UAVCAN", ["actuator_id", "", "", "", "", "", ""]),
+    ("MAV_CMD_PREFLIGHT_STORAGE", ["parameter_storage", "mission_storage", "logging", "", "", "", ""]),
+    ("MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN", ["autopilot", "companion", "", "", "", "", ""]),
+    ("MAV_CMD_OVERRIDE_GOTO", ["continue_mission", "hold_position", "frame", "yaw_angle", "lat", "lon", "alt"]),
+    ("MAV_CMD_MISSION_START", ["first_item", "last_item", "", "", "", "", ""]),
+    ("MAV_CMD_COMPONENT_ARM_DISARM", ["arm", "", "", "", "", "", ""]),
+    ("MAV_CMD_START_RX_PAIR", ["spektrum", "spektrum_type", "", "", "", "", ""]),
+    ("MAV_CMD_GET_MESSAGE_INTERVAL", ["message_id", "", "", "", "", "", ""]),
+    ("MAV_CMD_SET_MESSAGE_INTERVAL", ["message_id", "interval", "", "", "", "", ""]),
+    ("MAV_CMD_REQUEST_AUTOPILOT_CAPABILITIES", ["version", "", "", "", "", "", ""]),
+    ("MAV_CMD_IMAGE_START_CAPTURE", ["interval", "count", "resolution", "", "", "", ""]),
+    ("MAV_CMD_DO_TRIGGER_CONTROL", ["enable", "shutter_integration_time", "", "", "", "", ""]),
+    ("MAV_CMD_VIDEO_START_CAPTURE", ["camera_id", "frames_per_second", "resolution", "", "", "", ""]),
+    ("MAV_CMD_PANORAMA_CREATE", ["horizontal_angle", "vertical_angle", "horizontal_speed", "vertical_speed", "", "", ""]),
+    ("MAV_CMD_DO_VTOL_TRANSITION", ["state", "", "", "", "", "", ""]),
+    ("MAV_CMD_PAYLOAD_PREPARE_DEPLOY",
+     ["operation_mode", "approach_vector", "ground_speed", "altitude_clearance", "lat", "lon", "alt"]),
+    ("MAV_CMD_PAYLOAD_CONTROL_DEPLOY", ["operation_mode", "", "", "", "", "", ""]),
+];
+
+/// Range markers in `MAV_CMD` rather than commands.
+const COMMAND_SENTINELS: &'static [&'static str] = &[
+    "MAV_CMD_NAV_LAST", "MAV_CMD_CONDITION_LAST", "MAV_CMD_DO_LAST",
+];
+
+/// The `MAV_CMD` entries that are actual commands.
+fn command_entries(mavenum: &MavEnum) -> Vec<&MavEnumEntry> {
+    mavenum.entries.iter().filter(|entry| !COMMAND_SENTINELS.contains(&&entry.name[..])).collect()
+}
+
+/// `MAV_CMD_NAV_TAKEOFF` becomes `NavTakeoff`.
+fn command_variant_name(name: &str) -> String {
+    let name = name.trim_left_matches("MAV_CMD_");
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// The named parameters of a `MAV_CMD` entry as (index, name, description).
+fn command_fields(entry: &MavEnumEntry) -> Vec<(usize, String, String)> {
+    let names = COMMAND_PARAMS.iter().find(|&&(command, _)| command == entry.name).map(|&(_, names)| names);
+    let mut fields = vec![];
+    if let Some(ref params) = entry.params {
+        for (i, description) in params.iter().enumerate() {
+            let description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+            let name = match names.map(|names| names[i]) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => {
+                    let lower = description.to_lowercase();
+                    if lower.is_empty() || lower.starts_with("empty") || lower.starts_with("reserved") {
+                        continue;
+                    }
+                    format!("param{}", i + 1)
+                }
+            };
+            fields.push((i + 1, name, description));
+        }
+    }
+    fields
+}
+
+/// Latitude and longitude need more precision than `f32` to survive `COMMAND_INT`.
+fn command_field_type(name: &str) -> &'static str {
+    if name == "lat" || name == "lon" { "f64" } else { "f32" }
+}
+
+#[allow(unused_must_use)]
+fn generate_commands<W: Write>(mavenum: &MavEnum, output: &mut W) {
+    writeln!(output, "/// A `MAV_CMD` with named parameters.");
+    writeln!(output, "///");
+    writeln!(output, "/// Parameters without a known meaning are called `param<n>`. Those described as empty or");
+    writeln!(output, "/// reserved are left out and sent as zero. Latitudes and longitudes are `f64`.");
+    writeln!(output, "#[derive(Clone, Debug, PartialEq)]");
+    writeln!(output, "pub enum Command {{");
+    for entry in command_entries(mavenum) {
+        if let Some(ref description) = entry.description {
+            writeln!(output, "    /// {}", description.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+        let fields = command_fields(entry);
+        if fields.is_empty() {
+            writeln!(output, "    {},", command_variant_name(&entry.name));
+            continue;
+        }
+        writeln!(output, "    {} {{", command_variant_name(&entry.name));
+        for &(index, ref name, ref description) in &fields {
+            writeln!(output, "        /// Param {}: {}", index, description);
+            writeln!(output, "        {}: {},", name, command_field_type(name));
+        }
+        writeln!(output, "    }},");
+    }
+    writeln!(output, "}}");
+    writeln!(output, "");
+
+    writeln!(output, "impl Command {{");
+    writeln!(output, "    /// The `MAV_CMD` value of the command.");
+    writeln!(output, "    pub fn command(&self) -> u16 {{");
+    writeln!(output, "        match *self {{");
+    for entry in command_entries(mavenum) {
+        let pattern = if command_fields(entry).is_empty() { "" } else { " { .. }" };
+        writeln!(output, "            Command::{}{} => {},",
+                 command_variant_name(&entry.name),
+                 pattern,
+                 entry.value);
+    }
+    writeln!(output, "        }}");
+    writeln!(output, "    }}");
+    writeln!(output, "");
+    writeln!(output, "    /// Build a command from its `MAV_CMD` value and seven parameters.");
+    writeln!(output, "    pub fn from_params(command: u16, params: [f64; 7]) -> Option<Command> {{");
+    writeln!(output, "        match command {{");
+    for entry in command_entries(mavenum) {
+        let fields = command_fields(entry);
+        let name = command_variant_name(&entry.name);
+        if fields.is_empty() {
+            writeln!(output, "            {} => Some(Command::{}),", entry.value, name);
+            continue;
+        }
+        writeln!(output, "            {} => Some(Command::{} {{", entry.value, name);
+        for &(index, ref field, _) in &fields {
+            match command_field_type(field) {
+                "f64" => writeln!(output, "                {}: params[{}],", field, index - 1),
+                t => writeln!(output, "                {}: params[{}] as {},", field, index - 1, t),
+            };
+        }
+        writeln!(output, "            }}),");
+    }
+    writeln!(output, "            _ => None,");
+    writeln!(output, "        }}");
+    writeln!(output, "    }}");
+    writeln!(output, "");
+    writeln!(output, "    /// The seven parameters of the command, zero where unused.");
+    writeln!(output, "    pub fn params(&self) -> [f64; 7] {{");
+    writeln!(output, "        match *self {{");
+    for entry in command_entries(mavenum) {
+        let fields = command_fields(entry);
+        let name = command_variant_name(&entry.name);
+        if fields.is_empty() {
+            writeln!(output, "            Command::{} => [0.0; 7],", name);
+            continue;
+        }
+        let names: Vec<_> = fields.iter().map(|&(_, ref field, _)| field.clone()).collect();
+        let params: Vec<_> = (1..8)
+            .map(|i| match fields.iter().find(|&&(index, _, _)| index == i) {
+                Some(&(_, ref field, _)) if command_field_type(field) == "f64" => field.clone(),
+                Some(&(_, ref field, _)) => format!("{} as f64", field),
+                None => "0.0".into(),
+            })
+            .collect();
+        writeln!(output, "            Command::{} {{ {} }} => [{}],",
+                 name,
+                 names.join(", "),
+                 params.join(", "));
+    }
+    writeln!(output, "        }}");
+    writeln!(output, "    }}");
+    writeln!(output, "}}");
+    writeln!(output, "");
+}
+
 #[allow(unused_must_use)] // TODO fix
 pub fn generate_mod<R: Read, W: Write>(input: &mut R, output: &mut W) {
     let profile = parse_profile(input);
@@ -477,6 +700,32 @@ pub fn generate_mod<R: Read, W: Write>(input: &mut R, output: &mut W) {
     writeln!(output, "}}");
     writeln!(output, "");
 
+    for item in &profile.enums {
+        writeln!(output, "#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]");
+        writeln!(output, "pub enum {} {{", item.name);
+        for entry in &item.entries {
+            writeln!(output, "    {} = {},", entry.name, entry.value);
+        }
+        writeln!(output, "}}");
+        writeln!(output, "");
+
+        writeln!(output, "impl {} {{", item.name);
+        writeln!(output, "    pub fn from_u32(value: u32) -> Option<{}> {{", item.name);
+        writeln!(output, "        match value {{");
+        for entry in &item.entries {
+            writeln!(output, "            {} => Some({}::{}),", entry.value, item.name, entry.name);
+        }
+        writeln!(output, "            _ => None,");
+        writeln!(output, "        }}");
+        writeln!(output, "    }}");
+        writeln!(output, "}}");
+        writeln!(output, "");
+    }
+
+    if let Some(mavenum) = profile.enums.iter().find(|e| e.name == "MAV_CMD") {
+        generate_commands(mavenum, output);
+    }
+
     for item in &profile.messages {
         let mut f = item.fields.clone();
         f.sort_by(|a, b| a.mavtype.compare(&b.mavtype));
@@ -519,13 +768,14 @@ pub fn generate_mod<R: Read, W: Write>(input: &mut R, output: &mut W) {
                             MavType::UInt8 |
                             MavType::Int8 |
                             MavType::UInt8MavlinkVersion => {
-                                println!("                cur.read_{}().unwrap(),", t.rust_type());
+                                writeln!(output, "                cur.read_{}().unwrap(),",
+                                         t.rust_type());
                             }
                             MavType::Array(_, _) => {
                                 panic!("error");
                             }
                             _ => {
-                                println!("                cur.read_{}::<LittleEndian>().unwrap(),",
+                                writeln!(output, "                cur.read_{}::<LittleEndian>().unwrap(),",
                                          t.rust_type());
                             }
                         }
@@ -622,6 +872,31 @@ pub fn generate_mod<R: Read, W: Write>(input: &mut R, output: &mut W) {
     writeln!(output, "        }}");
     writeln!(output, "    }}");
     writeln!(output, "");
+    for target in &["target_system", "target_component"] {
+        writeln!(output, "    pub fn {}(&self) -> Option<u8> {{", target);
+        writeln!(output, "        match self {{");
+        for item in &profile.messages {
+            if item.fields.iter().any(|f| f.name == *target) {
+                writeln!(output, "            &MavMessage::{}(ref body) => Some(body.{}),",
+                         item.name,
+                         target);
+            }
+        }
+        writeln!(output, "            _ => None,");
+        writeln!(output, "        }}");
+        writeln!(output, "    }}");
+        writeln!(output, "");
+    }
+    writeln!(output, "    pub fn payload_len(id: u8) -> Option<usize> {{");
+    writeln!(output, "        match id {{");
+    for item in &profile.messages {
+        let len: usize = item.fields.iter().map(|f| f.mavtype.len()).sum();
+        writeln!(output, "            {} => Some({}),", item.id, len);
+    }
+    writeln!(output, "            _ => None,");
+    writeln!(output, "        }}");
+    writeln!(output, "    }}");
+    writeln!(output, "");
     writeln!(output, "    pub fn extra_crc(id: u8) -> u8 {{");
     writeln!(output, "        match id {{");
     for item in &profile.messages {
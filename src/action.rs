@@ -0,0 +1,353 @@
+use common::{self, Command, MavMessage, MAV_CMD, MAV_FRAME, MAV_LANDED_STATE, MAV_MISSION_RESULT, MAV_RESULT};
+use command::{CommandClient, CommandError, FORCE_DISARM};
+use connection::MavConnection;
+use geo::distance;
+use mission::MissionItem;
+use mode::Firmware;
+use vehicle::Vehicle;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Failure of an action.
+#[derive(Debug)]
+pub enum ActionError {
+    Io(io::Error),
+    /// The vehicle did not acknowledge the request.
+    Timeout,
+    /// The vehicle refused the command.
+    Rejected(MAV_RESULT),
+    /// The vehicle refused the guided waypoint of `goto`.
+    GotoRejected(Option<MAV_MISSION_RESULT>),
+    /// The `COMMAND_ACK` carried a result code that is not a known `MAV_RESULT`.
+    UnknownResult(u8),
+    /// The request was accepted, but the vehicle did not reach the expected state in time.
+    StateTimeout,
+    /// The vehicle's autopilot or type has no known mode numbering.
+    UnknownFirmware,
+    /// The mode name is not known for the vehicle's firmware.
+    UnknownMode(String),
+}
+
+impl From<io::Error> for ActionError {
+    fn from(e: io::Error) -> ActionError {
+        ActionError::Io(e)
+    }
+}
+
+impl From<CommandError> for ActionError {
+    fn from(e: CommandError) -> ActionError {
+        match e {
+            CommandError::Io(e) => ActionError::Io(e),
+            CommandError::Timeout => ActionError::Timeout,
+            CommandError::UnknownResult(r) => ActionError::UnknownResult(r),
+        }
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ActionError::Io(ref e) => write!(f, "{}", e),
+            ActionError::Timeout => write!(f, "vehicle did not acknowledge the request"),
+            ActionError::Rejected(r) => write!(f, "vehicle rejected the command with {:?}", r),
+            ActionError::GotoRejected(Some(r)) => write!(f, "vehicle rejected the waypoint with {:?}", r),
+            ActionError::GotoRejected(None) => write!(f, "vehicle rejected the waypoint"),
+            ActionError::UnknownResult(r) => write!(f, "command acknowledged with unknown result {}", r),
+            ActionError::StateTimeout => write!(f, "vehicle did not reach the expected state"),
+            ActionError::UnknownFirmware => write!(f, "vehicle firmware is not known"),
+            ActionError::UnknownMode(ref name) => write!(f, "unknown mode {}", name),
+        }
+    }
+}
+
+impl Error for ActionError {
+    fn description(&self) -> &str {
+        "action failed"
+    }
+}
+
+/// High-level actions on a single vehicle.
+///
+/// Each action sends its command, waits for the vehicle to accept it, and then waits for the
+/// vehicle's `HEARTBEAT`, `EXTENDED_SYS_STATE` or `GLOBAL_POSITION_INT` to show the result. Only
+/// telemetry received after the command was accepted counts. The client reads from the
+/// connection only while an action is running, so it must not be shared with other readers.
+pub struct ActionClient {
+    conn: Arc<MavConnection + Sync + Send>,
+    pub commands: CommandClient,
+    pub target_system: u8,
+    pub target_component: u8,
+    /// How long to wait for the vehicle to reach the state an action leads to. `land` waits
+    /// for touchdown and `goto` for arrival, so this has to cover those.
+    pub state_timeout: Duration,
+    /// Horizontal distance in meters from the target at which `goto` has arrived.
+    pub acceptance_radius: f64,
+    vehicle: Vehicle,
+    firmware: Option<Firmware>,
+}
+
+impl ActionClient {
+    pub fn new(conn: Arc<MavConnection + Sync + Send>, target_system: u8, target_component: u8) -> ActionClient {
+        ActionClient {
+            commands: CommandClient::new(conn.clone()),
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+            state_timeout: Duration::from_secs(30),
+            acceptance_radius: 2.0,
+            vehicle: Vehicle::new(target_system, target_component),
+            firmware: None,
+        }
+    }
+
+    /// The vehicle state as last seen during an action.
+    pub fn vehicle(&self) -> &Vehicle {
+        &self.vehicle
+    }
+
+    /// Read telemetry until `done` holds for the vehicle state.
+    fn wait<F: Fn(&Vehicle) -> bool>(&mut self, done: F) -> Result<(), ActionError> {
+        let deadline = Instant::now() + self.state_timeout;
+        while !done(&self.vehicle) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ActionError::StateTimeout);
+            }
+            match self.conn.recv_frame_timeout(deadline - now) {
+                Ok((header, msg)) => { self.vehicle.handle(&header, &msg); }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Err(ActionError::StateTimeout),
+                Err(e) => return Err(ActionError::Io(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the state that an action is about to change, so that only new telemetry counts.
+    fn forget_state(&mut self) {
+        self.vehicle.heartbeat = None;
+        self.vehicle.extended_sys_state = None;
+        self.vehicle.global_position = None;
+    }
+
+    /// Wait for a heartbeat, returning the firmware it identifies.
+    pub fn firmware(&mut self) -> Result<Firmware, ActionError> {
+        if let Some(firmware) = self.firmware {
+            return Ok(firmware);
+        }
+        try!(self.wait(|v| v.heartbeat.is_some()));
+        self.firmware = Firmware::from_heartbeat(&self.vehicle.heartbeat.as_ref().unwrap().value);
+        self.firmware.ok_or(ActionError::UnknownFirmware)
+    }
+
+    fn command_long(&mut self, cmd: common::COMMAND_LONG_DATA) -> Result<(), ActionError> {
+        match try!(self.commands.command_long(cmd)) {
+            MAV_RESULT::MAV_RESULT_ACCEPTED => {
+                self.forget_state();
+                Ok(())
+            }
+            result => Err(ActionError::Rejected(result)),
+        }
+    }
+
+    fn command(&mut self, command: &Command) -> Result<(), ActionError> {
+        let cmd = command.to_command_long(self.target_system, self.target_component);
+        self.command_long(cmd)
+    }
+
+    pub fn arm(&mut self) -> Result<(), ActionError> {
+        try!(self.command(&Command::ComponentArmDisarm { arm: 1.0 }));
+        self.wait(|v| v.armed() == Some(true))
+    }
+
+    /// Disarm, with `force` even when the vehicle thinks it is flying.
+    pub fn disarm(&mut self, force: bool) -> Result<(), ActionError> {
+        let mut cmd = Command::ComponentArmDisarm { arm: 0.0 }.to_command_long(self.target_system, self.target_component);
+        if force {
+            cmd.param2 = FORCE_DISARM;
+        }
+        try!(self.command_long(cmd));
+        self.wait(|v| v.armed() == Some(false))
+    }
+
+    /// Switch to a mode named as in `Firmware::custom_mode`, waiting for the heartbeat to show it.
+    pub fn set_mode(&mut self, name: &str) -> Result<(), ActionError> {
+        let firmware = try!(self.firmware());
+        let (custom_mode, command) = match (firmware.custom_mode(name), firmware.set_mode_command(name)) {
+            (Some(custom_mode), Some(command)) => (custom_mode, command),
+            _ => return Err(ActionError::UnknownMode(name.to_string())),
+        };
+        try!(self.command(&command));
+        self.wait_mode(firmware, custom_mode)
+    }
+
+    fn wait_mode(&mut self, firmware: Firmware, custom_mode: u32) -> Result<(), ActionError> {
+        let name = firmware.mode_name(custom_mode);
+        self.wait(|v| v.custom_mode().map_or(false, |mode| firmware.mode_name(mode) == name))
+    }
+
+    /// Take off to `alt` meters above home, waiting until the vehicle is in the air.
+    ///
+    /// ArduCopter only takes off in guided mode once armed. PX4 takes the altitude above mean
+    /// sea level, so for it the home altitude is added, waiting for telemetry to give it.
+    pub fn takeoff(&mut self, alt: f32) -> Result<(), ActionError> {
+        let alt = match self.firmware() {
+            Ok(Firmware::Px4) => {
+                try!(self.wait(|v| v.home_alt().is_some()));
+                self.vehicle.home_alt().unwrap() + alt
+            }
+            Ok(_) | Err(ActionError::UnknownFirmware) => alt,
+            Err(e) => return Err(e),
+        };
+        // PX4 takes NaN to mean the current position; ArduPilot ignores the position.
+        try!(self.command(&Command::NavTakeoff {
+            minimum_pitch: 0.0,
+            yaw_angle: ::std::f32::NAN,
+            lat: ::std::f64::NAN,
+            lon: ::std::f64::NAN,
+            alt: alt,
+        }));
+        self.wait(|v| match v.landed_state() {
+            Some(state) => state == MAV_LANDED_STATE::MAV_LANDED_STATE_IN_AIR,
+            None => v.relative_alt().map_or(false, |alt| alt > 1.0),
+        })
+    }
+
+    /// Land where the vehicle is, waiting for touchdown.
+    pub fn land(&mut self) -> Result<(), ActionError> {
+        try!(self.command(&Command::NavLand {
+            abort_alt: 0.0,
+            yaw_angle: ::std::f32::NAN,
+            lat: ::std::f64::NAN,
+            lon: ::std::f64::NAN,
+            alt: 0.0,
+        }));
+        // Vehicles that do not report their landed state disarm on touchdown.
+        self.wait(|v| match v.landed_state() {
+            Some(state) => state == MAV_LANDED_STATE::MAV_LANDED_STATE_ON_GROUND,
+            None => v.armed() == Some(false),
+        })
+    }
+
+    /// Return to launch, waiting for the heartbeat to show the return mode.
+    ///
+    /// Fails with `UnknownMode` before sending anything if the firmware has no `"RTL"` mode to wait for.
+    pub fn return_to_launch(&mut self) -> Result<(), ActionError> {
+        let firmware = try!(self.firmware());
+        let custom_mode = try!(firmware.custom_mode("RTL").ok_or(ActionError::UnknownMode("RTL".to_string())));
+        try!(self.command(&Command::NavReturnToLaunch));
+        self.wait_mode(firmware, custom_mode)
+    }
+
+    /// Fly to a position, with `alt` in meters above home, waiting for arrival.
+    ///
+    /// Uses ArduPilot's guided mode waypoint, a `MISSION_ITEM` that is flown at once rather
+    /// than stored, so the vehicle has to be flying in guided mode.
+    pub fn goto(&mut self, lat: f64, lon: f64, alt: f32) -> Result<(), ActionError> {
+        let mut item = MissionItem {
+            seq: 0,
+            frame: MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT as u8,
+            command: MAV_CMD::MAV_CMD_NAV_WAYPOINT as u16,
+            current: false,
+            autocontinue: false,
+            param1: 0.0,
+            param2: 0.0,
+            param3: 0.0,
+            param4: 0.0,
+            x: lat,
+            y: lon,
+            z: alt,
+        }.to_item_int(self.target_system, self.target_component);
+        // This value of `current` makes ArduPilot fly to the item in guided mode.
+        item.current = 2;
+        let msg = MavMessage::MISSION_ITEM_INT(item);
+
+        // Connections send as system 255, component 0, so only an ack addressed there is ours.
+        let acked = try!(self.commands.request(self.target_system, self.target_component, &msg, |m| match *m {
+            MavMessage::MISSION_ACK(ref ack) if ack.target_system == 255 && ack.target_component == 0 => {
+                Some(ack.mavtype)
+            }
+            _ => None,
+        }));
+        if acked != MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED as u8 {
+            return Err(ActionError::GotoRejected(MAV_MISSION_RESULT::from_u32(acked as u32)));
+        }
+
+        self.forget_state();
+        let radius = self.acceptance_radius;
+        self.wait(|v| match (v.position(), v.relative_alt()) {
+            (Some((lat_now, lon_now, _)), Some(alt_now)) => {
+                distance(lat_now, lon_now, lat, lon) <= radius && (alt_now - alt).abs() < 1.0
+            }
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_action {
+    use super::*;
+    use loopback::loopback;
+    use param::ParamValue;
+    use sim::SimVehicle;
+    use std::thread;
+
+    #[test]
+    pub fn test_actions() {
+        let (gcs, sim) = loopback();
+        let sim = thread::spawn(move || {
+            let mut vehicle = SimVehicle::new(1, 1, 47.0, 8.5, 500.0);
+            vehicle.heartbeat_interval = Duration::from_millis(50);
+            vehicle.telemetry_interval = Duration::from_millis(20);
+            vehicle.params.set("SIM_SPEED", ParamValue::Real32(50.0)).unwrap();
+            vehicle.params.set("SIM_CLIMB_RATE", ParamValue::Real32(20.0)).unwrap();
+            vehicle.run(&sim).unwrap_err();
+            vehicle
+        });
+
+        let mut actions = ActionClient::new(Arc::new(gcs), 1, 1);
+        actions.state_timeout = Duration::from_secs(5);
+        assert_eq!(actions.firmware().unwrap(), Firmware::ArduCopter);
+
+        match actions.set_mode("NOPE") {
+            Err(ActionError::UnknownMode(ref name)) => assert_eq!(name, "NOPE"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        actions.set_mode("guided").unwrap();
+        match actions.takeoff(10.0) {
+            Err(ActionError::Rejected(MAV_RESULT::MAV_RESULT_FAILED)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        actions.arm().unwrap();
+        actions.takeoff(10.0).unwrap();
+        match actions.disarm(false) {
+            Err(ActionError::Rejected(MAV_RESULT::MAV_RESULT_FAILED)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        actions.goto(47.0005, 8.5, 15.0).unwrap();
+        let (lat, _, _) = actions.vehicle().position().unwrap();
+        assert!((lat - 47.0005).abs() < 3e-5);
+
+        actions.land().unwrap();
+        assert_eq!(actions.vehicle().relative_alt(), Some(0.0));
+        match actions.goto(47.0, 8.5, 15.0) {
+            Err(ActionError::GotoRejected(Some(MAV_MISSION_RESULT::MAV_MISSION_ERROR))) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        actions.set_mode("GUIDED").unwrap();
+        actions.arm().unwrap();
+        actions.takeoff(5.0).unwrap();
+        actions.return_to_launch().unwrap();
+        assert_eq!(actions.vehicle().custom_mode(), Some(6));
+        actions.disarm(true).unwrap();
+
+        drop(actions);
+        assert!(!sim.join().unwrap().armed());
+    }
+}
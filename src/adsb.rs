@@ -0,0 +1,315 @@
+use common::{self, MavMessage, ADSB_ALTITUDE_TYPE, ADSB_EMITTER_TYPE, ADSB_FLAGS};
+use geo::offset;
+use Header;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// An aircraft known from `ADSB_VEHICLE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Traffic {
+    pub icao_address: u32,
+    /// Without trailing padding, `None` unless flagged valid.
+    pub callsign: Option<String>,
+    pub emitter_type: Option<ADSB_EMITTER_TYPE>,
+    pub altitude_type: Option<ADSB_ALTITUDE_TYPE>,
+    /// Degrees.
+    pub lat: f64,
+    pub lon: f64,
+    /// Meters above mean sea level.
+    pub altitude: f32,
+    /// Course over ground in degrees.
+    pub heading: f32,
+    /// Meters per second.
+    pub hor_velocity: f32,
+    /// Meters per second, positive up.
+    pub ver_velocity: f32,
+    pub squawk: u16,
+    /// `ADSB_FLAGS` bits.
+    pub flags: u16,
+    /// When the aircraft was last heard from, going by the receiver's time since last
+    /// communication.
+    pub last_seen: Instant,
+}
+
+impl Traffic {
+    pub fn from_message(data: &common::ADSB_VEHICLE_DATA) -> Traffic {
+        let callsign = if data.flags & ADSB_FLAGS::ADSB_FLAGS_VALID_CALLSIGN as u16 != 0 {
+            let len = data.callsign.iter().position(|&c| c == 0).unwrap_or(data.callsign.len());
+            Some(String::from_utf8_lossy(&data.callsign[..len]).trim_right().to_string())
+        } else {
+            None
+        };
+        Traffic {
+            icao_address: data.ICAO_address,
+            callsign: callsign,
+            emitter_type: ADSB_EMITTER_TYPE::from_u32(data.emitter_type as u32),
+            altitude_type: ADSB_ALTITUDE_TYPE::from_u32(data.altitude_type as u32),
+            lat: data.lat as f64 / 1e7,
+            lon: data.lon as f64 / 1e7,
+            altitude: data.altitude,
+            heading: data.heading as f32 / 100.0,
+            hor_velocity: data.hor_velocity,
+            ver_velocity: data.ver_velocity,
+            squawk: data.squawk,
+            flags: data.flags,
+            // Soon after boot the clock may not reach back that far.
+            last_seen: Instant::now().checked_sub(Duration::from_secs(data.tslc as u64)).unwrap_or_else(Instant::now),
+        }
+    }
+
+    pub fn has(&self, flag: ADSB_FLAGS) -> bool {
+        self.flags & flag as u16 != 0
+    }
+
+    /// North, east and up velocity in m/s, if heading and velocity are valid.
+    pub fn velocity(&self) -> Option<(f32, f32, f32)> {
+        if self.has(ADSB_FLAGS::ADSB_FLAGS_VALID_HEADING) && self.has(ADSB_FLAGS::ADSB_FLAGS_VALID_VELOCITY) {
+            let course = self.heading.to_radians();
+            Some((self.hor_velocity * course.cos(), self.hor_velocity * course.sin(), self.ver_velocity))
+        } else {
+            None
+        }
+    }
+
+    /// The closest point of approach to our own position, assuming both keep their velocity.
+    ///
+    /// `None` without valid coordinates, or if the reported values are not finite numbers.
+    /// Traffic without a valid velocity is taken to stand
+    /// still, and without a valid altitude only the horizontal distance is considered. The
+    /// altitude is compared with ours as is, so a pressure altitude may be off by the
+    /// difference between QNH and geometric altitude.
+    pub fn closest_approach(&self, own: &common::GLOBAL_POSITION_INT_DATA) -> Option<Approach> {
+        if !self.has(ADSB_FLAGS::ADSB_FLAGS_VALID_COORDS) {
+            return None;
+        }
+        let own_lat = own.lat as f64 / 1e7;
+        let own_lon = own.lon as f64 / 1e7;
+        let (north, east) = offset(own_lat, own_lon, self.lat, self.lon);
+        let (north, east) = (north as f32, east as f32);
+        let valid_altitude = self.has(ADSB_FLAGS::ADSB_FLAGS_VALID_ALTITUDE);
+        let up = if valid_altitude { self.altitude - own.alt as f32 / 1000.0 } else { 0.0 };
+
+        // Velocity of the traffic relative to us; ours is in cm/s, north, east and down.
+        let (vn, ve, vu) = self.velocity().unwrap_or((0.0, 0.0, 0.0));
+        let vn = vn - own.vx as f32 / 100.0;
+        let ve = ve - own.vy as f32 / 100.0;
+        let vu = if valid_altitude { vu + own.vz as f32 / 100.0 } else { 0.0 };
+
+        let speed2 = vn * vn + ve * ve + vu * vu;
+        let time = if speed2 > 0.0 {
+            (-(north * vn + east * ve + up * vu) / speed2).max(0.0)
+        } else {
+            0.0
+        };
+        let (north, east, up) = (north + vn * time, east + ve * time, up + vu * time);
+        let horizontal = (north * north + east * east).sqrt();
+        if !time.is_finite() || !horizontal.is_finite() || !up.is_finite() {
+            return None;
+        }
+        Some(Approach {
+            time: time,
+            horizontal: horizontal,
+            vertical: if valid_altitude { Some(up) } else { None },
+        })
+    }
+}
+
+/// The closest point of approach between the traffic and us.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Approach {
+    /// Seconds from now, 0 if the traffic is moving away.
+    pub time: f32,
+    /// Horizontal distance at that time in meters.
+    pub horizontal: f32,
+    /// Height of the traffic above us at that time in meters, if its altitude is known.
+    pub vertical: Option<f32>,
+}
+
+/// A change in the traffic table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficEvent {
+    /// The first `ADSB_VEHICLE` for an ICAO address.
+    New(u32),
+    /// Nothing was heard from the aircraft within the timeout, and it was removed.
+    Expired(u32),
+}
+
+/// Tracks the aircraft reported in `ADSB_VEHICLE`, together with our own vehicle's
+/// `GLOBAL_POSITION_INT` to find their closest approach.
+///
+/// The tracker does not read from a connection itself: pass it every received frame with
+/// `handle`, and call `check` regularly to expire aircraft that are no longer heard.
+pub struct TrafficTracker {
+    /// The system whose `GLOBAL_POSITION_INT` is our own position.
+    pub system_id: u8,
+    /// How long an aircraft is kept without news.
+    pub timeout: Duration,
+    traffic: HashMap<u32, Traffic>,
+    own: Option<common::GLOBAL_POSITION_INT_DATA>,
+}
+
+impl TrafficTracker {
+    pub fn new(system_id: u8, timeout: Duration) -> TrafficTracker {
+        TrafficTracker {
+            system_id: system_id,
+            timeout: timeout,
+            traffic: HashMap::new(),
+            own: None,
+        }
+    }
+
+    /// Record a received message, returning an event if it reports a new aircraft.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Option<TrafficEvent> {
+        match *msg {
+            MavMessage::ADSB_VEHICLE(ref data) => {
+                let traffic = Traffic::from_message(data);
+                let icao_address = traffic.icao_address;
+                match self.traffic.insert(icao_address, traffic) {
+                    Some(_) => None,
+                    None => Some(TrafficEvent::New(icao_address)),
+                }
+            }
+            MavMessage::GLOBAL_POSITION_INT(ref position) if header.system_id == self.system_id => {
+                self.own = Some(position.clone());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Remove aircraft not heard from within the timeout, returning an event for each.
+    pub fn check(&mut self) -> Vec<TrafficEvent> {
+        let timeout = self.timeout;
+        let expired: Vec<u32> = self.traffic
+            .values()
+            .filter(|traffic| traffic.last_seen.elapsed() > timeout)
+            .map(|traffic| traffic.icao_address)
+            .collect();
+        for icao_address in &expired {
+            self.traffic.remove(icao_address);
+        }
+        expired.into_iter().map(TrafficEvent::Expired).collect()
+    }
+
+    pub fn get(&self, icao_address: u32) -> Option<&Traffic> {
+        self.traffic.get(&icao_address)
+    }
+
+    pub fn traffic(&self) -> Vec<&Traffic> {
+        self.traffic.values().collect()
+    }
+
+    /// Our own position, as last received.
+    pub fn own_position(&self) -> Option<&common::GLOBAL_POSITION_INT_DATA> {
+        self.own.as_ref()
+    }
+
+    pub fn closest_approach(&self, icao_address: u32) -> Option<Approach> {
+        match (self.traffic.get(&icao_address), self.own.as_ref()) {
+            (Some(traffic), Some(own)) => traffic.closest_approach(own),
+            _ => None,
+        }
+    }
+
+    /// The closest approach of every aircraft with known coordinates, soonest first.
+    pub fn approaches(&self) -> Vec<(u32, Approach)> {
+        let own = match self.own {
+            Some(ref own) => own,
+            None => return Vec::new(),
+        };
+        let mut approaches: Vec<(u32, Approach)> = self.traffic
+            .values()
+            .filter_map(|traffic| traffic.closest_approach(own).map(|approach| (traffic.icao_address, approach)))
+            .collect();
+        approaches.sort_by(|a, b| a.1.time.partial_cmp(&b.1.time).unwrap_or(Ordering::Equal));
+        approaches
+    }
+}
+
+#[cfg(test)]
+mod test_adsb {
+    use super::*;
+    use geo::METERS_PER_DEGREE;
+    use std::thread;
+
+    const VALID: u16 = ADSB_FLAGS::ADSB_FLAGS_VALID_COORDS as u16 | ADSB_FLAGS::ADSB_FLAGS_VALID_ALTITUDE as u16 |
+        ADSB_FLAGS::ADSB_FLAGS_VALID_HEADING as u16 | ADSB_FLAGS::ADSB_FLAGS_VALID_VELOCITY as u16 |
+        ADSB_FLAGS::ADSB_FLAGS_VALID_CALLSIGN as u16;
+
+    fn adsb(icao_address: u32, lat: f64, lon: f64, altitude: f32, heading: u16, speed: f32) -> MavMessage {
+        MavMessage::ADSB_VEHICLE(common::ADSB_VEHICLE_DATA {
+            ICAO_address: icao_address,
+            lat: (lat * 1e7) as i32,
+            lon: (lon * 1e7) as i32,
+            altitude: altitude,
+            hor_velocity: speed,
+            ver_velocity: 0.0,
+            heading: heading,
+            flags: VALID,
+            squawk: 7000,
+            altitude_type: ADSB_ALTITUDE_TYPE::ADSB_ALTITUDE_TYPE_GEOMETRIC as u8,
+            callsign: b"SWR123  \0".to_vec(),
+            emitter_type: ADSB_EMITTER_TYPE::ADSB_EMITTER_TYPE_LARGE as u8,
+            tslc: 0,
+        })
+    }
+
+    #[test]
+    pub fn test_track() {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut tracker = TrafficTracker::new(1, Duration::from_millis(50));
+
+        // An aircraft 1 km north of us at the same altitude, flying south at 50 m/s.
+        let msg = adsb(0x4b1814, 47.0 + 1000.0 / METERS_PER_DEGREE, 8.5, 500.0, 18000, 50.0);
+        assert_eq!(tracker.handle(&header, &msg), Some(TrafficEvent::New(0x4b1814)));
+        assert_eq!(tracker.handle(&header, &msg), None);
+        assert_eq!(tracker.closest_approach(0x4b1814), None);
+
+        let traffic = tracker.get(0x4b1814).unwrap().clone();
+        assert_eq!(traffic.callsign, Some("SWR123".to_string()));
+        assert_eq!(traffic.emitter_type, Some(ADSB_EMITTER_TYPE::ADSB_EMITTER_TYPE_LARGE));
+        assert_eq!(traffic.altitude_type, Some(ADSB_ALTITUDE_TYPE::ADSB_ALTITUDE_TYPE_GEOMETRIC));
+        assert!(!traffic.has(ADSB_FLAGS::ADSB_FLAGS_SIMULATED));
+
+        // We are flying east at 10 m/s, 100 m below it.
+        let own = MavMessage::GLOBAL_POSITION_INT(common::GLOBAL_POSITION_INT_DATA {
+            time_boot_ms: 0,
+            lat: 470000000,
+            lon: 85000000,
+            alt: 400000,
+            relative_alt: 0,
+            vx: 0,
+            vy: 1000,
+            vz: 0,
+            hdg: 9000,
+        });
+        tracker.handle(&Header { system_id: 2, ..header }, &own);
+        assert!(tracker.own_position().is_none());
+        tracker.handle(&header, &own);
+
+        let approach = tracker.closest_approach(0x4b1814).unwrap();
+        // Relative velocity (-50, -10) over 1000 m: t = 50000 / 2600 s.
+        assert!((approach.time - 19.23).abs() < 0.05, "{:?}", approach);
+        assert!((approach.horizontal - 196.1).abs() < 0.5, "{:?}", approach);
+        assert!((approach.vertical.unwrap() - 100.0).abs() < 0.01);
+
+        // A receding aircraft is closest now, and comes after the approaching one.
+        tracker.handle(&header, &adsb(0x3c6444, 47.0 - 0.01, 8.5, 500.0, 18000, 50.0));
+        let approaches = tracker.approaches();
+        assert_eq!(approaches.len(), 2);
+        assert_eq!((approaches[0].0, approaches[0].1.time), (0x3c6444, 0.0));
+        assert_eq!(approaches[1].0, 0x4b1814);
+
+        thread::sleep(Duration::from_millis(60));
+        tracker.handle(&header, &msg);
+        assert_eq!(tracker.check(), vec![TrafficEvent::Expired(0x3c6444)]);
+        assert_eq!(tracker.traffic().len(), 1);
+
+        // Values that are not numbers leave an aircraft out rather than failing the sort.
+        tracker.handle(&header, &adsb(0x44cd25, 47.01, 8.5, ::std::f32::NAN, 18000, ::std::f32::NAN));
+        assert_eq!(tracker.closest_approach(0x44cd25), None);
+        assert_eq!(tracker.approaches().len(), 1);
+    }
+}
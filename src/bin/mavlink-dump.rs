@@ -1,37 +1,59 @@
 extern crate mavlink;
+extern crate log;
 use std::sync::Arc;
-use std::thread;
 use std::env;
+use std::io;
 use std::time::Duration;
 
+/// Prints status texts to stderr, so they stand out from the dumped messages.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 fn main() {
     let args: Vec<_> = env::args().collect();
 
     if args.len() < 2 {
-        println!("Usage: mavlink-dump (tcp|udpin|udpout):ip:port");
+        println!("Usage: mavlink-dump (tcp|udpin|udpout|ws|wsin):ip:port | (unix|unixin|unixgram|unixgramin):path");
         return;
     }
 
-    let vehicle = Arc::new(mavlink::connect(&args[1]).unwrap());
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    let vehicle: Arc<mavlink::MavConnection + Sync + Send> = Arc::from(mavlink::connect(&args[1]).unwrap());
     
     vehicle.send(&mavlink::request_parameters()).unwrap();
     vehicle.send(&mavlink::request_stream()).unwrap();
 
-    thread::spawn({
-        let vehicle = vehicle.clone();
-        move || {
-            loop {
-                vehicle.send(&mavlink::heartbeat_message()).ok();
-                thread::sleep(Duration::from_secs(1));
-            }
-        }
-    });
+    let _heartbeat = mavlink::HeartbeatEmitter::start(vehicle.clone(),
+                                                      mavlink::Heartbeat::default(),
+                                                      Duration::from_secs(1));
 
+    let mut statustext = mavlink::StatusTextAssembler::new();
     loop {
-        if let Ok(msg) = vehicle.recv() {
-            println!("{:?}", msg);
-        } else {
-            break;
+        match vehicle.recv_frame_timeout(Duration::from_millis(100)) {
+            Ok((header, msg)) => {
+                println!("{:?}", msg);
+                statustext.log(&header, &msg);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+        for text in statustext.check() {
+            text.log();
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,25 @@
+extern crate mavlink;
+use std::env;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    if args.len() != 2 && args.len() != 5 {
+        println!("Usage: mavlink-sim <address> [<lat> <lon> <alt>]");
+        return;
+    }
+
+    let (lat, lon, alt) = if args.len() == 5 {
+        (args[2].parse().expect("invalid latitude"),
+         args[3].parse().expect("invalid longitude"),
+         args[4].parse().expect("invalid altitude"))
+    } else {
+        (47.397742, 8.545594, 488.0)
+    };
+
+    let conn = mavlink::connect(&args[1]).unwrap();
+    let mut vehicle = mavlink::SimVehicle::new(1, 1, lat, lon, alt);
+    if let Err(e) = vehicle.run(&*conn) {
+        println!("Connection closed: {}", e);
+    }
+}
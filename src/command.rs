@@ -0,0 +1,460 @@
+use common::{self, Command, MavMessage, MAV_RESULT};
+use connection::MavConnection;
+use mission::{MissionItem, int_scale};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// How long a waiting thread reads or sleeps before checking for its own ack again.
+const POLL_INTERVAL_MS: u64 = 20;
+
+/// `param2` of `MAV_CMD_COMPONENT_ARM_DISARM` that disarms even in flight.
+pub const FORCE_DISARM: f32 = 21196.0;
+
+/// Target system, target component and, for commands, the command id of an outstanding
+/// request.
+type Key = (u8, u8, Option<u16>);
+
+/// Tells whether a message from the target is the reply a request waits for.
+type Matcher = Box<Fn(&MavMessage) -> bool + Send>;
+
+/// Failure to get a command acknowledged.
+#[derive(Debug)]
+pub enum CommandError {
+    Io(io::Error),
+    /// No `COMMAND_ACK` or reply arrived and retries were exhausted.
+    Timeout,
+    /// The `COMMAND_ACK` carried a result code that is not a known `MAV_RESULT`.
+    UnknownResult(u8),
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> CommandError {
+        CommandError::Io(e)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Io(ref e) => write!(f, "{}", e),
+            CommandError::Timeout => write!(f, "command was not acknowledged"),
+            CommandError::UnknownResult(r) => write!(f, "command acknowledged with unknown result {}", r),
+        }
+    }
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        "command failed"
+    }
+}
+
+/// How an outstanding request was answered.
+enum Answer {
+    /// The result of the `COMMAND_ACK` for a command.
+    Ack(u8),
+    /// A message accepted by the request's matcher.
+    Reply(MavMessage),
+}
+
+fn ack_result(result: u8) -> Result<MAV_RESULT, CommandError> {
+    MAV_RESULT::from_u32(result as u32).ok_or(CommandError::UnknownResult(result))
+}
+
+struct Pending {
+    waiting: HashMap<Key, Option<Matcher>>,
+    answers: HashMap<Key, Answer>,
+}
+
+/// Sends `COMMAND_LONG` and `COMMAND_INT` and waits for the matching `COMMAND_ACK`.
+///
+/// The client can be shared between threads to have commands outstanding to several targets at
+/// once. Whichever waiting thread happens to be reading picks up every ack and hands it to the
+/// thread that sent the command. Commands with the same target and command id are sent one at
+/// a time, since their acks cannot be told apart.
+///
+/// Commands answered by another message than the ack, and other messages that expect a reply,
+/// go through `send_for_reply` and `request` with the same timeout and retries.
+///
+/// Messages other than acks and replies that arrive while waiting are dropped, so the
+/// connection must not be shared with other readers.
+pub struct CommandClient {
+    conn: Arc<MavConnection + Sync + Send>,
+    /// How long to wait for an ack before re-sending the command.
+    pub timeout: Duration,
+    /// How many times a command is re-sent without an ack before giving up.
+    pub retries: usize,
+    pending: Mutex<Pending>,
+    acked: Condvar,
+    reader: Mutex<()>,
+}
+
+impl CommandClient {
+    pub fn new(conn: Arc<MavConnection + Sync + Send>) -> CommandClient {
+        CommandClient {
+            conn: conn,
+            timeout: Duration::from_millis(1000),
+            retries: 3,
+            pending: Mutex::new(Pending { waiting: HashMap::new(), answers: HashMap::new() }),
+            acked: Condvar::new(),
+            reader: Mutex::new(()),
+        }
+    }
+
+    /// Send a `COMMAND_LONG`, incrementing `confirmation` on each retransmission.
+    pub fn command_long(&self, cmd: common::COMMAND_LONG_DATA) -> Result<MAV_RESULT, CommandError> {
+        let key = (cmd.target_system, cmd.target_component, Some(cmd.command));
+        let first = cmd.confirmation;
+        self.command(key, |attempt| {
+            MavMessage::COMMAND_LONG(common::COMMAND_LONG_DATA {
+                confirmation: first.wrapping_add(attempt as u8),
+                ..cmd.clone()
+            })
+        })
+    }
+
+    /// Send a typed command as `COMMAND_LONG`.
+    pub fn send(&self, target_system: u8, target_component: u8, command: &Command)
+                -> Result<MAV_RESULT, CommandError> {
+        self.command_long(command.to_command_long(target_system, target_component))
+    }
+
+    /// Send a `COMMAND_INT`, which has no confirmation counter, so is re-sent unchanged.
+    pub fn command_int(&self, cmd: common::COMMAND_INT_DATA) -> Result<MAV_RESULT, CommandError> {
+        let key = (cmd.target_system, cmd.target_component, Some(cmd.command));
+        self.command(key, |_| MavMessage::COMMAND_INT(cmd.clone()))
+    }
+
+    /// Send a typed command as `COMMAND_LONG` and wait for a message from the target accepted
+    /// by `reply`, such as the one the command asks for, rather than for an accepting ack.
+    ///
+    /// A failing ack ends the wait with its result as `Ok(Err(result))`.
+    pub fn send_for_reply<T, F>(&self, target_system: u8, target_component: u8, command: &Command, reply: F)
+                                -> Result<Result<T, MAV_RESULT>, CommandError>
+        where F: Fn(&MavMessage) -> Option<T> + Send + Sync + 'static
+    {
+        let cmd = command.to_command_long(target_system, target_component);
+        let key = (target_system, target_component, Some(cmd.command));
+        let reply = Arc::new(reply);
+        let matcher: Matcher = {
+            let reply = reply.clone();
+            Box::new(move |m| reply(m).is_some())
+        };
+        let answer = try!(self.request_with(key, Some(matcher), |attempt| {
+            MavMessage::COMMAND_LONG(common::COMMAND_LONG_DATA { confirmation: attempt as u8, ..cmd.clone() })
+        }));
+        match answer {
+            Answer::Reply(msg) => Ok(Ok(reply(&msg).unwrap())),
+            Answer::Ack(result) => ack_result(result).map(Err),
+        }
+    }
+
+    /// Send `msg` and wait for a message from the target accepted by `reply`, re-sending it on
+    /// timeout like a command.
+    ///
+    /// Requests to the same target are sent one at a time.
+    pub fn request<T, F>(&self, target_system: u8, target_component: u8, msg: &MavMessage, reply: F)
+                         -> Result<T, CommandError>
+        where F: Fn(&MavMessage) -> Option<T> + Send + Sync + 'static
+    {
+        let key = (target_system, target_component, None);
+        let reply = Arc::new(reply);
+        let matcher: Matcher = {
+            let reply = reply.clone();
+            Box::new(move |m| reply(m).is_some())
+        };
+        match try!(self.request_with(key, Some(matcher), |_| msg.clone())) {
+            Answer::Reply(msg) => Ok(reply(&msg).unwrap()),
+            Answer::Ack(result) => Err(CommandError::UnknownResult(result)),
+        }
+    }
+
+    fn command<F: Fn(usize) -> MavMessage>(&self, key: Key, msg: F) -> Result<MAV_RESULT, CommandError> {
+        match try!(self.request_with(key, None, msg)) {
+            Answer::Ack(result) => ack_result(result),
+            Answer::Reply(_) => unreachable!(),
+        }
+    }
+
+    fn request_with<F>(&self, key: Key, matcher: Option<Matcher>, msg: F) -> Result<Answer, CommandError>
+        where F: Fn(usize) -> MavMessage
+    {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            while pending.waiting.contains_key(&key) {
+                pending = self.acked.wait(pending).unwrap();
+            }
+            pending.waiting.insert(key, matcher);
+            pending.answers.remove(&key);
+        }
+
+        let result = self.send_and_wait(key, msg);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.waiting.remove(&key);
+        pending.answers.remove(&key);
+        self.acked.notify_all();
+        result
+    }
+
+    fn send_and_wait<F: Fn(usize) -> MavMessage>(&self, key: Key, msg: F) -> Result<Answer, CommandError> {
+        let poll = Duration::from_millis(POLL_INTERVAL_MS);
+        for attempt in 0..self.retries + 1 {
+            try!(self.conn.send(&msg(attempt)));
+            let deadline = Instant::now() + self.timeout;
+
+            loop {
+                if let Some(answer) = self.pending.lock().unwrap().answers.remove(&key) {
+                    return Ok(answer);
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let wait = if deadline - now < poll { deadline - now } else { poll };
+
+                if let Ok(_reader) = self.reader.try_lock() {
+                    match self.conn.recv_frame_timeout(wait) {
+                        Ok((header, msg)) => self.record(header.system_id, header.component_id, msg),
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                        Err(e) => return Err(CommandError::Io(e)),
+                    }
+                } else {
+                    let pending = self.pending.lock().unwrap();
+                    if !pending.answers.contains_key(&key) {
+                        drop(self.acked.wait_timeout(pending, wait).unwrap());
+                    }
+                }
+            }
+        }
+        Err(CommandError::Timeout)
+    }
+
+    /// Hand a reply or ack to the request it answers, if one is outstanding.
+    ///
+    /// An accepting ack does not answer a command that waits for a reply.
+    fn record(&self, system_id: u8, component_id: u8, msg: MavMessage) {
+        let mut pending = self.pending.lock().unwrap();
+        let mut answer = None;
+        for (&(sys, comp, command), matcher) in &pending.waiting {
+            if sys != system_id || (comp != 0 && comp != component_id) {
+                continue;
+            }
+            if matcher.as_ref().map_or(false, |matcher| matcher(&msg)) {
+                answer = Some(((sys, comp, command), None));
+                break;
+            }
+            if let MavMessage::COMMAND_ACK(ref ack) = msg {
+                let accepted = ack.result == MAV_RESULT::MAV_RESULT_ACCEPTED as u8;
+                if command == Some(ack.command) && (matcher.is_none() || !accepted) {
+                    answer = Some(((sys, comp, command), Some(ack.result)));
+                    break;
+                }
+            }
+        }
+        if let Some((key, result)) = answer {
+            let answer = match result {
+                Some(result) => Answer::Ack(result),
+                None => Answer::Reply(msg),
+            };
+            pending.answers.insert(key, answer);
+            self.acked.notify_all();
+        }
+    }
+}
+
+impl Command {
+    /// The command carried by a `COMMAND_LONG` or `COMMAND_INT`.
+    pub fn from_message(msg: &MavMessage) -> Option<Command> {
+        match *msg {
+            MavMessage::COMMAND_LONG(ref cmd) => Command::from_command_long(cmd),
+            MavMessage::COMMAND_INT(ref cmd) => Command::from_command_int(cmd),
+            _ => None,
+        }
+    }
+
+    pub fn from_command_long(cmd: &common::COMMAND_LONG_DATA) -> Option<Command> {
+        Command::from_params(cmd.command, [
+            cmd.param1 as f64, cmd.param2 as f64, cmd.param3 as f64, cmd.param4 as f64,
+            cmd.param5 as f64, cmd.param6 as f64, cmd.param7 as f64,
+        ])
+    }
+
+    pub fn to_command_long(&self, target_system: u8, target_component: u8) -> common::COMMAND_LONG_DATA {
+        let params = self.params();
+        common::COMMAND_LONG_DATA {
+            param1: params[0] as f32,
+            param2: params[1] as f32,
+            param3: params[2] as f32,
+            param4: params[3] as f32,
+            param5: params[4] as f32,
+            param6: params[5] as f32,
+            param7: params[6] as f32,
+            command: self.command(),
+            target_system: target_system,
+            target_component: target_component,
+            confirmation: 0,
+        }
+    }
+
+    /// Parameters 5 and 6 are scaled from `x` and `y` the same way as in `MISSION_ITEM_INT`.
+    pub fn from_command_int(cmd: &common::COMMAND_INT_DATA) -> Option<Command> {
+        let scale = int_scale(cmd.frame);
+        Command::from_params(cmd.command, [
+            cmd.param1 as f64, cmd.param2 as f64, cmd.param3 as f64, cmd.param4 as f64,
+            cmd.x as f64 / scale, cmd.y as f64 / scale, cmd.z as f64,
+        ])
+    }
+
+    pub fn to_command_int(&self, target_system: u8, target_component: u8, frame: u8) -> common::COMMAND_INT_DATA {
+        let params = self.params();
+        let scale = int_scale(frame);
+        common::COMMAND_INT_DATA {
+            param1: params[0] as f32,
+            param2: params[1] as f32,
+            param3: params[2] as f32,
+            param4: params[3] as f32,
+            x: (params[4] * scale).round() as i32,
+            y: (params[5] * scale).round() as i32,
+            z: params[6] as f32,
+            command: self.command(),
+            target_system: target_system,
+            target_component: target_component,
+            frame: frame,
+            current: 0,
+            autocontinue: 0,
+        }
+    }
+
+    /// Convert a mission item, which in turn converts from `MISSION_ITEM` or `MISSION_ITEM_INT`.
+    pub fn from_mission_item(item: &MissionItem) -> Option<Command> {
+        Command::from_params(item.command, [
+            item.param1 as f64, item.param2 as f64, item.param3 as f64, item.param4 as f64,
+            item.x, item.y, item.z as f64,
+        ])
+    }
+
+    /// A mission item running this command, set to continue automatically.
+    pub fn to_mission_item(&self, seq: u16, frame: u8) -> MissionItem {
+        let params = self.params();
+        MissionItem {
+            seq: seq,
+            frame: frame,
+            command: self.command(),
+            current: false,
+            autocontinue: true,
+            param1: params[0] as f32,
+            param2: params[1] as f32,
+            param3: params[2] as f32,
+            param4: params[3] as f32,
+            x: params[4],
+            y: params[5],
+            z: params[6] as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_command {
+    use super::*;
+    use loopback::loopback;
+    use connection::MavConnection;
+    use Header;
+    use std::thread;
+
+    fn command(target_system: u8, command: u16) -> common::COMMAND_LONG_DATA {
+        common::COMMAND_LONG_DATA {
+            param1: 1.0,
+            param2: 0.0,
+            param3: 0.0,
+            param4: 0.0,
+            param5: 0.0,
+            param6: 0.0,
+            param7: 0.0,
+            command: command,
+            target_system: target_system,
+            target_component: 1,
+            confirmation: 0,
+        }
+    }
+
+    #[test]
+    pub fn test_concurrent_commands() {
+        let (gcs, vehicles) = loopback();
+        let mut client = CommandClient::new(Arc::new(gcs));
+        client.timeout = Duration::from_millis(100);
+        client.retries = 2;
+        let client = Arc::new(client);
+
+        // System 1 ignores the first attempt, system 2 denies everything, system 3 is silent.
+        let vehicles = thread::spawn(move || {
+            let mut confirmations = Vec::new();
+            while let Ok(MavMessage::COMMAND_LONG(cmd)) = vehicles.recv() {
+                let header = Header { sequence: 0, system_id: cmd.target_system, component_id: 1 };
+                let result = match (cmd.target_system, cmd.confirmation) {
+                    (1, 0) | (3, _) => continue,
+                    (1, _) => MAV_RESULT::MAV_RESULT_ACCEPTED,
+                    _ => MAV_RESULT::MAV_RESULT_DENIED,
+                };
+                confirmations.push((cmd.target_system, cmd.confirmation));
+                vehicles.send_frame(header, &MavMessage::COMMAND_ACK(common::COMMAND_ACK_DATA {
+                    command: cmd.command,
+                    result: result as u8,
+                })).unwrap();
+            }
+            confirmations
+        });
+
+        let threads: Vec<_> = (1..4).map(|sys| {
+            let client = client.clone();
+            thread::spawn(move || client.command_long(command(sys, 400)))
+        }).collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        match results[0] { Ok(MAV_RESULT::MAV_RESULT_ACCEPTED) => (), ref r => panic!("{:?}", r) }
+        match results[1] { Ok(MAV_RESULT::MAV_RESULT_DENIED) => (), ref r => panic!("{:?}", r) }
+        match results[2] { Err(CommandError::Timeout) => (), ref r => panic!("{:?}", r) }
+
+        drop(client);
+        let confirmations = vehicles.join().unwrap();
+        assert!(confirmations.contains(&(1, 1)));
+        assert!(confirmations.contains(&(2, 0)));
+    }
+
+    #[test]
+    pub fn test_typed_commands() {
+        let takeoff = Command::NavTakeoff {
+            minimum_pitch: 15.0,
+            yaw_angle: 90.0,
+            lat: 47.3977419,
+            lon: 8.5455938,
+            alt: 20.0,
+        };
+        let long = takeoff.to_command_long(1, 1);
+        assert_eq!(long.command, 22);
+        assert_eq!((long.param1, long.param2, long.param4, long.param7), (15.0, 0.0, 90.0, 20.0));
+
+        let int = takeoff.to_command_int(1, 1, common::MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT as u8);
+        assert_eq!((int.x, int.y), (473977419, 85455938));
+        assert_eq!(Command::from_command_int(&int), Some(takeoff.clone()));
+
+        let item = takeoff.to_mission_item(3, common::MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT as u8);
+        let message = item.to_item_int(1, 1);
+        assert_eq!(Command::from_mission_item(&MissionItem::from_item_int(&message)), Some(takeoff));
+
+        match Command::from_command_long(&command(1, 400)) {
+            Some(Command::ComponentArmDisarm { arm }) => assert_eq!(arm, 1.0),
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert_eq!(Command::NavReturnToLaunch.params(), [0.0; 7]);
+        assert_eq!(Command::from_params(1, [0.0; 7]), None);
+        // NAV_LAST is a range marker, not a command.
+        assert_eq!(Command::from_params(95, [0.0; 7]), None);
+    }
+}
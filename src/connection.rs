@@ -1,22 +1,113 @@
 use common::MavMessage;
-use {Header, read, write};
+use {Header, RawFrame, read_raw, write_raw};
 
 use std::sync::Mutex;
 use std::net::{TcpStream, UdpSocket, ToSocketAddrs, SocketAddr};
 use std::io::{self, Read};
 
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "websocket")]
+use websocket::WebSocket;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixStream, UnixListener, UnixDatagram};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// A MAVLink connection
 pub trait MavConnection {
     /// Receive a mavlink message.
     ///
     /// Blocks until a valid frame is received, ignoring invalid messages.
-    fn recv(&self) -> io::Result<MavMessage>;
-    
-    
+    fn recv(&self) -> io::Result<MavMessage> {
+        self.recv_frame().map(|(_, msg)| msg)
+    }
+
+    /// Receive a mavlink message along with the header it was sent with.
+    ///
+    /// Blocks until a valid frame is received, ignoring invalid messages.
+    fn recv_frame(&self) -> io::Result<(Header, MavMessage)> {
+        loop {
+            let frame = try!(self.recv_raw());
+            if let Some(msg) = frame.decode() {
+                return Ok((frame.header, msg));
+            }
+        }
+    }
+
+    /// Receive a mavlink message along with its header, waiting at most `timeout`.
+    ///
+    /// Fails with `ErrorKind::TimedOut` if no valid frame arrives in time.
+    fn recv_frame_timeout(&self, timeout: Duration) -> io::Result<(Header, MavMessage)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let frame = try!(self.recv_raw_timeout(try!(remaining(Some(deadline))).unwrap()));
+            if let Some(msg) = frame.decode() {
+                return Ok((frame.header, msg));
+            }
+        }
+    }
+
+    /// Receive a mavlink frame without decoding it.
+    ///
+    /// Unlike `recv_frame`, frames of unknown messages are returned rather than skipped.
+    fn recv_raw(&self) -> io::Result<RawFrame>;
+
+    /// Receive a mavlink frame without decoding it, waiting at most `timeout`.
+    ///
+    /// Fails with `ErrorKind::TimedOut` if no frame arrives in time. On stream connections, a
+    /// frame that is only partially received when the timeout expires is lost.
+    ///
+    /// Connections that cannot time out a read keep the default, which fails with
+    /// `ErrorKind::Other` without reading.
+    fn recv_raw_timeout(&self, _timeout: Duration) -> io::Result<RawFrame> {
+        Err(io::Error::new(io::ErrorKind::Other, "receive timeouts are not supported by this connection"))
+    }
+
     /// Send a mavlink message
     fn send(&self, data: &MavMessage) -> io::Result<()>;
+
+    /// Send a mavlink message with the given header, rather than one stamped by this connection.
+    ///
+    /// Used when forwarding frames on behalf of another system.
+    fn send_frame(&self, header: Header, data: &MavMessage) -> io::Result<()> {
+        self.send_raw(&RawFrame::new(header, data))
+    }
+
+    /// Send a mavlink frame exactly as it is.
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()>;
+}
+
+/// Time left until `deadline`, or a `TimedOut` error if it has passed.
+pub fn remaining(deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a frame"))
+            } else {
+                Ok(Some(deadline - now))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Socket read timeouts are reported as `WouldBlock` on some platforms; normalize them.
+fn timed_out(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a frame")
+    } else {
+        e
+    }
 }
 
 /// Connect to a MAVLink node by address string.
@@ -26,6 +117,12 @@ pub trait MavConnection {
 ///  * `tcp:<addr>:<port>`
 ///  * `udpin:<addr>:<port>`
 ///  * `udpout:<addr>:<port>`
+///  * `unix:<path>`
+///  * `unixin:<path>`
+///  * `unixgram:<path>`
+///  * `unixgramin:<path>`
+///  * `ws:<addr>:<port>[/<path>]` (requires the `websocket` feature)
+///  * `wsin:<addr>:<port>` (requires the `websocket` feature)
 ///
 /// The type of the connection is determined at runtime based on the address type, so the
 /// connection is returned as a trait object.
@@ -36,17 +133,66 @@ pub fn connect(address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
         Ok(Box::new(try!(Udp::udpin(&address["udpin:".len()..]))))
     } else if address.starts_with("udpout:") {
         Ok(Box::new(try!(Udp::udpout(&address["udpout:".len()..]))))
+    } else if address.starts_with("ws:") || address.starts_with("wsin:") {
+        connect_websocket(address)
     } else {
-        Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Prefix must be one of udpin, udpout, or tcp"))
+        connect_unix(address)
     }
 }
 
+#[cfg(feature = "websocket")]
+fn connect_websocket(address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
+    if address.starts_with("ws:") {
+        Ok(Box::new(try!(WebSocket::ws(&address["ws:".len()..]))))
+    } else {
+        Ok(Box::new(try!(WebSocket::wsin(&address["wsin:".len()..]))))
+    }
+}
+
+#[cfg(not(feature = "websocket"))]
+fn connect_websocket(_address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "WebSocket connections require the `websocket` feature"))
+}
+
+#[cfg(unix)]
+fn connect_unix(address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
+    if address.starts_with("unix:") {
+        Ok(Box::new(try!(Unix::unix(&address["unix:".len()..]))))
+    } else if address.starts_with("unixin:") {
+        Ok(Box::new(try!(Unix::unixin(&address["unixin:".len()..]))))
+    } else if address.starts_with("unixgram:") {
+        Ok(Box::new(try!(UnixGram::unixgram(&address["unixgram:".len()..]))))
+    } else if address.starts_with("unixgramin:") {
+        Ok(Box::new(try!(UnixGram::unixgramin(&address["unixgramin:".len()..]))))
+    } else {
+        Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+            "Prefix must be one of udpin, udpout, tcp, ws, wsin, unix, unixin, unixgram, or unixgramin"))
+    }
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
+    Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Prefix must be one of udpin, udpout, tcp, ws, or wsin"))
+}
+
 struct UdpWrite {
     socket: UdpSocket,
     dest: Option<SocketAddr>,
     sequence: u8,
 }
 
+impl UdpWrite {
+    fn send_raw(&mut self, frame: &RawFrame) -> io::Result<()> {
+        if let Some(addr) = self.dest {
+            let mut buf = Vec::new();
+            try!(write_raw(&mut buf, frame));
+            try!(self.socket.send_to(&buf, addr));
+        }
+
+        Ok(())
+    }
+}
+
 struct PacketBuf {
     buf: Vec<u8>,
     start: usize,
@@ -126,15 +272,14 @@ impl Udp {
         let socket = try!(UdpSocket::bind(&SocketAddr::from_str("0.0.0.0:0").unwrap()));
         Udp::new(socket, false, Some(addr))
     }
-}
 
-impl MavConnection for Udp {        
-    fn recv(&self) -> io::Result<MavMessage> {
+    fn recv_raw_until(&self, deadline: Option<Instant>) -> io::Result<RawFrame> {
         let mut guard = self.read.lock().unwrap();
         let state = &mut *guard;
         loop {
             if state.recv_buf.len() == 0 {
-                let (len, src) = try!(state.socket.recv_from(state.recv_buf.reset()));
+                try!(state.socket.set_read_timeout(try!(remaining(deadline))));
+                let (len, src) = try!(state.socket.recv_from(state.recv_buf.reset()).map_err(timed_out));
                 state.recv_buf.set_len(len);
                 
                 if self.server {
@@ -142,11 +287,21 @@ impl MavConnection for Udp {
                 }
             }
             
-            if let Ok((_, m)) = read(&mut state.recv_buf) {
-                return Ok(m);
+            if let Ok(frame) = read_raw(&mut state.recv_buf) {
+                return Ok(frame);
             }
         }
     }
+}
+
+impl MavConnection for Udp {        
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        self.recv_raw_until(None)
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
+        self.recv_raw_until(Some(Instant::now() + timeout))
+    }
 
     fn send(&self, data: &MavMessage) -> io::Result<()> {
         let mut guard = self.write.lock().unwrap();
@@ -160,13 +315,11 @@ impl MavConnection for Udp {
         
         state.sequence = state.sequence.wrapping_add(1);
         
-        if let Some(addr) = state.dest {
-            let mut buf = Vec::new();
-            try!(write(&mut buf, header, data));
-            try!(state.socket.send_to(&buf, addr));
-        }
-        
-        Ok(())
+        state.send_raw(&RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        self.write.lock().unwrap().send_raw(frame)
     }
 }
 
@@ -193,9 +346,16 @@ impl Tcp {
 }
 
 impl MavConnection for Tcp {
-    fn recv(&self) -> io::Result<MavMessage> {
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        let mut lock = self.read.lock().unwrap();
+        try!(lock.set_read_timeout(None));
+        read_raw(&mut *lock)
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
         let mut lock = self.read.lock().unwrap();
-        read(&mut *lock).map(|(_, pkt)| pkt)
+        try!(lock.set_read_timeout(try!(remaining(Some(Instant::now() + timeout)))));
+        read_raw(&mut *lock).map_err(timed_out)
     }
 
     fn send(&self, data: &MavMessage) -> io::Result<()> {
@@ -208,9 +368,288 @@ impl MavConnection for Tcp {
         };
         
         lock.sequence = lock.sequence.wrapping_add(1);
-        
-        try!(write(&mut lock.socket, header, data));
-        
+
+        write_raw(&mut lock.socket, &RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        write_raw(&mut lock.socket, frame)
+    }
+}
+
+/// Remove a stale socket file left behind by a previous listener, so that `bind` can succeed.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Unix domain stream socket MAVLink connection
+#[cfg(unix)]
+pub struct Unix {
+    read: Mutex<UnixStream>,
+    write: Mutex<UnixWrite>,
+}
+
+#[cfg(unix)]
+struct UnixWrite {
+    socket: UnixStream,
+    sequence: u8,
+}
+
+#[cfg(unix)]
+impl Unix {
+    fn new(socket: UnixStream) -> io::Result<Unix> {
+        Ok(Unix {
+            read: Mutex::new(try!(socket.try_clone())),
+            write: Mutex::new(UnixWrite { socket: socket, sequence: 0 }),
+        })
+    }
+
+    /// Connect to a listening Unix domain socket.
+    pub fn unix<P: AsRef<Path>>(path: P) -> io::Result<Unix> {
+        Unix::new(try!(UnixStream::connect(path)))
+    }
+
+    /// Listen on a Unix domain socket and wait for a single peer to connect.
+    pub fn unixin<P: AsRef<Path>>(path: P) -> io::Result<Unix> {
+        try!(remove_stale_socket(path.as_ref()));
+        let listener = try!(UnixListener::bind(path));
+        let (socket, _) = try!(listener.accept());
+        Unix::new(socket)
+    }
+}
+
+#[cfg(unix)]
+impl MavConnection for Unix {
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        let mut lock = self.read.lock().unwrap();
+        try!(lock.set_read_timeout(None));
+        read_raw(&mut *lock)
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
+        let mut lock = self.read.lock().unwrap();
+        try!(lock.set_read_timeout(try!(remaining(Some(Instant::now() + timeout)))));
+        read_raw(&mut *lock).map_err(timed_out)
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+
+        let header = Header {
+            sequence: lock.sequence,
+            system_id: 255,
+            component_id: 0,
+        };
+
+        lock.sequence = lock.sequence.wrapping_add(1);
+
+        write_raw(&mut lock.socket, &RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        write_raw(&mut lock.socket, frame)
+    }
+}
+
+#[cfg(unix)]
+static UNIXGRAM_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+struct UnixGramWrite {
+    socket: UnixDatagram,
+    dest: Option<PathBuf>,
+    sequence: u8,
+}
+
+#[cfg(unix)]
+impl UnixGramWrite {
+    fn send_raw(&mut self, frame: &RawFrame) -> io::Result<()> {
+        if let Some(ref path) = self.dest {
+            let mut buf = Vec::new();
+            try!(write_raw(&mut buf, frame));
+            try!(self.socket.send_to(&buf, path));
+        }
+
         Ok(())
     }
 }
+
+#[cfg(unix)]
+struct UnixGramRead {
+    socket: UnixDatagram,
+    recv_buf: PacketBuf,
+}
+
+/// Unix domain datagram socket MAVLink connection
+#[cfg(unix)]
+pub struct UnixGram {
+    read: Mutex<UnixGramRead>,
+    write: Mutex<UnixGramWrite>,
+    server: bool,
+    /// Socket file created by this connection, removed when it is dropped.
+    bound: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl UnixGram {
+    fn new(socket: UnixDatagram, server: bool, dest: Option<PathBuf>, bound: Option<PathBuf>) -> io::Result<UnixGram> {
+        Ok(UnixGram {
+            server: server,
+            bound: bound,
+            read: Mutex::new(UnixGramRead {
+                socket: try!(socket.try_clone()),
+                recv_buf: PacketBuf::new(),
+            }),
+            write: Mutex::new(UnixGramWrite {
+                socket: socket,
+                dest: dest,
+                sequence: 0,
+            }),
+        })
+    }
+
+    /// Bind a datagram socket at `path` and reply to whichever peer sent the last message.
+    pub fn unixgramin<P: AsRef<Path>>(path: P) -> io::Result<UnixGram> {
+        let path = path.as_ref().to_path_buf();
+        try!(remove_stale_socket(&path));
+        let socket = try!(UnixDatagram::bind(&path));
+        UnixGram::new(socket, true, None, Some(path))
+    }
+
+    /// Send datagrams to the socket at `path`.
+    ///
+    /// Unnamed datagram sockets cannot be replied to, so the connection binds its own socket
+    /// next to the destination, named `<path>.<pid>.<n>`.
+    pub fn unixgram<P: AsRef<Path>>(path: P) -> io::Result<UnixGram> {
+        let dest = path.as_ref().to_path_buf();
+        let n = UNIXGRAM_CLIENTS.fetch_add(1, Ordering::SeqCst);
+        let mut local = dest.clone().into_os_string();
+        local.push(format!(".{}.{}", ::std::process::id(), n));
+        let local = PathBuf::from(local);
+        try!(remove_stale_socket(&local));
+        let socket = try!(UnixDatagram::bind(&local));
+        UnixGram::new(socket, false, Some(dest), Some(local))
+    }
+
+    fn recv_raw_until(&self, deadline: Option<Instant>) -> io::Result<RawFrame> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        loop {
+            if state.recv_buf.len() == 0 {
+                try!(state.socket.set_read_timeout(try!(remaining(deadline))));
+                let (len, src) = try!(state.socket.recv_from(state.recv_buf.reset()).map_err(timed_out));
+                state.recv_buf.set_len(len);
+
+                if self.server {
+                    if let Some(path) = src.as_pathname() {
+                        self.write.lock().unwrap().dest = Some(path.to_path_buf());
+                    }
+                }
+            }
+
+            if let Ok(frame) = read_raw(&mut state.recv_buf) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixGram {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.bound {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(unix)]
+impl MavConnection for UnixGram {
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        self.recv_raw_until(None)
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
+        self.recv_raw_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut guard = self.write.lock().unwrap();
+        let state = &mut *guard;
+
+        let header = Header {
+            sequence: state.sequence,
+            system_id: 255,
+            component_id: 0,
+        };
+
+        state.sequence = state.sequence.wrapping_add(1);
+
+        state.send_raw(&RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        self.write.lock().unwrap().send_raw(frame)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test_unix {
+    use super::*;
+    use common;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    fn heartbeat_mode(msg: MavMessage) -> u32 {
+        match msg {
+            common::MavMessage::HEARTBEAT(h) => h.custom_mode,
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    pub fn test_unix_stream() {
+        let path = env::temp_dir().join(format!("mavlink-test-stream.{}", ::std::process::id()));
+        let server = thread::spawn({
+            let path = path.clone();
+            move || {
+                let conn = Unix::unixin(&path).unwrap();
+                conn.send(&::heartbeat_message()).unwrap();
+                conn.recv().unwrap()
+            }
+        });
+
+        let client = loop {
+            match Unix::unix(&path) {
+                Ok(c) => break c,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(heartbeat_mode(client.recv().unwrap()), 0);
+        client.send(&::heartbeat_message()).unwrap();
+        assert_eq!(heartbeat_mode(server.join().unwrap()), 0);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    pub fn test_unix_datagram() {
+        let path = env::temp_dir().join(format!("mavlink-test-gram.{}", ::std::process::id()));
+        let server = UnixGram::unixgramin(&path).unwrap();
+        let client = UnixGram::unixgram(&path).unwrap();
+        client.send(&::heartbeat_message()).unwrap();
+        assert_eq!(heartbeat_mode(server.recv().unwrap()), 0);
+        server.send(&::heartbeat_message()).unwrap();
+        assert_eq!(heartbeat_mode(client.recv().unwrap()), 0);
+
+        let bound = client.bound.clone().unwrap();
+        drop(client);
+        assert!(!bound.exists());
+    }
+}
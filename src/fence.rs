@@ -0,0 +1,470 @@
+use common::{self, Command, MavMessage, MAV_FRAME, MAV_RESULT, FENCE_BREACH};
+use command::{CommandClient, CommandError};
+use connection::MavConnection;
+use geo::{distance, METERS_PER_DEGREE};
+use Header;
+
+use std::f32;
+use std::sync::Arc;
+use std::time::Instant;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Failure to upload, read back or enable a geofence.
+#[derive(Debug)]
+pub enum FenceError {
+    Io(io::Error),
+    /// The vehicle did not answer.
+    Timeout,
+    /// The fence has no inclusion zone to derive an allowed area from.
+    NoInclusionZone,
+    /// The vehicle kept reporting a different allowed area than the one uploaded.
+    Mismatch(AllowedArea),
+    /// The vehicle refused to enable or disable the fence.
+    Rejected(MAV_RESULT),
+    /// The `COMMAND_ACK` carried a result code that is not a known `MAV_RESULT`.
+    UnknownResult(u8),
+}
+
+impl From<io::Error> for FenceError {
+    fn from(e: io::Error) -> FenceError {
+        FenceError::Io(e)
+    }
+}
+
+impl From<CommandError> for FenceError {
+    fn from(e: CommandError) -> FenceError {
+        match e {
+            CommandError::Io(e) => FenceError::Io(e),
+            CommandError::Timeout => FenceError::Timeout,
+            CommandError::UnknownResult(r) => FenceError::UnknownResult(r),
+        }
+    }
+}
+
+impl fmt::Display for FenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FenceError::Io(ref e) => write!(f, "{}", e),
+            FenceError::Timeout => write!(f, "vehicle did not answer"),
+            FenceError::NoInclusionZone => write!(f, "fence has no inclusion zone"),
+            FenceError::Mismatch(ref area) => write!(f, "vehicle reports a different allowed area {:?}", area),
+            FenceError::Rejected(r) => write!(f, "vehicle refused the fence command with {:?}", r),
+            FenceError::UnknownResult(r) => write!(f, "command acknowledged with unknown result {}", r),
+        }
+    }
+}
+
+impl Error for FenceError {
+    fn description(&self) -> &str {
+        "geofence operation failed"
+    }
+}
+
+/// The outline of a fence zone, in degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FenceShape {
+    /// Vertices as latitude and longitude, in order around the polygon.
+    Polygon(Vec<(f64, f64)>),
+    /// Center as latitude and longitude, and radius in meters. Together with the fence's
+    /// altitude limits this makes a cylinder.
+    Circle { center: (f64, f64), radius: f64 },
+}
+
+impl FenceShape {
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match *self {
+            FenceShape::Polygon(ref vertices) => {
+                // Count crossings of a ray going east from the point.
+                let mut inside = false;
+                let mut j = vertices.len().wrapping_sub(1);
+                for (i, &(lat_i, lon_i)) in vertices.iter().enumerate() {
+                    let (lat_j, lon_j) = vertices[j];
+                    if (lat_i > lat) != (lat_j > lat) &&
+                       lon < lon_i + (lat - lat_i) / (lat_j - lat_i) * (lon_j - lon_i) {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+            FenceShape::Circle { center, radius } => distance(center.0, center.1, lat, lon) <= radius,
+        }
+    }
+
+    /// Smallest and largest latitude and longitude.
+    fn bounds(&self) -> ((f64, f64), (f64, f64)) {
+        match *self {
+            FenceShape::Polygon(ref vertices) => {
+                vertices.iter().fold(((90.0, 180.0), (-90.0, -180.0)), |(min, max), &(lat, lon)| {
+                    ((min.0.min(lat), min.1.min(lon)), (max.0.max(lat), max.1.max(lon)))
+                })
+            }
+            FenceShape::Circle { center, radius } => {
+                let north = radius / METERS_PER_DEGREE;
+                let east = north / (center.0.to_radians()).cos();
+                ((center.0 - north, center.1 - east), (center.0 + north, center.1 + east))
+            }
+        }
+    }
+}
+
+/// An area the vehicle has to stay in, or one it has to keep out of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FenceZone {
+    pub shape: FenceShape,
+    pub inclusion: bool,
+}
+
+impl FenceZone {
+    pub fn inclusion(shape: FenceShape) -> FenceZone {
+        FenceZone { shape: shape, inclusion: true }
+    }
+
+    pub fn exclusion(shape: FenceShape) -> FenceZone {
+        FenceZone { shape: shape, inclusion: false }
+    }
+}
+
+/// A geofence of inclusion and exclusion zones with altitude limits.
+///
+/// A position is inside the fence when it is within the altitude limits, inside at least one
+/// inclusion zone if there are any, and outside every exclusion zone.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Geofence {
+    pub zones: Vec<FenceZone>,
+    /// Altitudes above home in meters.
+    pub min_alt: Option<f32>,
+    pub max_alt: Option<f32>,
+}
+
+impl Geofence {
+    pub fn new() -> Geofence {
+        Geofence::default()
+    }
+
+    /// How a position, with `alt` above home in meters, breaches the fence, if it does.
+    pub fn breach(&self, lat: f64, lon: f64, alt: f32) -> FENCE_BREACH {
+        if self.min_alt.map_or(false, |min| alt < min) {
+            return FENCE_BREACH::FENCE_BREACH_MINALT;
+        }
+        if self.max_alt.map_or(false, |max| alt > max) {
+            return FENCE_BREACH::FENCE_BREACH_MAXALT;
+        }
+        let mut inclusions = self.zones.iter().filter(|zone| zone.inclusion).peekable();
+        let included = inclusions.peek().is_none() || inclusions.any(|zone| zone.shape.contains(lat, lon));
+        let excluded = self.zones.iter().any(|zone| !zone.inclusion && zone.shape.contains(lat, lon));
+        if included && !excluded {
+            FENCE_BREACH::FENCE_BREACH_NONE
+        } else {
+            FENCE_BREACH::FENCE_BREACH_BOUNDARY
+        }
+    }
+
+    /// How the position in a `GLOBAL_POSITION_INT` breaches the fence, if it does.
+    pub fn breach_position(&self, position: &common::GLOBAL_POSITION_INT_DATA) -> FENCE_BREACH {
+        self.breach(position.lat as f64 / 1e7,
+                    position.lon as f64 / 1e7,
+                    position.relative_alt as f32 / 1000.0)
+    }
+
+    /// The box around the inclusion zones and altitude limits, or `None` without inclusion
+    /// zones. Without an altitude limit the box is unbounded in that direction.
+    pub fn allowed_area(&self) -> Option<AllowedArea> {
+        let mut bounds: Option<((f64, f64), (f64, f64))> = None;
+        for zone in self.zones.iter().filter(|zone| zone.inclusion) {
+            let (min, max) = zone.shape.bounds();
+            bounds = Some(match bounds {
+                Some((lo, hi)) => ((lo.0.min(min.0), lo.1.min(min.1)), (hi.0.max(max.0), hi.1.max(max.1))),
+                None => (min, max),
+            });
+        }
+        bounds.map(|(min, max)| {
+            AllowedArea {
+                frame: MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT as u8,
+                p1: (min.0 as f32, min.1 as f32, self.min_alt.unwrap_or(f32::NEG_INFINITY)),
+                p2: (max.0 as f32, max.1 as f32, self.max_alt.unwrap_or(f32::INFINITY)),
+            }
+        })
+    }
+}
+
+/// The box of `SAFETY_SET_ALLOWED_AREA` and `SAFETY_ALLOWED_AREA`, given by two corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllowedArea {
+    /// `MAV_FRAME` of the corners.
+    pub frame: u8,
+    pub p1: (f32, f32, f32),
+    pub p2: (f32, f32, f32),
+}
+
+impl AllowedArea {
+    pub fn from_message(data: &common::SAFETY_ALLOWED_AREA_DATA) -> AllowedArea {
+        AllowedArea {
+            frame: data.frame,
+            p1: (data.p1x, data.p1y, data.p1z),
+            p2: (data.p2x, data.p2y, data.p2z),
+        }
+    }
+
+    /// `SAFETY_SET_ALLOWED_AREA` for the target.
+    pub fn set_message(&self, target_system: u8, target_component: u8) -> MavMessage {
+        MavMessage::SAFETY_SET_ALLOWED_AREA(common::SAFETY_SET_ALLOWED_AREA_DATA {
+            p1x: self.p1.0,
+            p1y: self.p1.1,
+            p1z: self.p1.2,
+            p2x: self.p2.0,
+            p2y: self.p2.1,
+            p2z: self.p2.2,
+            target_system: target_system,
+            target_component: target_component,
+            frame: self.frame,
+        })
+    }
+
+    /// `SAFETY_ALLOWED_AREA`, as reported by a vehicle.
+    pub fn message(&self) -> MavMessage {
+        MavMessage::SAFETY_ALLOWED_AREA(common::SAFETY_ALLOWED_AREA_DATA {
+            p1x: self.p1.0,
+            p1y: self.p1.1,
+            p1z: self.p1.2,
+            p2x: self.p2.0,
+            p2y: self.p2.1,
+            p2z: self.p2.2,
+            frame: self.frame,
+        })
+    }
+}
+
+/// A change in whether a vehicle is inside its fence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FenceEvent {
+    /// The vehicle left the fence, or breached it in a different way.
+    Breach(FENCE_BREACH),
+    /// The vehicle is back inside.
+    Cleared,
+}
+
+/// Checks a vehicle's `GLOBAL_POSITION_INT` against a geofence, like the autopilot's own
+/// fence does.
+///
+/// The monitor does not read from a connection itself: pass it every received frame with
+/// `handle`.
+pub struct FenceMonitor {
+    pub system_id: u8,
+    pub fence: Geofence,
+    breach: FENCE_BREACH,
+    breach_count: u16,
+    breach_time: Option<Instant>,
+}
+
+impl FenceMonitor {
+    pub fn new(system_id: u8, fence: Geofence) -> FenceMonitor {
+        FenceMonitor {
+            system_id: system_id,
+            fence: fence,
+            breach: FENCE_BREACH::FENCE_BREACH_NONE,
+            breach_count: 0,
+            breach_time: None,
+        }
+    }
+
+    /// Handle a received message, returning a change in breach status.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Option<FenceEvent> {
+        let breach = match *msg {
+            MavMessage::GLOBAL_POSITION_INT(ref position) if header.system_id == self.system_id => {
+                self.fence.breach_position(position)
+            }
+            _ => return None,
+        };
+        if breach == self.breach {
+            return None;
+        }
+        self.breach = breach;
+        if breach == FENCE_BREACH::FENCE_BREACH_NONE {
+            Some(FenceEvent::Cleared)
+        } else {
+            self.breach_count = self.breach_count.wrapping_add(1);
+            self.breach_time = Some(Instant::now());
+            Some(FenceEvent::Breach(breach))
+        }
+    }
+
+    /// The current breach, `FENCE_BREACH_NONE` while inside.
+    pub fn breach(&self) -> FENCE_BREACH {
+        self.breach
+    }
+
+    /// How many times the fence was breached.
+    pub fn breach_count(&self) -> u16 {
+        self.breach_count
+    }
+
+    /// When the fence was last breached.
+    pub fn breach_time(&self) -> Option<Instant> {
+        self.breach_time
+    }
+}
+
+/// Uploads, reads back and enables a vehicle's geofence.
+///
+/// The message set has no way to transfer fence polygons or circles, only the box of
+/// `SAFETY_SET_ALLOWED_AREA`, so `upload` sends the box around the inclusion zones and the
+/// zones themselves are only checked locally by `FenceMonitor`. The client reads from the
+/// connection only while a request is running, so it must not be shared with other readers.
+pub struct FenceClient {
+    conn: Arc<MavConnection + Sync + Send>,
+    pub commands: CommandClient,
+    pub target_system: u8,
+    pub target_component: u8,
+}
+
+impl FenceClient {
+    pub fn new(conn: Arc<MavConnection + Sync + Send>, target_system: u8, target_component: u8) -> FenceClient {
+        FenceClient {
+            commands: CommandClient::new(conn.clone()),
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+        }
+    }
+
+    /// Wait for the next `SAFETY_ALLOWED_AREA` from the target, for up to the command timeout.
+    fn recv_area(&self) -> Result<Option<AllowedArea>, FenceError> {
+        let deadline = Instant::now() + self.commands.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            match self.conn.recv_frame_timeout(deadline - now) {
+                Ok((header, MavMessage::SAFETY_ALLOWED_AREA(ref data))) if header.system_id == self.target_system => {
+                    return Ok(Some(AllowedArea::from_message(data)));
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(FenceError::Io(e)),
+            }
+        }
+    }
+
+    /// Set the allowed area to the box around the fence's inclusion zones, returning the area
+    /// the vehicle reports back.
+    pub fn upload(&self, fence: &Geofence) -> Result<AllowedArea, FenceError> {
+        let area = try!(fence.allowed_area().ok_or(FenceError::NoInclusionZone));
+        let msg = area.set_message(self.target_system, self.target_component);
+        let mut reported = None;
+        for _ in 0..self.commands.retries + 1 {
+            try!(self.conn.send(&msg));
+            reported = try!(self.recv_area());
+            if reported == Some(area) {
+                return Ok(area);
+            }
+        }
+        match reported {
+            Some(reported) => Err(FenceError::Mismatch(reported)),
+            None => Err(FenceError::Timeout),
+        }
+    }
+
+    /// The allowed area the vehicle reports next. There is no message to request it, so this
+    /// relies on the vehicle sending it regularly.
+    pub fn download(&self) -> Result<AllowedArea, FenceError> {
+        for _ in 0..self.commands.retries + 1 {
+            if let Some(area) = try!(self.recv_area()) {
+                return Ok(area);
+            }
+        }
+        Err(FenceError::Timeout)
+    }
+
+    /// Enable or disable the vehicle's fence with `MAV_CMD_DO_FENCE_ENABLE`.
+    pub fn enable(&self, enable: bool) -> Result<(), FenceError> {
+        let command = Command::DoFenceEnable { enable: if enable { 1.0 } else { 0.0 } };
+        match try!(self.commands.send(self.target_system, self.target_component, &command)) {
+            MAV_RESULT::MAV_RESULT_ACCEPTED => Ok(()),
+            result => Err(FenceError::Rejected(result)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_fence {
+    use super::*;
+    use loopback::loopback;
+    use sim::SimVehicle;
+    use std::thread;
+    use std::time::Duration;
+
+    fn fence() -> Geofence {
+        Geofence {
+            zones: vec![
+                FenceZone::inclusion(FenceShape::Polygon(vec![(47.0, 8.5), (47.0, 8.51), (47.01, 8.51), (47.01, 8.5)])),
+                FenceZone::exclusion(FenceShape::Circle { center: (47.005, 8.505), radius: 100.0 }),
+            ],
+            min_alt: None,
+            max_alt: Some(120.0),
+        }
+    }
+
+    fn position(lat: f64, lon: f64, alt: f32) -> MavMessage {
+        MavMessage::GLOBAL_POSITION_INT(common::GLOBAL_POSITION_INT_DATA {
+            time_boot_ms: 0,
+            lat: (lat * 1e7) as i32,
+            lon: (lon * 1e7) as i32,
+            alt: 0,
+            relative_alt: (alt * 1000.0) as i32,
+            vx: 0,
+            vy: 0,
+            vz: 0,
+            hdg: 0,
+        })
+    }
+
+    #[test]
+    pub fn test_breach() {
+        let fence = fence();
+        assert_eq!(fence.breach(47.002, 8.502, 50.0), FENCE_BREACH::FENCE_BREACH_NONE);
+        assert_eq!(fence.breach(47.005, 8.5055, 50.0), FENCE_BREACH::FENCE_BREACH_BOUNDARY);
+        assert_eq!(fence.breach(47.002, 8.511, 50.0), FENCE_BREACH::FENCE_BREACH_BOUNDARY);
+        assert_eq!(fence.breach(47.002, 8.502, 150.0), FENCE_BREACH::FENCE_BREACH_MAXALT);
+        assert_eq!(Geofence::new().breach(0.0, 0.0, 0.0), FENCE_BREACH::FENCE_BREACH_NONE);
+
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut monitor = FenceMonitor::new(1, fence);
+        assert_eq!(monitor.handle(&header, &position(47.002, 8.502, 10.0)), None);
+        assert_eq!(monitor.handle(&header, &position(47.005, 8.505, 10.0)),
+                   Some(FenceEvent::Breach(FENCE_BREACH::FENCE_BREACH_BOUNDARY)));
+        assert_eq!(monitor.handle(&header, &position(47.005, 8.505, 10.0)), None);
+        assert_eq!(monitor.handle(&header, &position(47.002, 8.502, 10.0)), Some(FenceEvent::Cleared));
+        assert_eq!(monitor.handle(&Header { system_id: 2, ..header }, &position(48.0, 8.0, 10.0)), None);
+        assert_eq!(monitor.breach_count(), 1);
+    }
+
+    #[test]
+    pub fn test_upload() {
+        let (gcs, sim) = loopback();
+        let sim = thread::spawn(move || {
+            let mut vehicle = SimVehicle::new(1, 1, 47.0, 8.5, 500.0);
+            vehicle.run(&sim).unwrap_err();
+            vehicle
+        });
+
+        let mut client = FenceClient::new(Arc::new(gcs), 1, 1);
+        client.commands.timeout = Duration::from_millis(200);
+        match client.upload(&Geofence::new()) {
+            Err(FenceError::NoInclusionZone) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let area = client.upload(&fence()).unwrap();
+        assert_eq!((area.p1, area.p2), ((47.0, 8.5, f32::NEG_INFINITY), (47.01, 8.51, 120.0)));
+        assert_eq!(client.download().unwrap(), area);
+        client.enable(true).unwrap();
+
+        drop(client);
+        let vehicle = sim.join().unwrap();
+        assert_eq!(vehicle.allowed_area(), Some(area));
+        assert!(vehicle.fence_enabled());
+    }
+}
@@ -0,0 +1,824 @@
+use common::{self, MavMessage};
+use connection::MavConnection;
+use Header;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Length of the `payload` of `FILE_TRANSFER_PROTOCOL`.
+pub const FTP_PAYLOAD_LEN: usize = 251;
+
+/// Length of the header at the start of the FTP payload.
+const FTP_HEADER_LEN: usize = 12;
+
+/// Maximum number of data bytes in one FTP message.
+pub const FTP_DATA_LEN: usize = FTP_PAYLOAD_LEN - FTP_HEADER_LEN;
+
+/// Operations of the MAVLink FTP protocol, and the `Ack` and `Nak` replies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FtpOpcode {
+    None = 0,
+    TerminateSession = 1,
+    ResetSessions = 2,
+    ListDirectory = 3,
+    OpenFileRO = 4,
+    ReadFile = 5,
+    CreateFile = 6,
+    WriteFile = 7,
+    RemoveFile = 8,
+    CreateDirectory = 9,
+    RemoveDirectory = 10,
+    OpenFileWO = 11,
+    TruncateFile = 12,
+    Rename = 13,
+    CalcFileCRC32 = 14,
+    BurstReadFile = 15,
+    Ack = 128,
+    Nak = 129,
+}
+
+impl FtpOpcode {
+    pub fn from_u8(value: u8) -> Option<FtpOpcode> {
+        use self::FtpOpcode::*;
+        match value {
+            0 => Some(None),
+            1 => Some(TerminateSession),
+            2 => Some(ResetSessions),
+            3 => Some(ListDirectory),
+            4 => Some(OpenFileRO),
+            5 => Some(ReadFile),
+            6 => Some(CreateFile),
+            7 => Some(WriteFile),
+            8 => Some(RemoveFile),
+            9 => Some(CreateDirectory),
+            10 => Some(RemoveDirectory),
+            11 => Some(OpenFileWO),
+            12 => Some(TruncateFile),
+            13 => Some(Rename),
+            14 => Some(CalcFileCRC32),
+            15 => Some(BurstReadFile),
+            128 => Some(Ack),
+            129 => Some(Nak),
+            _ => Option::None,
+        }
+    }
+}
+
+/// Error code carried in the data of a `Nak`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FtpNak {
+    Fail,
+    /// Failure with the errno of the vehicle's file system.
+    FailErrno(u8),
+    InvalidDataSize,
+    InvalidSession,
+    NoSessionsAvailable,
+    Eof,
+    UnknownCommand,
+    FileExists,
+    FileProtected,
+    FileNotFound,
+    Unknown(u8),
+}
+
+impl FtpNak {
+    pub fn from_data(data: &[u8]) -> FtpNak {
+        match data.first().cloned().unwrap_or(1) {
+            1 => FtpNak::Fail,
+            2 => FtpNak::FailErrno(data.get(1).cloned().unwrap_or(0)),
+            3 => FtpNak::InvalidDataSize,
+            4 => FtpNak::InvalidSession,
+            5 => FtpNak::NoSessionsAvailable,
+            6 => FtpNak::Eof,
+            7 => FtpNak::UnknownCommand,
+            8 => FtpNak::FileExists,
+            9 => FtpNak::FileProtected,
+            10 => FtpNak::FileNotFound,
+            code => FtpNak::Unknown(code),
+        }
+    }
+
+    pub fn to_data(&self) -> Vec<u8> {
+        match *self {
+            FtpNak::Fail => vec![1],
+            FtpNak::FailErrno(errno) => vec![2, errno],
+            FtpNak::InvalidDataSize => vec![3],
+            FtpNak::InvalidSession => vec![4],
+            FtpNak::NoSessionsAvailable => vec![5],
+            FtpNak::Eof => vec![6],
+            FtpNak::UnknownCommand => vec![7],
+            FtpNak::FileExists => vec![8],
+            FtpNak::FileProtected => vec![9],
+            FtpNak::FileNotFound => vec![10],
+            FtpNak::Unknown(code) => vec![code],
+        }
+    }
+}
+
+/// The FTP message carried in the payload of `FILE_TRANSFER_PROTOCOL`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FtpPayload {
+    pub seq: u16,
+    pub session: u8,
+    pub opcode: u8,
+    /// For replies, the opcode of the request being answered.
+    pub req_opcode: u8,
+    /// Set on the last reply of a burst read.
+    pub burst_complete: bool,
+    pub offset: u32,
+    /// At most `FTP_DATA_LEN` bytes; its length is sent as the size field.
+    pub data: Vec<u8>,
+}
+
+impl FtpPayload {
+    pub fn new(opcode: FtpOpcode) -> FtpPayload {
+        FtpPayload {
+            seq: 0,
+            session: 0,
+            opcode: opcode as u8,
+            req_opcode: 0,
+            burst_complete: false,
+            offset: 0,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<FtpPayload> {
+        if payload.len() < FTP_HEADER_LEN {
+            return None;
+        }
+        let mut cur = io::Cursor::new(payload);
+        let seq = cur.read_u16::<LittleEndian>().unwrap();
+        let session = cur.read_u8().unwrap();
+        let opcode = cur.read_u8().unwrap();
+        let size = cur.read_u8().unwrap() as usize;
+        let req_opcode = cur.read_u8().unwrap();
+        let burst_complete = cur.read_u8().unwrap() != 0;
+        let _padding = cur.read_u8().unwrap();
+        let offset = cur.read_u32::<LittleEndian>().unwrap();
+        if FTP_HEADER_LEN + size > payload.len() {
+            return None;
+        }
+        Some(FtpPayload {
+            seq: seq,
+            session: session,
+            opcode: opcode,
+            req_opcode: req_opcode,
+            burst_complete: burst_complete,
+            offset: offset,
+            data: payload[FTP_HEADER_LEN..FTP_HEADER_LEN + size].to_vec(),
+        })
+    }
+
+    /// Encode into a zero padded payload of `FTP_PAYLOAD_LEN` bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut wtr = Vec::with_capacity(FTP_PAYLOAD_LEN);
+        wtr.write_u16::<LittleEndian>(self.seq).unwrap();
+        wtr.write_u8(self.session).unwrap();
+        wtr.write_u8(self.opcode).unwrap();
+        wtr.write_u8(self.data.len().min(FTP_DATA_LEN) as u8).unwrap();
+        wtr.write_u8(self.req_opcode).unwrap();
+        wtr.write_u8(self.burst_complete as u8).unwrap();
+        wtr.write_u8(0).unwrap();
+        wtr.write_u32::<LittleEndian>(self.offset).unwrap();
+        wtr.extend_from_slice(&self.data[..self.data.len().min(FTP_DATA_LEN)]);
+        wtr.resize(FTP_PAYLOAD_LEN, 0);
+        wtr
+    }
+
+    pub fn message(&self, target_system: u8, target_component: u8) -> MavMessage {
+        MavMessage::FILE_TRANSFER_PROTOCOL(common::FILE_TRANSFER_PROTOCOL_DATA {
+            target_network: 0,
+            target_system: target_system,
+            target_component: target_component,
+            payload: self.encode(),
+        })
+    }
+}
+
+/// The CRC32 used by `CalcFileCRC32`, to compare against a local copy of a file.
+///
+/// This is the usual reflected polynomial, but starting from zero with no final inversion.
+pub fn ftp_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// An entry of a directory listing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DirEntry {
+    File { name: String, size: u32 },
+    Directory(String),
+}
+
+/// Failure of an FTP operation.
+#[derive(Debug)]
+pub enum FtpError {
+    Io(io::Error),
+    /// The vehicle stopped responding and retries were exhausted.
+    Timeout,
+    /// The vehicle refused the operation.
+    Nak(FtpNak),
+    /// The vehicle replied with something that does not fit the protocol.
+    InvalidReply,
+}
+
+impl From<io::Error> for FtpError {
+    fn from(e: io::Error) -> FtpError {
+        if e.kind() == io::ErrorKind::TimedOut {
+            FtpError::Timeout
+        } else {
+            FtpError::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for FtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FtpError::Io(ref e) => write!(f, "{}", e),
+            FtpError::Timeout => write!(f, "file transfer timed out"),
+            FtpError::Nak(nak) => write!(f, "file transfer refused: {:?}", nak),
+            FtpError::InvalidReply => write!(f, "invalid file transfer reply"),
+        }
+    }
+}
+
+impl Error for FtpError {
+    fn description(&self) -> &str {
+        "file transfer failed"
+    }
+}
+
+/// Client for the MAVLink FTP protocol of a single component.
+pub struct FtpClient<'a> {
+    conn: &'a MavConnection,
+    pub target_system: u8,
+    pub target_component: u8,
+    /// How long to wait for a reply before re-sending a request.
+    pub timeout: Duration,
+    /// How many times a request is re-sent without a reply before giving up.
+    pub retries: usize,
+    /// Read files with `BurstReadFile`, falling back to `ReadFile` for lost parts.
+    pub burst: bool,
+    seq: Cell<u16>,
+}
+
+impl<'a> FtpClient<'a> {
+    pub fn new(conn: &'a MavConnection, target_system: u8, target_component: u8) -> FtpClient<'a> {
+        FtpClient {
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+            timeout: Duration::from_millis(1000),
+            retries: 5,
+            burst: true,
+            seq: Cell::new(0),
+        }
+    }
+
+    fn send(&self, payload: &FtpPayload) -> io::Result<()> {
+        self.conn.send(&payload.message(self.target_system, self.target_component))
+    }
+
+    /// Wait until `deadline` for an FTP reply from the target.
+    fn recv(&self, deadline: Instant) -> Result<FtpPayload, FtpError> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(FtpError::Timeout);
+            }
+            let (header, msg) = try!(self.conn.recv_frame_timeout(deadline - now));
+            if !self.is_target(&header) {
+                continue;
+            }
+            if let MavMessage::FILE_TRANSFER_PROTOCOL(ref ftp) = msg {
+                if let Some(payload) = FtpPayload::decode(&ftp.payload) {
+                    return Ok(payload);
+                }
+            }
+        }
+    }
+
+    fn is_target(&self, header: &Header) -> bool {
+        header.system_id == self.target_system &&
+            (self.target_component == 0 || header.component_id == self.target_component)
+    }
+
+    /// Send a request with the next sequence number and wait for its `Ack`, re-sending the same
+    /// request on timeout so that the vehicle can recognise it as a retransmission.
+    fn request(&self, mut payload: FtpPayload) -> Result<FtpPayload, FtpError> {
+        let seq = self.seq.get();
+        self.seq.set(seq.wrapping_add(1));
+        payload.seq = seq;
+
+        for _ in 0..self.retries + 1 {
+            try!(self.send(&payload));
+            let deadline = Instant::now() + self.timeout;
+            loop {
+                match self.recv(deadline) {
+                    Ok(reply) => {
+                        if reply.seq != seq.wrapping_add(1) || reply.req_opcode != payload.opcode {
+                            continue;
+                        }
+                        return match FtpOpcode::from_u8(reply.opcode) {
+                            Some(FtpOpcode::Ack) => Ok(reply),
+                            Some(FtpOpcode::Nak) => Err(FtpError::Nak(FtpNak::from_data(&reply.data))),
+                            _ => Err(FtpError::InvalidReply),
+                        };
+                    }
+                    Err(FtpError::Timeout) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(FtpError::Timeout)
+    }
+
+    fn path_request(&self, opcode: FtpOpcode, path: &str) -> Result<FtpPayload, FtpError> {
+        let mut payload = FtpPayload::new(opcode);
+        payload.data = path.as_bytes().to_vec();
+        self.request(payload)
+    }
+
+    /// Drop all open sessions on the vehicle, e.g. after an earlier client went away mid-transfer.
+    pub fn reset_sessions(&self) -> Result<(), FtpError> {
+        self.request(FtpPayload::new(FtpOpcode::ResetSessions)).map(|_| ())
+    }
+
+    fn terminate(&self, session: u8) -> Result<(), FtpError> {
+        let mut payload = FtpPayload::new(FtpOpcode::TerminateSession);
+        payload.session = session;
+        self.request(payload).map(|_| ())
+    }
+
+    /// List the files and directories in `path`.
+    pub fn list_directory(&self, path: &str) -> Result<Vec<DirEntry>, FtpError> {
+        let mut entries = Vec::new();
+        // The offset counts entries, including skipped ones.
+        let mut offset = 0;
+        loop {
+            let mut payload = FtpPayload::new(FtpOpcode::ListDirectory);
+            payload.offset = offset;
+            payload.data = path.as_bytes().to_vec();
+            let reply = match self.request(payload) {
+                Ok(reply) => reply,
+                Err(FtpError::Nak(FtpNak::Eof)) => return Ok(entries),
+                Err(e) => return Err(e),
+            };
+
+            let listed: Vec<&[u8]> = reply.data.split(|&b| b == 0).filter(|e| !e.is_empty()).collect();
+            if listed.is_empty() {
+                return Ok(entries);
+            }
+            offset += listed.len() as u32;
+            for entry in listed {
+                let text = String::from_utf8_lossy(&entry[1..]).into_owned();
+                match entry[0] {
+                    b'F' => {
+                        let mut parts = text.splitn(2, '\t');
+                        let name = parts.next().unwrap_or("").to_string();
+                        let size = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+                        entries.push(DirEntry::File { name: name, size: size });
+                    }
+                    b'D' => entries.push(DirEntry::Directory(text)),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Read the whole of a file.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FtpError> {
+        self.read_file_with_progress(path, |_, _| ())
+    }
+
+    /// Read the whole of a file, calling `progress` with the bytes received so far and the size.
+    ///
+    /// Parts lost from a burst are fetched again with individual reads, and a burst that stalls
+    /// is resumed from the first missing byte.
+    pub fn read_file_with_progress<F>(&self, path: &str, mut progress: F) -> Result<Vec<u8>, FtpError>
+        where F: FnMut(usize, usize)
+    {
+        let open = try!(self.path_request(FtpOpcode::OpenFileRO, path));
+        if open.data.len() < 4 {
+            return Err(FtpError::InvalidReply);
+        }
+        let session = open.session;
+        let size = io::Cursor::new(&open.data[..4]).read_u32::<LittleEndian>().unwrap() as usize;
+
+        let mut data = vec![0; size];
+        let mut received = Ranges::new();
+        let result = self.read_session(session, &mut data, &mut received, &mut progress);
+        let terminated = self.terminate(session);
+        try!(result);
+        try!(terminated);
+        Ok(data)
+    }
+
+    fn read_session<F>(&self,
+                       session: u8,
+                       data: &mut Vec<u8>,
+                       received: &mut Ranges,
+                       progress: &mut F)
+                       -> Result<(), FtpError>
+        where F: FnMut(usize, usize)
+    {
+        let size = data.len();
+        let mut stalls = 0;
+        while let Some((start, end)) = received.first_gap(size) {
+            let before = received.total();
+            // Only burst into the unread tail of the file; holes are filled one read at a time.
+            if self.burst && end == size && received.after(start) {
+                try!(self.burst_read(session, start, data, received, progress));
+            } else {
+                let mut payload = FtpPayload::new(FtpOpcode::ReadFile);
+                payload.session = session;
+                payload.offset = start as u32;
+                payload.data = vec![0; (end - start).min(FTP_DATA_LEN)];
+                let reply = match self.request(payload) {
+                    Ok(reply) => reply,
+                    Err(FtpError::Nak(FtpNak::Eof)) => return Err(FtpError::InvalidReply),
+                    Err(e) => return Err(e),
+                };
+                try!(store(data, received, start, &reply.data));
+                progress(received.total(), size);
+            }
+
+            if received.total() == before {
+                stalls += 1;
+                if stalls > self.retries {
+                    return Err(FtpError::Timeout);
+                }
+            } else {
+                stalls = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Request a burst from `offset` and take in replies until it completes or stalls.
+    fn burst_read<F>(&self,
+                     session: u8,
+                     offset: usize,
+                     data: &mut Vec<u8>,
+                     received: &mut Ranges,
+                     progress: &mut F)
+                     -> Result<(), FtpError>
+        where F: FnMut(usize, usize)
+    {
+        let mut payload = FtpPayload::new(FtpOpcode::BurstReadFile);
+        payload.seq = self.seq.get();
+        self.seq.set(payload.seq.wrapping_add(1));
+        payload.session = session;
+        payload.offset = offset as u32;
+        try!(self.send(&payload));
+
+        loop {
+            let reply = match self.recv(Instant::now() + self.timeout) {
+                Ok(reply) => reply,
+                Err(FtpError::Timeout) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if reply.req_opcode != FtpOpcode::BurstReadFile as u8 || reply.session != session {
+                continue;
+            }
+            match FtpOpcode::from_u8(reply.opcode) {
+                Some(FtpOpcode::Ack) => {
+                    try!(store(data, received, reply.offset as usize, &reply.data));
+                    progress(received.total(), data.len());
+                    if reply.burst_complete {
+                        return Ok(());
+                    }
+                }
+                Some(FtpOpcode::Nak) => {
+                    return match FtpNak::from_data(&reply.data) {
+                        FtpNak::Eof => Ok(()),
+                        nak => Err(FtpError::Nak(nak)),
+                    };
+                }
+                _ => return Err(FtpError::InvalidReply),
+            }
+        }
+    }
+
+    /// Create or replace a file with `contents`.
+    pub fn write_file(&self, path: &str, contents: &[u8]) -> Result<(), FtpError> {
+        let session = match self.path_request(FtpOpcode::CreateFile, path) {
+            Ok(reply) => reply.session,
+            Err(FtpError::Nak(FtpNak::FileExists)) => {
+                try!(self.remove_file(path));
+                try!(self.path_request(FtpOpcode::CreateFile, path)).session
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut result = Ok(());
+        for (i, chunk) in contents.chunks(FTP_DATA_LEN).enumerate() {
+            let mut payload = FtpPayload::new(FtpOpcode::WriteFile);
+            payload.session = session;
+            payload.offset = (i * FTP_DATA_LEN) as u32;
+            payload.data = chunk.to_vec();
+            result = self.request(payload).map(|_| ());
+            if result.is_err() {
+                break;
+            }
+        }
+        let terminated = self.terminate(session);
+        try!(result);
+        terminated
+    }
+
+    pub fn remove_file(&self, path: &str) -> Result<(), FtpError> {
+        self.path_request(FtpOpcode::RemoveFile, path).map(|_| ())
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<(), FtpError> {
+        self.path_request(FtpOpcode::CreateDirectory, path).map(|_| ())
+    }
+
+    pub fn remove_directory(&self, path: &str) -> Result<(), FtpError> {
+        self.path_request(FtpOpcode::RemoveDirectory, path).map(|_| ())
+    }
+
+    /// The CRC32 of a file as computed by the vehicle, see `ftp_crc32`.
+    pub fn crc32(&self, path: &str) -> Result<u32, FtpError> {
+        let reply = try!(self.path_request(FtpOpcode::CalcFileCRC32, path));
+        if reply.data.len() < 4 {
+            return Err(FtpError::InvalidReply);
+        }
+        Ok(io::Cursor::new(&reply.data[..4]).read_u32::<LittleEndian>().unwrap())
+    }
+}
+
+/// Copy a chunk of file data into place, recording it as received.
+fn store(data: &mut Vec<u8>, received: &mut Ranges, offset: usize, chunk: &[u8]) -> Result<(), FtpError> {
+    if offset + chunk.len() > data.len() {
+        return Err(FtpError::InvalidReply);
+    }
+    data[offset..offset + chunk.len()].copy_from_slice(chunk);
+    received.insert(offset, offset + chunk.len());
+    Ok(())
+}
+
+/// Sorted, non-overlapping byte ranges `start..end` of a file that have been received.
+struct Ranges(Vec<(usize, usize)>);
+
+impl Ranges {
+    fn new() -> Ranges {
+        Ranges(Vec::new())
+    }
+
+    fn insert(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        self.0.push((start, end));
+        self.0.sort();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for &(s, e) in &self.0 {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.0 = merged;
+    }
+
+    fn total(&self) -> usize {
+        self.0.iter().map(|&(s, e)| e - s).sum()
+    }
+
+    /// The first range of `0..size` not yet received.
+    fn first_gap(&self, size: usize) -> Option<(usize, usize)> {
+        let mut pos = 0;
+        for &(s, e) in &self.0 {
+            if s > pos {
+                return Some((pos, s));
+            }
+            pos = e;
+        }
+        if pos < size { Some((pos, size)) } else { None }
+    }
+
+    /// Whether nothing at or after `offset` has been received.
+    fn after(&self, offset: usize) -> bool {
+        self.0.iter().all(|&(_, e)| e <= offset)
+    }
+}
+
+#[cfg(test)]
+mod test_ftp {
+    use super::*;
+    use loopback::{loopback_with, LoopbackConfig};
+    use std::collections::HashMap;
+    use std::thread;
+
+    /// Packets sent per burst by the fake vehicle.
+    const BURST_LEN: usize = 8;
+
+    /// A vehicle with an in-memory file system, served until the client hangs up.
+    ///
+    /// Like a real implementation it answers a repeated sequence number with its previous reply.
+    fn serve(conn: &MavConnection, mut files: HashMap<String, Vec<u8>>) -> HashMap<String, Vec<u8>> {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut sessions: HashMap<u8, String> = HashMap::new();
+        let mut next_session = 0;
+        let mut last: Option<(u16, FtpPayload)> = None;
+
+        while let Ok(msg) = conn.recv() {
+            let request = match msg {
+                MavMessage::FILE_TRANSFER_PROTOCOL(ftp) => FtpPayload::decode(&ftp.payload).unwrap(),
+                _ => continue,
+            };
+            let reply_to = |opcode: FtpOpcode, data: Vec<u8>| FtpPayload {
+                seq: request.seq.wrapping_add(1),
+                session: request.session,
+                opcode: opcode as u8,
+                req_opcode: request.opcode,
+                burst_complete: false,
+                offset: request.offset,
+                data: data,
+            };
+            let path = String::from_utf8(request.data.clone()).unwrap_or_default();
+
+            if let Some((seq, ref reply)) = last {
+                if seq == request.seq && request.opcode != FtpOpcode::BurstReadFile as u8 {
+                    conn.send_frame(header, &reply.message(255, 0)).ok();
+                    continue;
+                }
+            }
+
+            let reply = match FtpOpcode::from_u8(request.opcode).unwrap() {
+                FtpOpcode::ListDirectory => {
+                    let mut names: Vec<_> = files.keys().filter(|f| f.starts_with(&path)).cloned().collect();
+                    names.sort();
+                    let entries: Vec<u8> = names.iter()
+                        .skip(request.offset as usize)
+                        .take(2)
+                        .flat_map(|name| format!("F{}\t{}\0", &name[path.len() + 1..], files[name].len()).into_bytes())
+                        .collect();
+                    if entries.is_empty() {
+                        reply_to(FtpOpcode::Nak, FtpNak::Eof.to_data())
+                    } else {
+                        reply_to(FtpOpcode::Ack, entries)
+                    }
+                }
+                FtpOpcode::OpenFileRO if files.contains_key(&path) => {
+                    next_session += 1;
+                    sessions.insert(next_session, path.clone());
+                    let mut reply = reply_to(FtpOpcode::Ack, vec![]);
+                    reply.session = next_session;
+                    reply.data.write_u32::<LittleEndian>(files[&path].len() as u32).unwrap();
+                    reply
+                }
+                FtpOpcode::CreateFile if files.contains_key(&path) => {
+                    reply_to(FtpOpcode::Nak, FtpNak::FileExists.to_data())
+                }
+                FtpOpcode::CreateFile => {
+                    next_session += 1;
+                    sessions.insert(next_session, path.clone());
+                    files.insert(path, Vec::new());
+                    let mut reply = reply_to(FtpOpcode::Ack, vec![]);
+                    reply.session = next_session;
+                    reply
+                }
+                FtpOpcode::ReadFile => {
+                    let file = &files[&sessions[&request.session]];
+                    let start = request.offset as usize;
+                    if start >= file.len() {
+                        reply_to(FtpOpcode::Nak, FtpNak::Eof.to_data())
+                    } else {
+                        let end = (start + request.data.len()).min(file.len());
+                        reply_to(FtpOpcode::Ack, file[start..end].to_vec())
+                    }
+                }
+                FtpOpcode::BurstReadFile => {
+                    let file = &files[&sessions[&request.session]];
+                    let mut offset = request.offset as usize;
+                    let mut seq = request.seq;
+                    for i in 0..BURST_LEN {
+                        seq = seq.wrapping_add(1);
+                        let mut reply = reply_to(FtpOpcode::Ack, vec![]);
+                        reply.seq = seq;
+                        reply.offset = offset as u32;
+                        if offset >= file.len() {
+                            reply.opcode = FtpOpcode::Nak as u8;
+                            reply.data = FtpNak::Eof.to_data();
+                            conn.send_frame(header, &reply.message(255, 0)).ok();
+                            break;
+                        }
+                        let end = (offset + FTP_DATA_LEN).min(file.len());
+                        reply.data = file[offset..end].to_vec();
+                        reply.burst_complete = i == BURST_LEN - 1;
+                        conn.send_frame(header, &reply.message(255, 0)).ok();
+                        offset = end;
+                    }
+                    continue;
+                }
+                FtpOpcode::WriteFile => {
+                    let file = files.get_mut(&sessions[&request.session]).unwrap();
+                    let end = request.offset as usize + request.data.len();
+                    if file.len() < end {
+                        file.resize(end, 0);
+                    }
+                    file[request.offset as usize..end].copy_from_slice(&request.data);
+                    reply_to(FtpOpcode::Ack, vec![])
+                }
+                FtpOpcode::TerminateSession => {
+                    sessions.remove(&request.session);
+                    reply_to(FtpOpcode::Ack, vec![])
+                }
+                FtpOpcode::RemoveFile if files.remove(&path).is_some() => reply_to(FtpOpcode::Ack, vec![]),
+                FtpOpcode::CalcFileCRC32 if files.contains_key(&path) => {
+                    let mut data = vec![];
+                    data.write_u32::<LittleEndian>(ftp_crc32(&files[&path])).unwrap();
+                    reply_to(FtpOpcode::Ack, data)
+                }
+                FtpOpcode::OpenFileRO | FtpOpcode::RemoveFile | FtpOpcode::CalcFileCRC32 => {
+                    reply_to(FtpOpcode::Nak, FtpNak::FileNotFound.to_data())
+                }
+                _ => reply_to(FtpOpcode::Nak, FtpNak::UnknownCommand.to_data()),
+            };
+            conn.send_frame(header, &reply.message(255, 0)).ok();
+            last = Some((request.seq, reply));
+        }
+        files
+    }
+
+    #[test]
+    pub fn test_payload() {
+        let mut payload = FtpPayload::new(FtpOpcode::ReadFile);
+        payload.seq = 0x1234;
+        payload.session = 2;
+        payload.offset = 0x01020304;
+        payload.data = vec![7; 10];
+        let encoded = payload.encode();
+        assert_eq!(encoded.len(), FTP_PAYLOAD_LEN);
+        assert_eq!(&encoded[..FTP_HEADER_LEN], &[0x34, 0x12, 2, 5, 10, 0, 0, 0, 4, 3, 2, 1]);
+        assert_eq!(FtpPayload::decode(&encoded), Some(payload));
+
+        assert_eq!(ftp_crc32(b""), 0);
+        assert_eq!(ftp_crc32(b"123456789"), 0x2dfd2d88);
+    }
+
+    #[test]
+    pub fn test_transfers() {
+        let log: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut files = HashMap::new();
+        files.insert("/logs/00000001.bin".to_string(), log.clone());
+        files.insert("/logs/00000002.bin".to_string(), vec![1, 2, 3]);
+        files.insert("/logs/00000003.bin".to_string(), vec![]);
+
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || serve(&vehicle, files));
+
+        {
+            let mut client = FtpClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let entries = client.list_directory("/logs").unwrap();
+            assert_eq!(entries.len(), 3);
+            assert_eq!(entries[1], DirEntry::File { name: "00000002.bin".into(), size: 3 });
+
+            let mut last_progress = 0;
+            let data = client.read_file_with_progress("/logs/00000001.bin", |received, size| {
+                assert_eq!(size, log.len());
+                last_progress = received;
+            }).unwrap();
+            assert_eq!(data, log);
+            assert_eq!(last_progress, log.len());
+            assert_eq!(client.read_file("/logs/00000003.bin").unwrap(), vec![]);
+
+            client.burst = false;
+            assert_eq!(client.read_file("/logs/00000002.bin").unwrap(), vec![1, 2, 3]);
+
+            client.write_file("/params.parm", b"SYSID_THISMAV 1\n").unwrap();
+            client.write_file("/params.parm", &log[..1000]).unwrap();
+            assert_eq!(client.crc32("/params.parm").unwrap(), ftp_crc32(&log[..1000]));
+
+            client.remove_file("/logs/00000002.bin").unwrap();
+            match client.remove_file("/logs/00000002.bin") {
+                Err(FtpError::Nak(FtpNak::FileNotFound)) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        drop(gcs);
+        let files = vehicle.join().unwrap();
+        assert_eq!(files["/params.parm"], &log[..1000]);
+        assert!(!files.contains_key("/logs/00000002.bin"));
+    }
+}
@@ -0,0 +1,20 @@
+use std::f64::consts::PI;
+
+/// Meters per degree of latitude, on a spherical earth.
+pub const METERS_PER_DEGREE: f64 = 111_319.5;
+
+/// Meters north and east from the first position to the second, both in degrees.
+///
+/// Treats the earth as flat around the two positions, so it is only accurate for short
+/// distances away from the poles.
+pub fn offset(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> (f64, f64) {
+    let north = (lat2 - lat1) * METERS_PER_DEGREE;
+    let east = (lon2 - lon1) * METERS_PER_DEGREE * ((lat1 + lat2) / 2.0 * PI / 180.0).cos();
+    (north, east)
+}
+
+/// Horizontal distance in meters between two positions in degrees, for short distances.
+pub fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (north, east) = offset(lat1, lon1, lat2, lon2);
+    (north * north + east * east).sqrt()
+}
@@ -0,0 +1,242 @@
+use common::{self, MavMessage, MAV_AUTOPILOT, MAV_STATE, MAV_TYPE};
+use connection::MavConnection;
+use periodic::PeriodicSender;
+use Header;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The contents of a `HEARTBEAT` sent by this system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub mavtype: MAV_TYPE,
+    pub autopilot: MAV_AUTOPILOT,
+    /// `MAV_MODE_FLAG` bits.
+    pub base_mode: u8,
+    pub custom_mode: u32,
+    pub system_status: MAV_STATE,
+}
+
+impl Default for Heartbeat {
+    /// A ground control station.
+    fn default() -> Heartbeat {
+        Heartbeat {
+            mavtype: MAV_TYPE::MAV_TYPE_GCS,
+            autopilot: MAV_AUTOPILOT::MAV_AUTOPILOT_INVALID,
+            base_mode: 0,
+            custom_mode: 0,
+            system_status: MAV_STATE::MAV_STATE_ACTIVE,
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn message(&self) -> MavMessage {
+        MavMessage::HEARTBEAT(common::HEARTBEAT_DATA {
+            custom_mode: self.custom_mode,
+            mavtype: self.mavtype as u8,
+            autopilot: self.autopilot as u8,
+            base_mode: self.base_mode,
+            system_status: self.system_status as u8,
+            mavlink_version: 0x3,
+        })
+    }
+}
+
+/// Sends a `HEARTBEAT` periodically from a background thread until dropped.
+pub struct HeartbeatEmitter {
+    sender: PeriodicSender<Heartbeat>,
+}
+
+impl HeartbeatEmitter {
+    /// Start sending `heartbeat` every `interval` with the connection's own system id.
+    pub fn start(conn: Arc<MavConnection + Sync + Send>, heartbeat: Heartbeat, interval: Duration)
+                 -> HeartbeatEmitter {
+        HeartbeatEmitter::spawn(heartbeat, interval, move |msg| conn.send(msg).is_ok())
+    }
+
+    /// Start sending `heartbeat` every `interval` as the given system and component.
+    pub fn start_as(conn: Arc<MavConnection + Sync + Send>,
+                    system_id: u8,
+                    component_id: u8,
+                    heartbeat: Heartbeat,
+                    interval: Duration)
+                    -> HeartbeatEmitter {
+        let mut sequence = 0u8;
+        HeartbeatEmitter::spawn(heartbeat, interval, move |msg| {
+            let header = Header {
+                sequence: sequence,
+                system_id: system_id,
+                component_id: component_id,
+            };
+            sequence = sequence.wrapping_add(1);
+            conn.send_frame(header, msg).is_ok()
+        })
+    }
+
+    fn spawn<F>(heartbeat: Heartbeat, interval: Duration, send: F) -> HeartbeatEmitter
+        where F: FnMut(&MavMessage) -> bool + Send + 'static
+    {
+        let next = |heartbeat: &mut Heartbeat, stopping: bool| {
+            if stopping { None } else { Some(heartbeat.message()) }
+        };
+        HeartbeatEmitter {
+            sender: PeriodicSender::start(heartbeat, interval, next, send),
+        }
+    }
+
+    /// The heartbeat currently being sent.
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.sender.with(|current| current.clone())
+    }
+
+    /// Change the heartbeat, e.g. on a mode or state change, starting with the next one sent.
+    pub fn set(&self, heartbeat: Heartbeat) {
+        self.sender.with(|current| *current = heartbeat);
+    }
+}
+
+/// A system or component known from its heartbeats.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub system_id: u8,
+    pub component_id: u8,
+    /// The most recent heartbeat.
+    pub heartbeat: common::HEARTBEAT_DATA,
+    pub last_seen: Instant,
+    /// Whether no heartbeat arrived within the tracker's timeout.
+    pub lost: bool,
+}
+
+/// A change in the liveness of a peer, identified by system and component id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The first heartbeat from the peer.
+    New(u8, u8),
+    /// No heartbeat arrived from the peer within the timeout.
+    Lost(u8, u8),
+    /// A heartbeat arrived from a lost peer.
+    Regained(u8, u8),
+}
+
+/// Tracks every system and component seen sending `HEARTBEAT`.
+///
+/// The tracker does not read from a connection itself: pass it every received frame with
+/// `handle`, and call `check` regularly to find peers that have gone quiet.
+pub struct HeartbeatTracker {
+    /// How long a peer may go without a heartbeat before it is considered lost.
+    pub timeout: Duration,
+    peers: HashMap<(u8, u8), Peer>,
+}
+
+impl HeartbeatTracker {
+    pub fn new(timeout: Duration) -> HeartbeatTracker {
+        HeartbeatTracker {
+            timeout: timeout,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record a received message, returning an event if it is the heartbeat of a new or lost peer.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Option<PeerEvent> {
+        let heartbeat = match *msg {
+            MavMessage::HEARTBEAT(ref heartbeat) => heartbeat,
+            _ => return None,
+        };
+        let key = (header.system_id, header.component_id);
+        let now = Instant::now();
+
+        if let Some(peer) = self.peers.get_mut(&key) {
+            peer.heartbeat = heartbeat.clone();
+            peer.last_seen = now;
+            if peer.lost {
+                peer.lost = false;
+                return Some(PeerEvent::Regained(key.0, key.1));
+            }
+            return None;
+        }
+
+        self.peers.insert(key, Peer {
+            system_id: key.0,
+            component_id: key.1,
+            heartbeat: heartbeat.clone(),
+            last_seen: now,
+            lost: false,
+        });
+        Some(PeerEvent::New(key.0, key.1))
+    }
+
+    /// Mark peers whose last heartbeat is older than the timeout as lost, returning an event for
+    /// each newly lost one.
+    pub fn check(&mut self) -> Vec<PeerEvent> {
+        let now = Instant::now();
+        let mut events = Vec::new();
+        for peer in self.peers.values_mut() {
+            if !peer.lost && now.duration_since(peer.last_seen) > self.timeout {
+                peer.lost = true;
+                events.push(PeerEvent::Lost(peer.system_id, peer.component_id));
+            }
+        }
+        events
+    }
+
+    pub fn peer(&self, system_id: u8, component_id: u8) -> Option<&Peer> {
+        self.peers.get(&(system_id, component_id))
+    }
+
+    /// All peers ever seen, including lost ones.
+    pub fn peers(&self) -> Vec<&Peer> {
+        self.peers.values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test_heartbeat {
+    use super::*;
+    use loopback::loopback;
+    use connection::MavConnection;
+
+    fn wait_for_event(tracker: &mut HeartbeatTracker, conn: &MavConnection) -> PeerEvent {
+        loop {
+            let event = match conn.recv_frame_timeout(Duration::from_millis(10)) {
+                Ok((header, msg)) => tracker.handle(&header, &msg),
+                Err(_) => tracker.check().pop(),
+            };
+            if let Some(event) = event {
+                return event;
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_emit_and_track() {
+        let (vehicle, gcs) = loopback();
+        let vehicle: Arc<MavConnection + Sync + Send> = Arc::new(vehicle);
+        let interval = Duration::from_millis(20);
+        let mut tracker = HeartbeatTracker::new(Duration::from_millis(100));
+
+        let heartbeat = Heartbeat {
+            mavtype: MAV_TYPE::MAV_TYPE_QUADROTOR,
+            autopilot: MAV_AUTOPILOT::MAV_AUTOPILOT_PX4,
+            ..Heartbeat::default()
+        };
+        let emitter = HeartbeatEmitter::start_as(vehicle.clone(), 1, 1, heartbeat.clone(), interval);
+        assert_eq!(wait_for_event(&mut tracker, &gcs), PeerEvent::New(1, 1));
+        assert_eq!(tracker.peer(1, 1).unwrap().heartbeat.mavtype, MAV_TYPE::MAV_TYPE_QUADROTOR as u8);
+
+        emitter.set(Heartbeat { custom_mode: 4, ..heartbeat.clone() });
+        while tracker.peer(1, 1).unwrap().heartbeat.custom_mode != 4 {
+            let (header, msg) = gcs.recv_frame().unwrap();
+            assert_eq!(tracker.handle(&header, &msg), None);
+        }
+
+        drop(emitter);
+        assert_eq!(wait_for_event(&mut tracker, &gcs), PeerEvent::Lost(1, 1));
+        assert!(tracker.peer(1, 1).unwrap().lost);
+
+        let _emitter = HeartbeatEmitter::start_as(vehicle.clone(), 1, 1, heartbeat, interval);
+        assert_eq!(wait_for_event(&mut tracker, &gcs), PeerEvent::Regained(1, 1));
+        assert_eq!(tracker.peers().len(), 1);
+    }
+}
@@ -1,5 +1,9 @@
 extern crate byteorder;
 extern crate crc16;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "websocket")]
+extern crate tungstenite;
 
 use std::io;
 use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
@@ -7,6 +11,80 @@ use std::io::prelude::*;
 
 mod connection;
 pub use connection::{ MavConnection, Tcp, Udp, connect };
+#[cfg(unix)]
+pub use connection::{ Unix, UnixGram };
+
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "websocket")]
+pub use websocket::WebSocket;
+
+mod loopback;
+pub use loopback::{ Loopback, LoopbackConfig, loopback, loopback_with };
+
+mod router;
+pub use router::Router;
+
+mod param;
+pub use param::{ ParamClient, Param, ParamValue, ParamEncoding, param_id, param_name };
+
+mod param_server;
+pub use param_server::{ ParamServer, ParamStorage, MemoryStorage, FileStorage };
+
+mod mission;
+pub use mission::{ MissionClient, MissionItem, MissionError, is_global_frame };
+
+mod mission_server;
+pub use mission_server::MissionServer;
+
+mod command;
+pub use command::{ CommandClient, CommandError, FORCE_DISARM };
+
+mod periodic;
+
+mod heartbeat;
+pub use heartbeat::{ Heartbeat, HeartbeatEmitter, HeartbeatTracker, Peer, PeerEvent };
+
+mod vehicle;
+pub use vehicle::{ Vehicle, Timestamped };
+
+mod ftp;
+pub use ftp::{ FtpClient, FtpError, FtpNak, FtpOpcode, FtpPayload, DirEntry, ftp_crc32, FTP_DATA_LEN, FTP_PAYLOAD_LEN };
+
+mod logs;
+pub use logs::{ LogClient, LogEntry, LogError, LogProgress, LOG_CHUNK_LEN };
+
+mod timesync;
+pub use timesync::TimeSync;
+
+mod rate;
+pub use rate::{ RateClient, RateMeter, RateRequest, stream_for_message };
+
+mod mode;
+pub use mode::{ Firmware, mode_name };
+
+mod geo;
+
+mod action;
+pub use action::{ ActionClient, ActionError };
+
+mod offboard;
+pub use offboard::{ SetpointStreamer, Setpoint, PositionTarget, AttitudeTarget, Divergence };
+
+mod manual;
+pub use manual::{ ManualController, ManualInput, ManualMapping, Axis };
+
+mod statustext;
+pub use statustext::{ StatusText, StatusTextAssembler, severity_level, statustext, STATUSTEXT_LEN };
+
+mod fence;
+pub use fence::{ FenceClient, FenceError, FenceEvent, FenceMonitor, FenceShape, FenceZone, Geofence, AllowedArea };
+
+mod adsb;
+pub use adsb::{ Traffic, TrafficEvent, TrafficTracker, Approach };
+
+mod sim;
+pub use sim::SimVehicle;
 
 /// The MAVLink common message set
 ///
@@ -31,8 +109,71 @@ pub struct Header {
     pub component_id: u8,
 }
 
-/// Read a MAVLink message from a Read stream.
-pub fn read<R: Read>(r: &mut R) -> io::Result<(Header, MavMessage)> {
+/// MAVLink protocol version of frames using the 0xFE start byte
+pub const MAVLINK_V1: u8 = 1;
+
+/// A MAVLink frame whose payload has not been decoded.
+///
+/// Raw frames can be forwarded or logged unchanged, including frames of messages this crate
+/// does not know how to decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub version: u8,
+    pub header: Header,
+    pub msgid: u8,
+    pub payload: Vec<u8>,
+    pub crc: u16,
+}
+
+impl RawFrame {
+    /// Serialize a message into a frame with the given header.
+    pub fn new(header: Header, data: &MavMessage) -> RawFrame {
+        let mut frame = RawFrame {
+            version: MAVLINK_V1,
+            header: header,
+            msgid: data.message_id(),
+            payload: data.serialize(),
+            crc: 0,
+        };
+        frame.crc = frame.calculate_crc();
+        frame
+    }
+
+    /// Whether the message id is one this crate can decode.
+    pub fn is_known(&self) -> bool {
+        MavMessage::payload_len(self.msgid).is_some()
+    }
+
+    /// The checksum the frame should carry, assuming the message id is known.
+    fn calculate_crc(&self) -> u16 {
+        let mut crc = crc16::State::<crc16::MCRF4XX>::new();
+        crc.update(&[
+            self.payload.len() as u8,
+            self.header.sequence,
+            self.header.system_id,
+            self.header.component_id,
+            self.msgid,
+        ]);
+        crc.update(&self.payload[..]);
+        crc.update(&[MavMessage::extra_crc(self.msgid)]);
+        crc.get()
+    }
+
+    /// Decode the payload, if the message is known, well-formed and its checksum matches.
+    pub fn decode(&self) -> Option<MavMessage> {
+        if MavMessage::payload_len(self.msgid) != Some(self.payload.len()) ||
+           self.calculate_crc() != self.crc {
+            return None;
+        }
+        MavMessage::parse(self.msgid, &self.payload[..])
+    }
+}
+
+/// Read a MAVLink frame from a Read stream without decoding it.
+///
+/// Frames of known messages with a bad checksum are skipped. The checksum of an unknown message
+/// cannot be verified, so such frames are returned as they were read.
+pub fn read_raw<R: Read>(r: &mut R) -> io::Result<RawFrame> {
     loop {
         if try!(r.read_u8()) != MAV_STX {
             continue;
@@ -43,52 +184,60 @@ pub fn read<R: Read>(r: &mut R) -> io::Result<(Header, MavMessage)> {
         let compid =  try!(r.read_u8());
         let msgid  =  try!(r.read_u8());
         
-        let mut payload_buf = [0; 255];
-        let payload = &mut payload_buf[..len];
-        try!(r.read_exact(payload));
+        let mut payload = vec![0; len];
+        try!(r.read_exact(&mut payload[..]));
         
         let crc = try!(r.read_u16::<LittleEndian>());
 
-        let mut crc_calc = crc16::State::<crc16::MCRF4XX>::new();
-        crc_calc.update(&[len as u8, seq, sysid, compid, msgid]);
-        crc_calc.update(payload);
-        crc_calc.update(&[MavMessage::extra_crc(msgid)]);
-        if crc_calc.get() != crc {
+        let frame = RawFrame {
+            version: MAVLINK_V1,
+            header: Header { sequence: seq, system_id: sysid, component_id: compid },
+            msgid: msgid,
+            payload: payload,
+            crc: crc,
+        };
+
+        if frame.is_known() && frame.calculate_crc() != crc {
             continue;
         }
-        
-        if let Some(msg) = MavMessage::parse(msgid, payload) {
-            return Ok((Header { sequence: seq, system_id: sysid, component_id: compid }, msg));
-        }
+
+        return Ok(frame);
     }
 }
 
-/// Write a MAVLink message to a Write stream.
-pub fn write<W: Write>(w: &mut W, header: Header, data: &MavMessage) -> io::Result<()> {
-    let msgid = data.message_id();
-    let payload = data.serialize();
-    
+/// Write a MAVLink frame to a Write stream exactly as it is.
+pub fn write_raw<W: Write>(w: &mut W, frame: &RawFrame) -> io::Result<()> {
     let header = &[
         MAV_STX,
-        payload.len() as u8,
-        header.sequence,
-        header.system_id,
-        header.component_id,
-        msgid,
+        frame.payload.len() as u8,
+        frame.header.sequence,
+        frame.header.system_id,
+        frame.header.component_id,
+        frame.msgid,
     ];
-    
-    let mut crc = crc16::State::<crc16::MCRF4XX>::new();
-    crc.update(&header[1..]);
-    crc.update(&payload[..]);
-    crc.update(&[MavMessage::extra_crc(msgid)]);
-    
+
     try!(w.write_all(header));
-    try!(w.write_all(&payload[..]));
-    try!(w.write_u16::<LittleEndian>(crc.get()));
+    try!(w.write_all(&frame.payload[..]));
+    try!(w.write_u16::<LittleEndian>(frame.crc));
 
     Ok(())
 }
 
+/// Read a MAVLink message from a Read stream.
+pub fn read<R: Read>(r: &mut R) -> io::Result<(Header, MavMessage)> {
+    loop {
+        let frame = try!(read_raw(r));
+        if let Some(msg) = frame.decode() {
+            return Ok((frame.header, msg));
+        }
+    }
+}
+
+/// Write a MAVLink message to a Write stream.
+pub fn write<W: Write>(w: &mut W, header: Header, data: &MavMessage) -> io::Result<()> {
+    write_raw(w, &RawFrame::new(header, data))
+}
+
 /// Create a heartbeat message
 pub fn heartbeat_message() -> common::MavMessage {
     common::MavMessage::HEARTBEAT(common::HEARTBEAT_DATA {
@@ -148,6 +297,27 @@ mod test_message {
         }
     }
     
+    #[test]
+    pub fn test_raw_frame() {
+        let mut r = HEARTBEAT;
+        let frame = read_raw(&mut r).expect("Failed to read frame");
+        assert_eq!(frame.header, HEARTBEAT_HEADER);
+        assert_eq!(frame.msgid, 0);
+        assert!(frame.decode().is_some());
+
+        let mut v = vec![];
+        write_raw(&mut v, &frame).expect("Failed to write frame");
+        assert_eq!(&v[..], HEARTBEAT);
+
+        // Unknown messages are passed through, but cannot be decoded.
+        let mut unknown = HEARTBEAT.to_vec();
+        unknown[5] = 200;
+        let frame = read_raw(&mut &unknown[..]).expect("Failed to read frame");
+        assert_eq!(frame.msgid, 200);
+        assert!(frame.decode().is_none());
+        assert!(read(&mut &unknown[..]).is_err());
+    }
+    
     #[test]
     pub fn test_write() {
         let mut v = vec![];
@@ -0,0 +1,340 @@
+use common::{self, MavMessage};
+use connection::MavConnection;
+use Header;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::io::{self, Write};
+use std::fmt;
+
+/// Number of log bytes carried by one `LOG_DATA`.
+pub const LOG_CHUNK_LEN: u32 = 90;
+
+/// A log stored on the vehicle, as reported by `LOG_ENTRY`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub id: u16,
+    pub size: u32,
+    /// UTC timestamp of the log in seconds since 1970, or 0 if not available.
+    pub time_utc: u32,
+}
+
+/// Progress of a log download.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogProgress {
+    /// Bytes received so far, including those held back waiting for a gap to be filled.
+    pub received: u32,
+    pub size: u32,
+    pub elapsed: Duration,
+}
+
+impl LogProgress {
+    /// Average throughput so far in bytes per second.
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 * 1e-9;
+        if secs > 0.0 { self.received as f64 / secs } else { 0.0 }
+    }
+}
+
+/// Failure of a log transfer.
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    /// The vehicle stopped responding and retries were exhausted.
+    Timeout,
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> LogError {
+        if e.kind() == io::ErrorKind::TimedOut {
+            LogError::Timeout
+        } else {
+            LogError::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogError::Io(ref e) => write!(f, "{}", e),
+            LogError::Timeout => write!(f, "log transfer timed out"),
+        }
+    }
+}
+
+impl Error for LogError {
+    fn description(&self) -> &str {
+        "log transfer failed"
+    }
+}
+
+/// Client for listing, downloading and erasing the onboard logs of a single component.
+pub struct LogClient<'a> {
+    conn: &'a MavConnection,
+    pub target_system: u8,
+    pub target_component: u8,
+    /// How long to wait for data before re-requesting it.
+    pub timeout: Duration,
+    /// How many times a request is re-sent without progress before giving up.
+    pub retries: usize,
+}
+
+impl<'a> LogClient<'a> {
+    pub fn new(conn: &'a MavConnection, target_system: u8, target_component: u8) -> LogClient<'a> {
+        LogClient {
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+            timeout: Duration::from_millis(1000),
+            retries: 5,
+        }
+    }
+
+    fn is_target(&self, header: &Header) -> bool {
+        header.system_id == self.target_system &&
+            (self.target_component == 0 || header.component_id == self.target_component)
+    }
+
+    /// Wait up to `timeout` for a log message from the target.
+    fn recv(&self) -> Result<MavMessage, LogError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(LogError::Timeout);
+            }
+            let (header, msg) = try!(self.conn.recv_frame_timeout(deadline - now));
+            if !self.is_target(&header) {
+                continue;
+            }
+            match msg {
+                MavMessage::LOG_ENTRY(..) | MavMessage::LOG_DATA(..) => return Ok(msg),
+                _ => (),
+            }
+        }
+    }
+
+    fn request_list(&self) -> io::Result<()> {
+        self.conn.send(&MavMessage::LOG_REQUEST_LIST(common::LOG_REQUEST_LIST_DATA {
+            start: 0,
+            end: 0xffff,
+            target_system: self.target_system,
+            target_component: self.target_component,
+        }))
+    }
+
+    fn request_data(&self, id: u16, ofs: u32, count: u32) -> io::Result<()> {
+        self.conn.send(&MavMessage::LOG_REQUEST_DATA(common::LOG_REQUEST_DATA_DATA {
+            ofs: ofs,
+            count: count,
+            id: id,
+            target_system: self.target_system,
+            target_component: self.target_component,
+        }))
+    }
+
+    /// List the logs on the vehicle, ordered by id.
+    pub fn list(&self) -> Result<Vec<LogEntry>, LogError> {
+        let mut entries = BTreeMap::new();
+        let mut total = None;
+        let mut retries = 0;
+        try!(self.request_list());
+
+        while total.map_or(true, |n| entries.len() < n) {
+            match self.recv() {
+                Ok(MavMessage::LOG_ENTRY(e)) => {
+                    total = Some(e.num_logs as usize);
+                    // An empty list is reported as a single entry with no logs.
+                    if e.num_logs > 0 {
+                        entries.insert(e.id, LogEntry { id: e.id, size: e.size, time_utc: e.time_utc });
+                    }
+                }
+                Ok(_) => (),
+                Err(LogError::Timeout) if retries < self.retries => {
+                    retries += 1;
+                    try!(self.request_list());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Download a log into `out`.
+    pub fn download<W: Write>(&self, entry: &LogEntry, out: &mut W) -> Result<(), LogError> {
+        self.download_with_progress(entry, out, |_| ())
+    }
+
+    /// Download a log into `out`, calling `progress` after every chunk received.
+    ///
+    /// The vehicle streams the log in 90 byte chunks. Chunks arriving after a gap are held back
+    /// until the missing ones have been requested again, so `out` is written strictly in order.
+    pub fn download_with_progress<W, F>(&self, entry: &LogEntry, out: &mut W, mut progress: F)
+                                        -> Result<(), LogError>
+        where W: Write,
+              F: FnMut(&LogProgress)
+    {
+        let start = Instant::now();
+        let mut size = entry.size;
+        let mut written = 0u32;
+        let mut pending: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let mut retries = 0;
+        // End of the range last requested, after which the next gap is requested.
+        let mut requested_end = size;
+        try!(self.request_data(entry.id, 0, size));
+
+        while written < size {
+            match self.recv() {
+                Ok(MavMessage::LOG_DATA(ref data)) if data.id == entry.id => {
+                    let count = (data.count as u32).min(LOG_CHUNK_LEN);
+                    // A short chunk marks the end of the log, whatever the entry said.
+                    if count < LOG_CHUNK_LEN {
+                        size = size.min(data.ofs + count);
+                    }
+                    if data.ofs < written || pending.contains_key(&data.ofs) {
+                        continue;
+                    }
+                    pending.insert(data.ofs, data.data[..count as usize].to_vec());
+                    retries = 0;
+
+                    while let Some(chunk) = pending.remove(&written) {
+                        try!(out.write_all(&chunk));
+                        written += chunk.len() as u32;
+                    }
+                    progress(&LogProgress {
+                        received: written + pending.values().map(|c| c.len() as u32).sum::<u32>(),
+                        size: size,
+                        elapsed: start.elapsed(),
+                    });
+
+                    // Once the requested range has streamed past, fetch the first gap straight away.
+                    if data.ofs + count >= requested_end.min(size) && written < size {
+                        requested_end = pending.keys().next().cloned().unwrap_or(size);
+                        try!(self.request_data(entry.id, written, requested_end - written));
+                    }
+                }
+                Ok(_) => (),
+                Err(LogError::Timeout) if retries < self.retries => {
+                    retries += 1;
+                    requested_end = pending.keys().next().cloned().unwrap_or(size);
+                    try!(self.request_data(entry.id, written, requested_end - written));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        try!(self.request_end());
+        Ok(())
+    }
+
+    /// Tell the vehicle that log transfers are over, so that it can resume logging.
+    pub fn request_end(&self) -> io::Result<()> {
+        self.conn.send(&MavMessage::LOG_REQUEST_END(common::LOG_REQUEST_END_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+        }))
+    }
+
+    /// Erase all logs. The protocol has no acknowledgement; use `list` to check the result.
+    pub fn erase(&self) -> io::Result<()> {
+        self.conn.send(&MavMessage::LOG_ERASE(common::LOG_ERASE_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_logs {
+    use super::*;
+    use loopback::{loopback_with, LoopbackConfig};
+    use std::collections::HashMap;
+    use std::thread;
+
+    /// A vehicle storing logs by id, served until the client hangs up.
+    fn serve(conn: &MavConnection, mut logs: HashMap<u16, Vec<u8>>) -> HashMap<u16, Vec<u8>> {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        while let Ok(msg) = conn.recv() {
+            match msg {
+                MavMessage::LOG_REQUEST_LIST(..) => {
+                    let mut ids: Vec<_> = logs.keys().cloned().collect();
+                    ids.sort();
+                    if ids.is_empty() {
+                        ids.push(0);
+                    }
+                    for &id in &ids {
+                        conn.send_frame(header, &MavMessage::LOG_ENTRY(common::LOG_ENTRY_DATA {
+                            time_utc: 1500000000,
+                            size: logs.get(&id).map_or(0, |l| l.len() as u32),
+                            id: id,
+                            num_logs: logs.len() as u16,
+                            last_log_num: *ids.last().unwrap(),
+                        })).ok();
+                    }
+                }
+                MavMessage::LOG_REQUEST_DATA(r) => {
+                    let log = &logs[&r.id];
+                    let end = (r.ofs as usize + r.count as usize).min(log.len());
+                    let mut ofs = r.ofs as usize;
+                    while ofs < end {
+                        let count = (end - ofs).min(LOG_CHUNK_LEN as usize);
+                        let mut data = log[ofs..ofs + count].to_vec();
+                        data.resize(LOG_CHUNK_LEN as usize, 0);
+                        conn.send_frame(header, &MavMessage::LOG_DATA(common::LOG_DATA_DATA {
+                            ofs: ofs as u32,
+                            id: r.id,
+                            count: count as u8,
+                            data: data,
+                        })).ok();
+                        ofs += count;
+                    }
+                }
+                MavMessage::LOG_ERASE(..) => logs.clear(),
+                _ => (),
+            }
+        }
+        logs
+    }
+
+    #[test]
+    pub fn test_list_download_erase() {
+        let log: Vec<u8> = (0..10000u32).map(|i| (i % 253) as u8).collect();
+        let mut logs = HashMap::new();
+        logs.insert(1, vec![1, 2, 3]);
+        logs.insert(2, log.clone());
+
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || serve(&vehicle, logs));
+
+        {
+            let mut client = LogClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let entries = client.list().unwrap();
+            assert_eq!(entries.iter().map(|e| (e.id, e.size)).collect::<Vec<_>>(), vec![(1, 3), (2, 10000)]);
+
+            let mut out = Vec::new();
+            let mut last = None;
+            client.download_with_progress(&entries[1], &mut out, |p| last = Some(p.clone())).unwrap();
+            assert_eq!(out, log);
+            let last = last.unwrap();
+            assert_eq!((last.received, last.size), (10000, 10000));
+            assert!(last.rate() > 0.0);
+
+            let mut out = Vec::new();
+            client.download(&entries[0], &mut out).unwrap();
+            assert_eq!(out, vec![1, 2, 3]);
+
+            // Nothing acknowledges the erase, so it is repeated in case it was lost.
+            while !client.list().unwrap().is_empty() {
+                client.erase().unwrap();
+            }
+        }
+
+        drop(gcs);
+        assert!(vehicle.join().unwrap().is_empty());
+    }
+}
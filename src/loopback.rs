@@ -0,0 +1,309 @@
+use common::MavMessage;
+use connection::{MavConnection, remaining};
+use {Header, RawFrame, read_raw, write_raw};
+
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use std::io;
+
+/// Impairments applied to frames crossing a loopback link.
+///
+/// Each direction of the link is impaired independently. Probabilities are in the range
+/// `0.0..=1.0`; the defaults describe a perfect link.
+#[derive(Debug, Clone)]
+pub struct LoopbackConfig {
+    /// Probability that a frame is dropped.
+    pub loss: f64,
+    /// Probability that a frame is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a frame is held back by `reorder_delay`, letting later frames overtake it.
+    pub reorder: f64,
+    /// Extra delay applied to reordered frames.
+    pub reorder_delay: Duration,
+    /// Delay between sending a frame and it becoming available to the peer.
+    pub latency: Duration,
+    /// Seed for the pseudo-random impairments, so that test runs are reproducible.
+    pub seed: u64,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> LoopbackConfig {
+        LoopbackConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            latency: Duration::from_millis(0),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+/// Create a pair of connected in-memory MAVLink endpoints.
+///
+/// Messages sent on one endpoint are framed exactly as they would be on a real link and are
+/// received by the other.
+pub fn loopback() -> (Loopback, Loopback) {
+    loopback_with(LoopbackConfig::default())
+}
+
+/// Create a pair of connected in-memory MAVLink endpoints with an impaired link.
+pub fn loopback_with(config: LoopbackConfig) -> (Loopback, Loopback) {
+    let a = Arc::new(Channel::new());
+    let b = Arc::new(Channel::new());
+    let seed = config.seed;
+    (Loopback::new(a.clone(), b.clone(), config.clone(), seed),
+     Loopback::new(b, a, config, !seed))
+}
+
+struct Packet {
+    due: Instant,
+    order: u64,
+    data: Vec<u8>,
+}
+
+struct Queue {
+    packets: Vec<Packet>,
+    closed: bool,
+}
+
+/// One direction of a loopback link.
+struct Channel {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            queue: Mutex::new(Queue { packets: Vec::new(), closed: false }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, packet: Packet) {
+        let mut queue = self.queue.lock().unwrap();
+        let pos = queue.packets.iter()
+            .position(|p| (p.due, p.order) > (packet.due, packet.order))
+            .unwrap_or(queue.packets.len());
+        queue.packets.insert(pos, packet);
+        self.ready.notify_all();
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// Small xorshift generator; impairments only need to be cheap and repeatable.
+struct Rng(u64);
+
+impl Rng {
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+struct LoopbackWrite {
+    sequence: u8,
+    order: u64,
+    rng: Rng,
+}
+
+/// In-memory MAVLink connection, created by `loopback()`
+pub struct Loopback {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+    write: Mutex<LoopbackWrite>,
+    config: LoopbackConfig,
+}
+
+impl Loopback {
+    fn new(rx: Arc<Channel>, tx: Arc<Channel>, config: LoopbackConfig, seed: u64) -> Loopback {
+        Loopback {
+            rx: rx,
+            tx: tx,
+            write: Mutex::new(LoopbackWrite {
+                sequence: 0,
+                order: 0,
+                rng: Rng(if seed == 0 { 1 } else { seed }),
+            }),
+            config: config,
+        }
+    }
+}
+
+impl Loopback {
+    fn recv_raw_until(&self, deadline: Option<Instant>) -> io::Result<RawFrame> {
+        let mut queue = self.rx.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let wait = match queue.packets.first() {
+                Some(p) if p.due <= now => None,
+                Some(p) => Some(p.due - now),
+                None if queue.closed => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "loopback peer closed"));
+                }
+                None => Some(Duration::from_secs(3600)),
+            };
+            let wait = match wait {
+                Some(wait) => match try!(remaining(deadline)) {
+                    Some(left) if left < wait => Some(left),
+                    _ => Some(wait),
+                },
+                None => None,
+            };
+
+            match wait {
+                None => {
+                    let packet = queue.packets.remove(0);
+                    if let Ok(frame) = read_raw(&mut &packet.data[..]) {
+                        return Ok(frame);
+                    }
+                }
+                Some(timeout) => {
+                    queue = self.rx.ready.wait_timeout(queue, timeout).unwrap().0;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.tx.close();
+        self.rx.close();
+    }
+}
+
+impl MavConnection for Loopback {
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        self.recv_raw_until(None)
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
+        self.recv_raw_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        let state = &mut *lock;
+
+        let header = Header {
+            sequence: state.sequence,
+            system_id: 255,
+            component_id: 0,
+        };
+
+        state.sequence = state.sequence.wrapping_add(1);
+
+        self.transmit(state, &RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        self.transmit(&mut *lock, frame)
+    }
+}
+
+impl Loopback {
+    /// Frame a message and queue it for the peer, applying the configured impairments.
+    fn transmit(&self, state: &mut LoopbackWrite, frame: &RawFrame) -> io::Result<()> {
+        if self.tx.queue.lock().unwrap().closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "loopback peer closed"));
+        }
+
+        let mut buf = Vec::new();
+        try!(write_raw(&mut buf, frame));
+
+        if state.rng.chance(self.config.loss) {
+            return Ok(());
+        }
+
+        let copies = if state.rng.chance(self.config.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut due = Instant::now() + self.config.latency;
+            if state.rng.chance(self.config.reorder) {
+                due += self.config.reorder_delay;
+            }
+            state.order += 1;
+            self.tx.push(Packet { due: due, order: state.order, data: buf.clone() });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_loopback {
+    use super::*;
+    use common;
+    use connection::MavConnection;
+    use std::time::Duration;
+
+    fn ping(seq: u32) -> MavMessage {
+        common::MavMessage::PING(common::PING_DATA {
+            time_usec: 0,
+            seq: seq,
+            target_system: 0,
+            target_component: 0,
+        })
+    }
+
+    fn ping_seq(msg: MavMessage) -> u32 {
+        match msg {
+            common::MavMessage::PING(p) => p.seq,
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    pub fn test_both_directions() {
+        let (a, b) = loopback();
+        a.send(&ping(1)).unwrap();
+        b.send(&ping(2)).unwrap();
+        assert_eq!(ping_seq(b.recv().unwrap()), 1);
+        assert_eq!(ping_seq(a.recv().unwrap()), 2);
+    }
+
+    #[test]
+    pub fn test_duplicate_and_reorder() {
+        let (a, b) = loopback_with(LoopbackConfig {
+            duplicate: 1.0,
+            ..LoopbackConfig::default()
+        });
+        a.send(&ping(1)).unwrap();
+        assert_eq!(ping_seq(b.recv().unwrap()), 1);
+        assert_eq!(ping_seq(b.recv().unwrap()), 1);
+
+        let (a, b) = loopback_with(LoopbackConfig {
+            reorder: 0.5,
+            reorder_delay: Duration::from_millis(50),
+            ..LoopbackConfig::default()
+        });
+        for i in 0..20 {
+            a.send(&ping(i)).unwrap();
+        }
+        let received: Vec<u32> = (0..20).map(|_| ping_seq(b.recv().unwrap())).collect();
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        assert!(received != sorted);
+    }
+
+    #[test]
+    pub fn test_loss_and_close() {
+        let (a, b) = loopback_with(LoopbackConfig { loss: 1.0, ..LoopbackConfig::default() });
+        a.send(&ping(1)).unwrap();
+        drop(a);
+        assert_eq!(b.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(b.send(&ping(2)).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,294 @@
+use common::{self, MavMessage};
+use connection::MavConnection;
+use periodic::PeriodicSender;
+
+use std::i16;
+use std::u16;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The state of an input device such as a gamepad.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ManualInput {
+    /// Axis positions from -1 to 1.
+    pub axes: Vec<f32>,
+    /// Pressed buttons, the lowest bit being the first button.
+    pub buttons: u16,
+}
+
+/// Maps an input axis to an output axis or channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Axis {
+    /// Index into `ManualInput::axes`.
+    pub input: usize,
+    /// Input positions closer to 0 than this count as 0.
+    pub deadband: f32,
+    /// Applied after the deadband, negative to reverse the axis.
+    pub scale: f32,
+    /// Added after scaling, e.g. 0.5 with a scale of 0.5 to map a stick to a throttle from 0 to 1.
+    pub offset: f32,
+}
+
+impl Axis {
+    pub fn new(input: usize) -> Axis {
+        Axis {
+            input: input,
+            deadband: 0.0,
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    /// The output from -1 to 1, or `None` if the input has no such axis.
+    pub fn value(&self, input: &ManualInput) -> Option<f32> {
+        input.axes.get(self.input).map(|&position| {
+            let position = if position.abs() <= self.deadband {
+                0.0
+            } else {
+                // Rescale so the output starts from 0 at the edge of the deadband.
+                position.signum() * (position.abs() - self.deadband) / (1.0 - self.deadband)
+            };
+            (position * self.scale + self.offset).max(-1.0).min(1.0)
+        })
+    }
+}
+
+/// Which message the controller sends and how the input maps to it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManualMapping {
+    /// `MANUAL_CONTROL` with the x (pitch), y (roll), z (thrust) and r (yaw) axes, plus the
+    /// input's buttons. Unmapped axes are sent as invalid.
+    ManualControl { x: Option<Axis>, y: Option<Axis>, z: Option<Axis>, r: Option<Axis> },
+    /// `RC_CHANNELS_OVERRIDE` for channels 1 to 8, from -1 at 1000 µs to 1 at 2000 µs.
+    /// Unmapped channels are left unchanged.
+    RcOverride([Option<Axis>; 8]),
+}
+
+fn manual_axis(axis: &Option<Axis>, input: &ManualInput) -> i16 {
+    match axis.as_ref().and_then(|axis| axis.value(input)) {
+        Some(value) => (value * 1000.0).round() as i16,
+        None => i16::MAX,
+    }
+}
+
+fn pwm(axis: &Option<Axis>, input: &ManualInput) -> u16 {
+    match axis.as_ref().and_then(|axis| axis.value(input)) {
+        Some(value) => (1500.0 + value * 500.0).round() as u16,
+        None => u16::MAX,
+    }
+}
+
+fn rc_override(target_system: u8, target_component: u8, raw: [u16; 8]) -> MavMessage {
+    MavMessage::RC_CHANNELS_OVERRIDE(common::RC_CHANNELS_OVERRIDE_DATA {
+        chan1_raw: raw[0],
+        chan2_raw: raw[1],
+        chan3_raw: raw[2],
+        chan4_raw: raw[3],
+        chan5_raw: raw[4],
+        chan6_raw: raw[5],
+        chan7_raw: raw[6],
+        chan8_raw: raw[7],
+        target_system: target_system,
+        target_component: target_component,
+    })
+}
+
+impl ManualMapping {
+    /// `MANUAL_CONTROL` with the four axes taken from the first four input axes.
+    pub fn manual_control() -> ManualMapping {
+        ManualMapping::ManualControl {
+            x: Some(Axis::new(0)),
+            y: Some(Axis::new(1)),
+            z: Some(Axis::new(2)),
+            r: Some(Axis::new(3)),
+        }
+    }
+
+    pub fn message(&self, target_system: u8, target_component: u8, input: &ManualInput) -> MavMessage {
+        match *self {
+            ManualMapping::ManualControl { ref x, ref y, ref z, ref r } => {
+                MavMessage::MANUAL_CONTROL(common::MANUAL_CONTROL_DATA {
+                    x: manual_axis(x, input),
+                    y: manual_axis(y, input),
+                    z: manual_axis(z, input),
+                    r: manual_axis(r, input),
+                    buttons: input.buttons,
+                    target: target_system,
+                })
+            }
+            ManualMapping::RcOverride(ref channels) => {
+                let mut raw = [0; 8];
+                for (raw, axis) in raw.iter_mut().zip(channels) {
+                    *raw = pwm(axis, input);
+                }
+                rc_override(target_system, target_component, raw)
+            }
+        }
+    }
+
+    /// The message that hands control back to the pilot's radio, if there is one.
+    pub fn release(&self, target_system: u8, target_component: u8) -> Option<MavMessage> {
+        match *self {
+            ManualMapping::ManualControl { .. } => None,
+            ManualMapping::RcOverride(ref channels) => {
+                // 0 releases a channel; the ones never overridden stay untouched.
+                let mut raw = [u16::MAX; 8];
+                for (raw, axis) in raw.iter_mut().zip(channels) {
+                    if axis.is_some() {
+                        *raw = 0;
+                    }
+                }
+                Some(rc_override(target_system, target_component, raw))
+            }
+        }
+    }
+}
+
+struct ControllerState {
+    mapping: ManualMapping,
+    input: Option<(ManualInput, Instant)>,
+}
+
+/// Sends manual control input at a fixed rate from a background thread until dropped.
+///
+/// Feed it the latest state of the input device with `update`. When no update arrives within
+/// the timeout, e.g. because the device was unplugged or the loop reading it hung, the
+/// controller stops sending and, for RC overrides, releases the channels back to the radio so
+/// that the vehicle's own failsafes apply, repeating the release until the timeout has passed
+/// once more. Sending resumes with the next update. Dropping the
+/// controller releases the channels as well.
+pub struct ManualController {
+    sender: PeriodicSender<ControllerState>,
+}
+
+impl ManualController {
+    pub fn start(conn: Arc<MavConnection + Sync + Send>,
+                 target_system: u8,
+                 target_component: u8,
+                 mapping: ManualMapping,
+                 interval: Duration,
+                 timeout: Duration)
+                 -> ManualController {
+        let state = ControllerState { mapping: mapping, input: None };
+        let next = move |state: &mut ControllerState, stopping: bool| match state.input {
+            Some((ref input, updated)) if !stopping && updated.elapsed() < timeout => {
+                Some(state.mapping.message(target_system, target_component, input))
+            }
+            // Keep releasing until the timeout passes again, in case a release is lost.
+            Some((_, updated)) if stopping || updated.elapsed() < timeout * 2 => {
+                state.mapping.release(target_system, target_component)
+            }
+            _ => None,
+        };
+        ManualController {
+            sender: PeriodicSender::start(state, interval, next, move |msg| conn.send(msg).is_ok()),
+        }
+    }
+
+    /// Set the current state of the input device.
+    pub fn update(&self, input: ManualInput) {
+        self.sender.with(|state| state.input = Some((input, Instant::now())));
+    }
+
+    /// Change the mapping, starting with the next message sent.
+    pub fn set_mapping(&self, mapping: ManualMapping) {
+        self.sender.with(|state| state.mapping = mapping);
+    }
+
+    pub fn mapping(&self) -> ManualMapping {
+        self.sender.with(|state| state.mapping.clone())
+    }
+}
+
+#[cfg(test)]
+mod test_manual {
+    use super::*;
+    use loopback::loopback;
+
+    #[test]
+    pub fn test_manual_control() {
+        let input = ManualInput { axes: vec![0.5, -1.0, 0.04, 0.3], buttons: 0b101 };
+        let throttle = Axis { scale: 0.5, offset: 0.5, ..Axis::new(1) };
+        let mapping = ManualMapping::ManualControl {
+            x: Some(Axis { scale: -1.0, ..Axis::new(0) }),
+            y: Some(throttle),
+            z: Some(Axis { deadband: 0.05, ..Axis::new(2) }),
+            r: Some(Axis::new(7)),
+        };
+        match mapping.message(1, 1, &input) {
+            MavMessage::MANUAL_CONTROL(data) => {
+                assert_eq!((data.x, data.y, data.z, data.r), (-500, 0, 0, i16::MAX));
+                assert_eq!((data.buttons, data.target), (0b101, 1));
+            }
+            msg => panic!("unexpected {:?}", msg),
+        }
+        assert!(mapping.release(1, 1).is_none());
+
+        let deadband = Axis { deadband: 0.5, ..Axis::new(0) };
+        assert_eq!(deadband.value(&ManualInput { axes: vec![0.75], buttons: 0 }), Some(0.5));
+        assert_eq!(deadband.value(&ManualInput { axes: vec![-1.0], buttons: 0 }), Some(-1.0));
+    }
+
+    #[test]
+    pub fn test_override_deadman() {
+        let (gcs, vehicle) = loopback();
+        let gcs: Arc<MavConnection + Sync + Send> = Arc::new(gcs);
+        let mut channels = [None; 8];
+        channels[0] = Some(Axis::new(0));
+        channels[2] = Some(Axis { scale: -1.0, ..Axis::new(1) });
+        let controller = ManualController::start(gcs.clone(),
+                                                 1,
+                                                 1,
+                                                 ManualMapping::RcOverride(channels),
+                                                 Duration::from_millis(20),
+                                                 Duration::from_millis(100));
+        drop(gcs);
+
+        // Nothing is sent before the first input.
+        assert!(vehicle.recv_frame_timeout(Duration::from_millis(60)).is_err());
+
+        controller.update(ManualInput { axes: vec![0.2, 1.0], buttons: 0 });
+        let mut overrides = 0;
+        let release;
+        loop {
+            match vehicle.recv().unwrap() {
+                MavMessage::RC_CHANNELS_OVERRIDE(data) => {
+                    if data.chan1_raw == 0 {
+                        release = data;
+                        break;
+                    }
+                    assert_eq!((data.chan1_raw, data.chan2_raw, data.chan3_raw), (1600, u16::MAX, 1000));
+                    overrides += 1;
+                }
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        // Stale input released the overridden channels only, repeatedly until the timeout passed again.
+        assert!(overrides >= 2);
+        assert_eq!((release.chan2_raw, release.chan3_raw, release.chan8_raw), (u16::MAX, 0, u16::MAX));
+        let mut releases = 1;
+        while let Ok((_, msg)) = vehicle.recv_frame_timeout(Duration::from_millis(60)) {
+            match msg {
+                MavMessage::RC_CHANNELS_OVERRIDE(ref data) if data.chan1_raw == 0 => releases += 1,
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        assert!(releases >= 2, "releases {}", releases);
+
+        // Fresh input resumes sending, and dropping the controller releases again.
+        controller.update(ManualInput { axes: vec![-1.0, 0.0], buttons: 0 });
+        match vehicle.recv().unwrap() {
+            MavMessage::RC_CHANNELS_OVERRIDE(data) => assert_eq!((data.chan1_raw, data.chan3_raw), (1000, 1500)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        drop(controller);
+        let mut last = None;
+        while let Ok(msg) = vehicle.recv() {
+            last = Some(msg);
+        }
+        match last {
+            Some(MavMessage::RC_CHANNELS_OVERRIDE(data)) => assert_eq!((data.chan1_raw, data.chan3_raw), (0, 0)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+}
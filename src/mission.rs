@@ -0,0 +1,585 @@
+use common::{self, MavMessage, MAV_FRAME, MAV_MISSION_RESULT};
+use connection::MavConnection;
+use Header;
+
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::fmt;
+use std::i16;
+use std::io;
+
+/// Whether `x` and `y` in the given `MAV_FRAME` are latitude and longitude.
+pub fn is_global_frame(frame: u8) -> bool {
+    use common::MAV_FRAME::*;
+    match MAV_FRAME::from_u32(frame as u32) {
+        Some(MAV_FRAME_GLOBAL) |
+        Some(MAV_FRAME_GLOBAL_RELATIVE_ALT) |
+        Some(MAV_FRAME_GLOBAL_INT) |
+        Some(MAV_FRAME_GLOBAL_RELATIVE_ALT_INT) |
+        Some(MAV_FRAME_GLOBAL_TERRAIN_ALT) |
+        Some(MAV_FRAME_GLOBAL_TERRAIN_ALT_INT) => true,
+        _ => false,
+    }
+}
+
+/// Scale between `x`/`y` and their integer representation in `MISSION_ITEM_INT` and `COMMAND_INT`.
+pub fn int_scale(frame: u8) -> f64 {
+    if is_global_frame(frame) { 1e7 } else { 1e4 }
+}
+
+/// A mission item, whether it was carried by `MISSION_ITEM` or `MISSION_ITEM_INT`.
+///
+/// In global frames `x` and `y` are latitude and longitude in degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissionItem {
+    pub seq: u16,
+    pub frame: u8,
+    pub command: u16,
+    pub current: bool,
+    pub autocontinue: bool,
+    pub param1: f32,
+    pub param2: f32,
+    pub param3: f32,
+    pub param4: f32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f32,
+}
+
+impl MissionItem {
+    pub fn from_item(item: &common::MISSION_ITEM_DATA) -> MissionItem {
+        MissionItem {
+            seq: item.seq,
+            frame: item.frame,
+            command: item.command,
+            current: item.current != 0,
+            autocontinue: item.autocontinue != 0,
+            param1: item.param1,
+            param2: item.param2,
+            param3: item.param3,
+            param4: item.param4,
+            x: item.x as f64,
+            y: item.y as f64,
+            z: item.z,
+        }
+    }
+
+    pub fn from_item_int(item: &common::MISSION_ITEM_INT_DATA) -> MissionItem {
+        let scale = int_scale(item.frame);
+        MissionItem {
+            seq: item.seq,
+            frame: item.frame,
+            command: item.command,
+            current: item.current != 0,
+            autocontinue: item.autocontinue != 0,
+            param1: item.param1,
+            param2: item.param2,
+            param3: item.param3,
+            param4: item.param4,
+            x: item.x as f64 / scale,
+            y: item.y as f64 / scale,
+            z: item.z,
+        }
+    }
+
+    /// Extract the item from a `MISSION_ITEM` or `MISSION_ITEM_INT` message.
+    pub fn from_message(msg: &MavMessage) -> Option<MissionItem> {
+        match *msg {
+            MavMessage::MISSION_ITEM(ref item) => Some(MissionItem::from_item(item)),
+            MavMessage::MISSION_ITEM_INT(ref item) => Some(MissionItem::from_item_int(item)),
+            _ => None,
+        }
+    }
+
+    pub fn to_item(&self, target_system: u8, target_component: u8) -> common::MISSION_ITEM_DATA {
+        common::MISSION_ITEM_DATA {
+            param1: self.param1,
+            param2: self.param2,
+            param3: self.param3,
+            param4: self.param4,
+            x: self.x as f32,
+            y: self.y as f32,
+            z: self.z,
+            seq: self.seq,
+            command: self.command,
+            target_system: target_system,
+            target_component: target_component,
+            frame: self.frame,
+            current: self.current as u8,
+            autocontinue: self.autocontinue as u8,
+        }
+    }
+
+    pub fn to_item_int(&self, target_system: u8, target_component: u8) -> common::MISSION_ITEM_INT_DATA {
+        let scale = int_scale(self.frame);
+        common::MISSION_ITEM_INT_DATA {
+            param1: self.param1,
+            param2: self.param2,
+            param3: self.param3,
+            param4: self.param4,
+            x: (self.x * scale).round() as i32,
+            y: (self.y * scale).round() as i32,
+            z: self.z,
+            seq: self.seq,
+            command: self.command,
+            target_system: target_system,
+            target_component: target_component,
+            frame: self.frame,
+            current: self.current as u8,
+            autocontinue: self.autocontinue as u8,
+        }
+    }
+}
+
+/// Failure of a mission transfer.
+#[derive(Debug)]
+pub enum MissionError {
+    Io(io::Error),
+    /// The vehicle stopped responding and retries were exhausted.
+    Timeout,
+    /// The vehicle ended the transfer with an error, or replied with an unknown result code.
+    Rejected(Option<MAV_MISSION_RESULT>),
+}
+
+impl From<io::Error> for MissionError {
+    fn from(e: io::Error) -> MissionError {
+        if e.kind() == io::ErrorKind::TimedOut {
+            MissionError::Timeout
+        } else {
+            MissionError::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for MissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MissionError::Io(ref e) => write!(f, "{}", e),
+            MissionError::Timeout => write!(f, "mission transfer timed out"),
+            MissionError::Rejected(Some(result)) => write!(f, "mission transfer rejected: {:?}", result),
+            MissionError::Rejected(None) => write!(f, "mission transfer rejected"),
+        }
+    }
+}
+
+impl Error for MissionError {
+    fn description(&self) -> &str {
+        "mission transfer failed"
+    }
+}
+
+/// Client for the mission protocol of a single component.
+pub struct MissionClient<'a> {
+    conn: &'a MavConnection,
+    pub target_system: u8,
+    pub target_component: u8,
+    /// Upload items as `MISSION_ITEM_INT` rather than `MISSION_ITEM`.
+    pub use_int: bool,
+    /// How long to wait for a reply before re-sending a message.
+    pub timeout: Duration,
+    /// How many times a message is re-sent without a reply before giving up.
+    pub retries: usize,
+}
+
+impl<'a> MissionClient<'a> {
+    pub fn new(conn: &'a MavConnection, target_system: u8, target_component: u8) -> MissionClient<'a> {
+        MissionClient {
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+            use_int: true,
+            timeout: Duration::from_millis(1500),
+            retries: 5,
+        }
+    }
+
+    fn is_target(&self, header: &Header) -> bool {
+        header.system_id == self.target_system &&
+            (self.target_component == 0 || header.component_id == self.target_component)
+    }
+
+    /// Wait up to `timeout` for a mission message from the target. Failing acks end the transfer.
+    fn recv(&self) -> Result<MavMessage, MissionError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(MissionError::Timeout);
+            }
+            let (header, msg) = try!(self.conn.recv_frame_timeout(deadline - now));
+            if !self.is_target(&header) {
+                continue;
+            }
+            match msg {
+                MavMessage::MISSION_ACK(ref ack) if ack.mavtype != MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED as u8 => {
+                    return Err(MissionError::Rejected(MAV_MISSION_RESULT::from_u32(ack.mavtype as u32)));
+                }
+                MavMessage::MISSION_ACK(..) |
+                MavMessage::MISSION_COUNT(..) |
+                MavMessage::MISSION_REQUEST(..) |
+                MavMessage::MISSION_ITEM(..) |
+                MavMessage::MISSION_ITEM_INT(..) |
+                MavMessage::MISSION_CURRENT(..) => return Ok(msg),
+                _ => (),
+            }
+        }
+    }
+
+    /// Send `msgs` and wait for a reply accepted by `reply`, re-sending them all on timeout.
+    fn request<T, F>(&self, msgs: &[&MavMessage], reply: F) -> Result<T, MissionError>
+        where F: Fn(MavMessage) -> Option<T>
+    {
+        for _ in 0..self.retries + 1 {
+            for msg in msgs {
+                try!(self.conn.send(msg));
+            }
+            loop {
+                match self.recv() {
+                    Ok(m) => if let Some(result) = reply(m) { return Ok(result) },
+                    Err(MissionError::Timeout) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(MissionError::Timeout)
+    }
+
+    fn ack(&self, result: MAV_MISSION_RESULT) -> io::Result<()> {
+        self.conn.send(&MavMessage::MISSION_ACK(common::MISSION_ACK_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+            mavtype: result as u8,
+        }))
+    }
+
+    /// Request the items `start..end` one by one, sending `first` again with the first request
+    /// until that is answered.
+    fn fetch_items(&self, start: u16, end: u16, first: Option<&MavMessage>)
+                   -> Result<Vec<MissionItem>, MissionError> {
+        let mut items = Vec::new();
+        for seq in start..end {
+            let request = MavMessage::MISSION_REQUEST(common::MISSION_REQUEST_DATA {
+                seq: seq,
+                target_system: self.target_system,
+                target_component: self.target_component,
+            });
+            let msgs = match first {
+                Some(first) if seq == start => vec![first, &request],
+                _ => vec![&request],
+            };
+            items.push(try!(self.request(&msgs, |m| {
+                MissionItem::from_message(&m).and_then(|item| if item.seq == seq { Some(item) } else { None })
+            })));
+        }
+        try!(self.ack(MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED));
+        Ok(items)
+    }
+
+    /// The number of items in the vehicle's mission, which starts a download.
+    fn count(&self) -> Result<u16, MissionError> {
+        let request = MavMessage::MISSION_REQUEST_LIST(common::MISSION_REQUEST_LIST_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.request(&[&request], |m| match m {
+            MavMessage::MISSION_COUNT(c) => Some(c.count),
+            _ => None,
+        })
+    }
+
+    /// Download the complete mission.
+    pub fn download(&self) -> Result<Vec<MissionItem>, MissionError> {
+        let count = try!(self.count());
+        self.fetch_items(0, count, None)
+    }
+
+    /// Download the items with sequence numbers from `start` to `end` inclusive, or to the end
+    /// of the mission if `end` is `None`.
+    ///
+    /// The partial list request only carries sequence numbers up to 32767; larger ones fail with
+    /// `ErrorKind::InvalidInput`, as does an `end` before `start`.
+    pub fn download_partial(&self, start: u16, end: Option<u16>) -> Result<Vec<MissionItem>, MissionError> {
+        let max = i16::MAX as u16;
+        if start > max || end.map_or(false, |end| end > max || end < start) {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "invalid mission item range");
+            return Err(MissionError::Io(e));
+        }
+        let stop = match end {
+            Some(end) => end + 1,
+            None => try!(self.count()),
+        };
+        if start >= stop {
+            try!(self.ack(MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED));
+            return Ok(Vec::new());
+        }
+        let request = MavMessage::MISSION_REQUEST_PARTIAL_LIST(common::MISSION_REQUEST_PARTIAL_LIST_DATA {
+            start_index: start as i16,
+            end_index: end.map_or(-1, |end| end as i16),
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.fetch_items(start, stop, Some(&request))
+    }
+
+    fn item_message(&self, item: &MissionItem) -> MavMessage {
+        if self.use_int {
+            MavMessage::MISSION_ITEM_INT(item.to_item_int(self.target_system, self.target_component))
+        } else {
+            MavMessage::MISSION_ITEM(item.to_item(self.target_system, self.target_component))
+        }
+    }
+
+    /// Answer item requests for `items` until the vehicle acknowledges the transfer.
+    ///
+    /// `start` is the message that began the transfer; it is re-sent if the vehicle never asks
+    /// for an item.
+    fn send_items(&self, start: MavMessage, items: &[MissionItem]) -> Result<(), MissionError> {
+        let first = items.first().map(|item| item.seq).unwrap_or(0);
+        let mut last = start;
+        let mut retries = 0;
+        // An ack before the last item went out is left over from an earlier transfer.
+        let mut sent_last = items.is_empty();
+        try!(self.conn.send(&last));
+
+        loop {
+            match self.recv() {
+                Ok(MavMessage::MISSION_REQUEST(r)) => {
+                    let index = r.seq.wrapping_sub(first) as usize;
+                    match items.get(index) {
+                        Some(item) => {
+                            let mut item = item.clone();
+                            item.seq = r.seq;
+                            last = self.item_message(&item);
+                            try!(self.conn.send(&last));
+                            retries = 0;
+                            sent_last = sent_last || index == items.len() - 1;
+                        }
+                        None => {
+                            try!(self.ack(MAV_MISSION_RESULT::MAV_MISSION_INVALID_SEQUENCE));
+                            return Err(MissionError::Rejected(Some(MAV_MISSION_RESULT::MAV_MISSION_INVALID_SEQUENCE)));
+                        }
+                    }
+                }
+                Ok(MavMessage::MISSION_ACK(..)) if sent_last => return Ok(()),
+                Ok(_) => (),
+                Err(MissionError::Timeout) if retries < self.retries => {
+                    retries += 1;
+                    try!(self.conn.send(&last));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Replace the vehicle's mission with `items`, which are renumbered from zero.
+    pub fn upload(&self, items: &[MissionItem]) -> Result<(), MissionError> {
+        let items: Vec<MissionItem> = items.iter().enumerate().map(|(seq, item)| {
+            MissionItem { seq: seq as u16, ..item.clone() }
+        }).collect();
+        let count = MavMessage::MISSION_COUNT(common::MISSION_COUNT_DATA {
+            count: items.len() as u16,
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.send_items(count, &items)
+    }
+
+    /// Overwrite part of the vehicle's mission with `items`, starting at sequence number `start`.
+    pub fn upload_partial(&self, start: u16, items: &[MissionItem]) -> Result<(), MissionError> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let items: Vec<MissionItem> = items.iter().enumerate().map(|(i, item)| {
+            MissionItem { seq: start + i as u16, ..item.clone() }
+        }).collect();
+        let write = MavMessage::MISSION_WRITE_PARTIAL_LIST(common::MISSION_WRITE_PARTIAL_LIST_DATA {
+            start_index: start as i16,
+            end_index: (start as usize + items.len() - 1) as i16,
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.send_items(write, &items)
+    }
+
+    /// Delete the vehicle's mission.
+    pub fn clear(&self) -> Result<(), MissionError> {
+        let clear = MavMessage::MISSION_CLEAR_ALL(common::MISSION_CLEAR_ALL_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.request(&[&clear], |m| match m {
+            MavMessage::MISSION_ACK(..) => Some(()),
+            _ => None,
+        })
+    }
+
+    /// Make `seq` the current mission item, waiting for `MISSION_CURRENT` to confirm it.
+    pub fn set_current(&self, seq: u16) -> Result<(), MissionError> {
+        let set = MavMessage::MISSION_SET_CURRENT(common::MISSION_SET_CURRENT_DATA {
+            seq: seq,
+            target_system: self.target_system,
+            target_component: self.target_component,
+        });
+        self.request(&[&set], |m| match m {
+            MavMessage::MISSION_CURRENT(ref c) if c.seq == seq => Some(()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_mission {
+    use super::*;
+    use loopback::{loopback, loopback_with, LoopbackConfig};
+    use std::thread;
+
+    fn waypoint(lat: f64, lon: f64, alt: f32) -> MissionItem {
+        MissionItem {
+            seq: 0,
+            frame: MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT as u8,
+            command: 16,
+            current: false,
+            autocontinue: true,
+            param1: 0.0,
+            param2: 0.0,
+            param3: 0.0,
+            param4: 0.0,
+            x: lat,
+            y: lon,
+            z: alt,
+        }
+    }
+
+    /// A vehicle that stores at most `capacity` items, served until the client hangs up.
+    fn serve(conn: &MavConnection, capacity: u16) {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let ack = |result: MAV_MISSION_RESULT| MavMessage::MISSION_ACK(common::MISSION_ACK_DATA {
+            target_system: 255,
+            target_component: 0,
+            mavtype: result as u8,
+        });
+        let request = |seq: u16| MavMessage::MISSION_REQUEST(common::MISSION_REQUEST_DATA {
+            seq: seq,
+            target_system: 255,
+            target_component: 0,
+        });
+        let mut mission: Vec<MissionItem> = Vec::new();
+        let mut expected = 0;
+
+        while let Ok(msg) = conn.recv() {
+            let reply = match msg {
+                MavMessage::MISSION_COUNT(c) if c.count > capacity => ack(MAV_MISSION_RESULT::MAV_MISSION_NO_SPACE),
+                MavMessage::MISSION_COUNT(c) => {
+                    mission = vec![waypoint(0.0, 0.0, 0.0); c.count as usize];
+                    expected = 0;
+                    if c.count == 0 { ack(MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED) } else { request(0) }
+                }
+                MavMessage::MISSION_ITEM_INT(ref item) if item.seq == expected => {
+                    mission[expected as usize] = MissionItem::from_item_int(item);
+                    expected += 1;
+                    if expected as usize == mission.len() { ack(MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED) } else { request(expected) }
+                }
+                MavMessage::MISSION_ITEM_INT(..) if expected as usize == mission.len() => {
+                    ack(MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED)
+                }
+                MavMessage::MISSION_ITEM_INT(..) => request(expected),
+                MavMessage::MISSION_REQUEST_LIST(..) => MavMessage::MISSION_COUNT(common::MISSION_COUNT_DATA {
+                    count: mission.len() as u16,
+                    target_system: 255,
+                    target_component: 0,
+                }),
+                MavMessage::MISSION_REQUEST(r) => MavMessage::MISSION_ITEM(mission[r.seq as usize].to_item(255, 0)),
+                _ => continue,
+            };
+            conn.send_frame(header, &reply).ok();
+        }
+    }
+
+    #[test]
+    pub fn test_upload_download() {
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || serve(&vehicle, 10));
+
+        {
+            let mut client = MissionClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let mission: Vec<_> = (0..5).map(|i| waypoint(47.0 + i as f64 * 1e-4, 8.5, 20.0)).collect();
+            client.upload(&mission).unwrap();
+
+            let downloaded = client.download().unwrap();
+            assert_eq!(downloaded.len(), 5);
+            for (i, item) in downloaded.iter().enumerate() {
+                assert_eq!(item.seq, i as u16);
+                assert!((item.x - mission[i].x).abs() < 1e-5);
+            }
+
+            let partial = client.download_partial(1, Some(3)).unwrap();
+            assert_eq!(partial.iter().map(|item| item.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert_eq!(client.download_partial(3, None).unwrap().len(), 2);
+            match client.download_partial(40000, None) {
+                Err(MissionError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+            assert!(client.download_partial(3, Some(2)).is_err());
+
+            match client.upload(&vec![waypoint(0.0, 0.0, 0.0); 11]) {
+                Err(MissionError::Rejected(Some(MAV_MISSION_RESULT::MAV_MISSION_NO_SPACE))) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        drop(gcs);
+        vehicle.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_upload_ignores_stale_ack() {
+        let (gcs, vehicle) = loopback();
+        let vehicle = thread::spawn(move || {
+            let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+            let ack = MavMessage::MISSION_ACK(common::MISSION_ACK_DATA {
+                target_system: 255,
+                target_component: 0,
+                mavtype: MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED as u8,
+            });
+            let mut received = None;
+            while let Ok(msg) = vehicle.recv() {
+                match msg {
+                    MavMessage::MISSION_COUNT(..) => {
+                        // An ack still in flight from a previous upload arrives first.
+                        vehicle.send_frame(header, &ack).ok();
+                        vehicle.send_frame(header, &MavMessage::MISSION_REQUEST(common::MISSION_REQUEST_DATA {
+                            seq: 0,
+                            target_system: 255,
+                            target_component: 0,
+                        })).ok();
+                    }
+                    MavMessage::MISSION_ITEM_INT(item) => {
+                        received = Some(MissionItem::from_item_int(&item));
+                        vehicle.send_frame(header, &ack).ok();
+                    }
+                    _ => (),
+                }
+            }
+            received
+        });
+
+        {
+            let client = MissionClient::new(&gcs, 1, 1);
+            client.upload(&[waypoint(47.0, 8.5, 20.0)]).unwrap();
+        }
+
+        drop(gcs);
+        assert_eq!(vehicle.join().unwrap(), Some(waypoint(47.0, 8.5, 20.0)));
+    }
+
+    #[test]
+    pub fn test_int_conversion() {
+        let item = waypoint(-35.3632621, 149.1652374, 10.0);
+        let int = item.to_item_int(1, 1);
+        assert_eq!(int.x, -353632621);
+        assert_eq!(MissionItem::from_item_int(&int), item);
+    }
+}
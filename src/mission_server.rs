@@ -0,0 +1,423 @@
+use common::{self, MavMessage, MAV_MISSION_RESULT};
+use mission::MissionItem;
+use Header;
+
+use std::time::{Duration, Instant};
+
+/// An upload in progress.
+struct Upload {
+    /// System and component sending the items.
+    peer: (u8, u8),
+    /// Sequence number of the first item, which is not zero for partial uploads.
+    start: u16,
+    items: Vec<MissionItem>,
+    count: u16,
+    partial: bool,
+    last_request: Instant,
+    retries: usize,
+}
+
+impl Upload {
+    fn next(&self) -> u16 {
+        self.start + self.items.len() as u16
+    }
+}
+
+/// The vehicle side of the mission protocol, holding the mission and the current item.
+///
+/// Pass every received frame to `handle` and send back the reply it returns, and call `poll`
+/// regularly so that items lost during an upload are requested again. Call `reached` when the
+/// vehicle gets to the current item. Like `ParamServer`, messages are returned rather than sent
+/// so that the caller can send them with its own system id and sequence numbers.
+pub struct MissionServer {
+    pub system_id: u8,
+    pub component_id: u8,
+    /// Largest number of items accepted.
+    pub capacity: u16,
+    /// Whether downloads are answered with `MISSION_ITEM_INT` rather than `MISSION_ITEM`.
+    pub use_int: bool,
+    /// How long to wait for an item before requesting it again.
+    pub timeout: Duration,
+    /// How many times an item is requested again before the upload is abandoned.
+    pub retries: usize,
+    mission: Vec<MissionItem>,
+    current: u16,
+    upload: Option<Upload>,
+    /// Peer and sequence number of the last item of the last completed upload.
+    completed: Option<((u8, u8), u16)>,
+}
+
+impl MissionServer {
+    pub fn new(system_id: u8, component_id: u8) -> MissionServer {
+        MissionServer {
+            system_id: system_id,
+            component_id: component_id,
+            capacity: 1000,
+            use_int: true,
+            timeout: Duration::from_millis(1000),
+            retries: 5,
+            mission: Vec::new(),
+            current: 0,
+            upload: None,
+            completed: None,
+        }
+    }
+
+    pub fn mission(&self) -> &[MissionItem] {
+        &self.mission
+    }
+
+    /// Sequence number of the current item.
+    pub fn current(&self) -> u16 {
+        self.current
+    }
+
+    pub fn current_item(&self) -> Option<&MissionItem> {
+        self.mission.get(self.current as usize)
+    }
+
+    /// Whether an upload is in progress.
+    pub fn is_uploading(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// A `MISSION_CURRENT` with the current item, which autopilots also send periodically.
+    pub fn current_message(&self) -> MavMessage {
+        MavMessage::MISSION_CURRENT(common::MISSION_CURRENT_DATA { seq: self.current })
+    }
+
+    /// Make `seq` the current item, returning the `MISSION_CURRENT` announcing it.
+    ///
+    /// Sequence numbers past the end of the mission leave the current item unchanged.
+    pub fn set_current(&mut self, seq: u16) -> MavMessage {
+        if (seq as usize) < self.mission.len() {
+            self.current = seq;
+        }
+        self.current_message()
+    }
+
+    /// Mark the current item as reached, advancing to the next one if it continues automatically.
+    ///
+    /// Returns the `MISSION_ITEM_REACHED` and, if the current item changed, `MISSION_CURRENT`.
+    pub fn reached(&mut self) -> Vec<MavMessage> {
+        let autocontinue = match self.current_item() {
+            Some(item) => item.autocontinue,
+            None => return Vec::new(),
+        };
+        let mut messages = vec![MavMessage::MISSION_ITEM_REACHED(common::MISSION_ITEM_REACHED_DATA {
+            seq: self.current,
+        })];
+        if autocontinue && (self.current as usize + 1) < self.mission.len() {
+            self.current += 1;
+            messages.push(self.current_message());
+        }
+        messages
+    }
+
+    fn is_target(&self, target_system: u8, target_component: u8) -> bool {
+        target_system == self.system_id && (target_component == 0 || target_component == self.component_id)
+    }
+
+    fn ack(&self, peer: (u8, u8), result: MAV_MISSION_RESULT) -> MavMessage {
+        MavMessage::MISSION_ACK(common::MISSION_ACK_DATA {
+            target_system: peer.0,
+            target_component: peer.1,
+            mavtype: result as u8,
+        })
+    }
+
+    fn request(&self, peer: (u8, u8), seq: u16) -> MavMessage {
+        MavMessage::MISSION_REQUEST(common::MISSION_REQUEST_DATA {
+            seq: seq,
+            target_system: peer.0,
+            target_component: peer.1,
+        })
+    }
+
+    /// Start receiving `count` items from `start`, returning the first request.
+    fn start_upload(&mut self, peer: (u8, u8), start: u16, count: u16, partial: bool) -> MavMessage {
+        self.completed = None;
+        self.upload = Some(Upload {
+            peer: peer,
+            start: start,
+            items: Vec::new(),
+            count: count,
+            partial: partial,
+            last_request: Instant::now(),
+            retries: 0,
+        });
+        self.request(peer, start)
+    }
+
+    /// Store a received item, returning the request for the next one or the final ack.
+    fn receive_item(&mut self, peer: (u8, u8), item: MissionItem) -> Option<MavMessage> {
+        let done = match self.upload {
+            Some(ref mut upload) if upload.peer == peer => {
+                if item.seq == upload.next() {
+                    upload.items.push(item);
+                    upload.retries = 0;
+                }
+                upload.last_request = Instant::now();
+                upload.items.len() == upload.count as usize
+            }
+            Some(_) => return None,
+            // The upload is complete and the ack must have been lost, so the sender repeated
+            // its last item.
+            None if self.completed == Some((peer, item.seq)) => {
+                return Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED));
+            }
+            None => return Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ERROR)),
+        };
+        if !done {
+            let next = self.upload.as_ref().unwrap().next();
+            return Some(self.request(peer, next));
+        }
+
+        let upload = self.upload.take().unwrap();
+        self.completed = Some((peer, upload.start + upload.count - 1));
+        if upload.partial {
+            for item in upload.items {
+                let seq = item.seq as usize;
+                self.mission[seq] = item;
+            }
+        } else {
+            self.mission = upload.items;
+            self.current = 0;
+        }
+        Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED))
+    }
+
+    /// Handle a received message, returning the reply to send, if any.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Option<MavMessage> {
+        let peer = (header.system_id, header.component_id);
+        match *msg {
+            MavMessage::MISSION_COUNT(ref c) if self.is_target(c.target_system, c.target_component) => {
+                if c.count > self.capacity {
+                    Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_NO_SPACE))
+                } else if c.count == 0 {
+                    self.upload = None;
+                    self.completed = None;
+                    self.mission.clear();
+                    self.current = 0;
+                    Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED))
+                } else {
+                    Some(self.start_upload(peer, 0, c.count, false))
+                }
+            }
+            MavMessage::MISSION_WRITE_PARTIAL_LIST(ref w) if self.is_target(w.target_system, w.target_component) => {
+                if w.start_index < 0 || w.end_index < w.start_index || w.end_index as usize >= self.mission.len() {
+                    Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_INVALID_SEQUENCE))
+                } else {
+                    let count = (w.end_index - w.start_index + 1) as u16;
+                    Some(self.start_upload(peer, w.start_index as u16, count, true))
+                }
+            }
+            MavMessage::MISSION_ITEM(ref i) if self.is_target(i.target_system, i.target_component) => {
+                self.receive_item(peer, MissionItem::from_item(i))
+            }
+            MavMessage::MISSION_ITEM_INT(ref i) if self.is_target(i.target_system, i.target_component) => {
+                self.receive_item(peer, MissionItem::from_item_int(i))
+            }
+            MavMessage::MISSION_REQUEST_LIST(ref r) if self.is_target(r.target_system, r.target_component) => {
+                Some(MavMessage::MISSION_COUNT(common::MISSION_COUNT_DATA {
+                    count: self.mission.len() as u16,
+                    target_system: peer.0,
+                    target_component: peer.1,
+                }))
+            }
+            MavMessage::MISSION_REQUEST(ref r) if self.is_target(r.target_system, r.target_component) => {
+                Some(match self.mission.get(r.seq as usize) {
+                    Some(item) => {
+                        let item = MissionItem { current: r.seq == self.current, ..item.clone() };
+                        if self.use_int {
+                            MavMessage::MISSION_ITEM_INT(item.to_item_int(peer.0, peer.1))
+                        } else {
+                            MavMessage::MISSION_ITEM(item.to_item(peer.0, peer.1))
+                        }
+                    }
+                    None => self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_INVALID_SEQUENCE),
+                })
+            }
+            MavMessage::MISSION_CLEAR_ALL(ref c) if self.is_target(c.target_system, c.target_component) => {
+                self.upload = None;
+                self.completed = None;
+                self.mission.clear();
+                self.current = 0;
+                Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED))
+            }
+            MavMessage::MISSION_SET_CURRENT(ref s) if self.is_target(s.target_system, s.target_component) => {
+                Some(self.set_current(s.seq))
+            }
+            _ => None,
+        }
+    }
+
+    /// The request for an item that is overdue during an upload, or the ack abandoning it.
+    pub fn poll(&mut self) -> Option<MavMessage> {
+        let (peer, next, give_up) = match self.upload {
+            Some(ref mut upload) if upload.last_request.elapsed() >= self.timeout => {
+                upload.retries += 1;
+                upload.last_request = Instant::now();
+                (upload.peer, upload.next(), upload.retries > self.retries)
+            }
+            _ => return None,
+        };
+        if give_up {
+            self.upload = None;
+            Some(self.ack(peer, MAV_MISSION_RESULT::MAV_MISSION_ERROR))
+        } else {
+            Some(self.request(peer, next))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_mission_server {
+    use super::*;
+    use common::MAV_FRAME;
+    use connection::MavConnection;
+    use loopback::{loopback_with, LoopbackConfig};
+    use mission::{MissionClient, MissionError};
+    use std::io;
+    use std::thread;
+
+    fn waypoint(lat: f64, lon: f64, alt: f32) -> MissionItem {
+        MissionItem {
+            seq: 0,
+            frame: MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT as u8,
+            command: 16,
+            current: false,
+            autocontinue: true,
+            param1: 0.0,
+            param2: 0.0,
+            param3: 0.0,
+            param4: 0.0,
+            x: lat,
+            y: lon,
+            z: alt,
+        }
+    }
+
+    /// Serve the mission until the client hangs up.
+    fn serve(conn: &MavConnection, server: &mut MissionServer) {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        loop {
+            match conn.recv_frame_timeout(Duration::from_millis(5)) {
+                Ok((h, msg)) => {
+                    if let Some(reply) = server.handle(&h, &msg) {
+                        conn.send_frame(header, &reply).ok();
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(_) => return,
+            }
+            if let Some(request) = server.poll() {
+                conn.send_frame(header, &request).ok();
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_serve_client() {
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || {
+            let mut server = MissionServer::new(1, 1);
+            server.capacity = 10;
+            server.timeout = Duration::from_millis(20);
+            serve(&vehicle, &mut server);
+            server
+        });
+
+        {
+            let mut client = MissionClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let mission: Vec<_> = (0..5).map(|i| waypoint(47.0 + i as f64 * 1e-4, 8.5, 20.0)).collect();
+            client.upload(&mission).unwrap();
+            client.upload_partial(3, &[waypoint(46.0, 7.0, 30.0)]).unwrap();
+            client.set_current(2).unwrap();
+
+            let downloaded = client.download().unwrap();
+            assert_eq!(downloaded.len(), 5);
+            assert!((downloaded[1].x - mission[1].x).abs() < 1e-7);
+            assert_eq!(downloaded[3].z, 30.0);
+            assert!(downloaded[2].current && !downloaded[0].current);
+
+            match client.upload(&vec![waypoint(0.0, 0.0, 0.0); 11]) {
+                Err(MissionError::Rejected(Some(MAV_MISSION_RESULT::MAV_MISSION_NO_SPACE))) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+            match client.upload_partial(5, &[waypoint(0.0, 0.0, 0.0)]) {
+                Err(MissionError::Rejected(Some(MAV_MISSION_RESULT::MAV_MISSION_INVALID_SEQUENCE))) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        drop(gcs);
+        let mut server = vehicle.join().unwrap();
+        assert_eq!(server.mission().len(), 5);
+        assert_eq!(server.current(), 2);
+
+        let messages = server.reached();
+        assert_eq!(messages.len(), 2);
+        match messages[0] {
+            MavMessage::MISSION_ITEM_REACHED(ref r) => assert_eq!(r.seq, 2),
+            ref m => panic!("unexpected message {:?}", m),
+        }
+        assert_eq!(server.current(), 3);
+        server.set_current(4);
+        assert_eq!(server.reached().len(), 1);
+        assert_eq!(server.current(), 4);
+    }
+
+    #[test]
+    pub fn test_clear_and_abandon() {
+        let mut server = MissionServer::new(1, 1);
+        server.timeout = Duration::from_millis(0);
+        server.retries = 1;
+        let gcs = Header { sequence: 0, system_id: 255, component_id: 0 };
+
+        let count = MavMessage::MISSION_COUNT(common::MISSION_COUNT_DATA {
+            count: 2,
+            target_system: 1,
+            target_component: 1,
+        });
+        assert!(server.handle(&gcs, &count).is_some());
+        assert!(server.is_uploading());
+        match server.poll() {
+            Some(MavMessage::MISSION_REQUEST(ref r)) => assert_eq!(r.seq, 0),
+            m => panic!("unexpected message {:?}", m),
+        }
+        match server.poll() {
+            Some(MavMessage::MISSION_ACK(ref a)) => assert_eq!(a.mavtype, MAV_MISSION_RESULT::MAV_MISSION_ERROR as u8),
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert!(!server.is_uploading());
+
+        // With no upload in progress, only a repeat of the last item of a completed upload is acked.
+        let item = |seq: u16| {
+            MavMessage::MISSION_ITEM_INT(MissionItem { seq: seq, ..waypoint(0.0, 0.0, 0.0) }.to_item_int(1, 1))
+        };
+        let result = |reply: Option<MavMessage>| match reply {
+            Some(MavMessage::MISSION_ACK(ref a)) => a.mavtype,
+            m => panic!("unexpected message {:?}", m),
+        };
+        assert_eq!(result(server.handle(&gcs, &item(1))), MAV_MISSION_RESULT::MAV_MISSION_ERROR as u8);
+        server.timeout = Duration::from_millis(1000);
+        assert!(server.handle(&gcs, &count).is_some());
+        assert!(server.handle(&gcs, &item(0)).is_some());
+        assert_eq!(result(server.handle(&gcs, &item(1))), MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED as u8);
+        assert_eq!(result(server.handle(&gcs, &item(1))), MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED as u8);
+        assert_eq!(result(server.handle(&gcs, &item(0))), MAV_MISSION_RESULT::MAV_MISSION_ERROR as u8);
+        let other = Header { system_id: 254, ..gcs };
+        assert_eq!(result(server.handle(&other, &item(1))), MAV_MISSION_RESULT::MAV_MISSION_ERROR as u8);
+
+        // Requests for other systems are ignored.
+        let clear = MavMessage::MISSION_CLEAR_ALL(common::MISSION_CLEAR_ALL_DATA {
+            target_system: 2,
+            target_component: 1,
+        });
+        assert!(server.handle(&gcs, &clear).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+use common::{self, Command, MavMessage, MAV_AUTOPILOT, MAV_MODE_FLAG, MAV_TYPE};
+
+/// The flight stack whose `custom_mode` numbering a vehicle uses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Firmware {
+    ArduCopter,
+    ArduPlane,
+    ArduRover,
+    Px4,
+}
+
+// ArduCopter modes that the simulator implements.
+pub const COPTER_STABILIZE: u32 = 0;
+pub const COPTER_AUTO: u32 = 3;
+pub const COPTER_GUIDED: u32 = 4;
+pub const COPTER_LOITER: u32 = 5;
+pub const COPTER_RTL: u32 = 6;
+pub const COPTER_LAND: u32 = 9;
+
+const COPTER_MODES: &'static [(u32, &'static str)] = &[
+    (COPTER_STABILIZE, "STABILIZE"), (1, "ACRO"), (2, "ALT_HOLD"), (COPTER_AUTO, "AUTO"),
+    (COPTER_GUIDED, "GUIDED"), (COPTER_LOITER, "LOITER"), (COPTER_RTL, "RTL"), (7, "CIRCLE"),
+    (COPTER_LAND, "LAND"), (11, "DRIFT"), (13, "SPORT"), (14, "FLIP"),
+    (15, "AUTOTUNE"), (16, "POSHOLD"), (17, "BRAKE"), (18, "THROW"), (19, "AVOID_ADSB"),
+    (20, "GUIDED_NOGPS"), (21, "SMART_RTL"), (22, "FLOWHOLD"), (23, "FOLLOW"), (24, "ZIGZAG"),
+    (25, "SYSTEMID"), (26, "AUTOROTATE"), (27, "AUTO_RTL"),
+];
+
+const PLANE_MODES: &'static [(u32, &'static str)] = &[
+    (0, "MANUAL"), (1, "CIRCLE"), (2, "STABILIZE"), (3, "TRAINING"), (4, "ACRO"), (5, "FBWA"),
+    (6, "FBWB"), (7, "CRUISE"), (8, "AUTOTUNE"), (10, "AUTO"), (11, "RTL"), (12, "LOITER"),
+    (13, "TAKEOFF"), (14, "AVOID_ADSB"), (15, "GUIDED"), (17, "QSTABILIZE"), (18, "QHOVER"),
+    (19, "QLOITER"), (20, "QLAND"), (21, "QRTL"), (22, "QAUTOTUNE"), (23, "QACRO"),
+    (24, "THERMAL"), (25, "LOITER_ALT_QLAND"),
+];
+
+const ROVER_MODES: &'static [(u32, &'static str)] = &[
+    (0, "MANUAL"), (1, "ACRO"), (3, "STEERING"), (4, "HOLD"), (5, "LOITER"), (6, "FOLLOW"),
+    (7, "SIMPLE"), (8, "DOCK"), (9, "CIRCLE"), (10, "AUTO"), (11, "RTL"), (12, "SMART_RTL"),
+    (15, "GUIDED"), (16, "INITIALISING"),
+];
+
+const PX4_MAIN_AUTO: u32 = 4;
+
+/// PX4 modes, with the main mode in the third byte of `custom_mode` and the sub mode in the fourth.
+const PX4_MODES: &'static [(u32, &'static str)] = &[
+    (0x00010000, "MANUAL"), (0x00020000, "ALTCTL"), (0x00030000, "POSCTL"),
+    (0x01040000, "AUTO.READY"), (0x02040000, "AUTO.TAKEOFF"), (0x03040000, "AUTO.LOITER"),
+    (0x04040000, "AUTO.MISSION"), (0x05040000, "AUTO.RTL"), (0x06040000, "AUTO.LAND"),
+    (0x07040000, "AUTO.RTGS"), (0x08040000, "AUTO.FOLLOW_TARGET"), (0x09040000, "AUTO.PRECLAND"),
+    (0x00050000, "ACRO"), (0x00060000, "OFFBOARD"), (0x00070000, "STABILIZED"), (0x00080000, "RATTITUDE"),
+];
+
+/// Generic names accepted for PX4 modes, mapped to the PX4 name.
+const PX4_ALIASES: &'static [(&'static str, &'static str)] = &[
+    ("AUTO", "AUTO.MISSION"), ("MISSION", "AUTO.MISSION"), ("RTL", "AUTO.RTL"),
+    ("LAND", "AUTO.LAND"), ("TAKEOFF", "AUTO.TAKEOFF"), ("LOITER", "AUTO.LOITER"),
+    ("HOLD", "AUTO.LOITER"), ("POSITION", "POSCTL"), ("ALTITUDE", "ALTCTL"),
+    ("STABILIZE", "STABILIZED"),
+];
+
+impl Firmware {
+    /// The firmware of a vehicle with the given `HEARTBEAT.autopilot` and `HEARTBEAT.type`.
+    pub fn detect(autopilot: u8, mavtype: u8) -> Option<Firmware> {
+        use common::MAV_TYPE::*;
+        if autopilot == MAV_AUTOPILOT::MAV_AUTOPILOT_PX4 as u8 {
+            return Some(Firmware::Px4);
+        }
+        if autopilot != MAV_AUTOPILOT::MAV_AUTOPILOT_ARDUPILOTMEGA as u8 {
+            return None;
+        }
+        match MAV_TYPE::from_u32(mavtype as u32) {
+            Some(MAV_TYPE_QUADROTOR) | Some(MAV_TYPE_COAXIAL) | Some(MAV_TYPE_HELICOPTER) |
+            Some(MAV_TYPE_HEXAROTOR) | Some(MAV_TYPE_OCTOROTOR) | Some(MAV_TYPE_TRICOPTER) => {
+                Some(Firmware::ArduCopter)
+            }
+            // Quadplanes report themselves as VTOLs.
+            Some(MAV_TYPE_FIXED_WING) | Some(MAV_TYPE_VTOL_DUOROTOR) | Some(MAV_TYPE_VTOL_QUADROTOR) |
+            Some(MAV_TYPE_VTOL_TILTROTOR) => Some(Firmware::ArduPlane),
+            Some(MAV_TYPE_GROUND_ROVER) | Some(MAV_TYPE_SURFACE_BOAT) => Some(Firmware::ArduRover),
+            _ => None,
+        }
+    }
+
+    pub fn from_heartbeat(heartbeat: &common::HEARTBEAT_DATA) -> Option<Firmware> {
+        Firmware::detect(heartbeat.autopilot, heartbeat.mavtype)
+    }
+
+    /// The custom modes of this firmware and their names.
+    pub fn modes(&self) -> &'static [(u32, &'static str)] {
+        match *self {
+            Firmware::ArduCopter => COPTER_MODES,
+            Firmware::ArduPlane => PLANE_MODES,
+            Firmware::ArduRover => ROVER_MODES,
+            Firmware::Px4 => PX4_MODES,
+        }
+    }
+
+    /// The name of a `custom_mode`, such as `"GUIDED"` or, for PX4, `"AUTO.MISSION"`.
+    pub fn mode_name(&self, custom_mode: u32) -> Option<&'static str> {
+        let custom_mode = if *self == Firmware::Px4 {
+            // Only auto modes have sub modes; the low bytes are unused.
+            let main = custom_mode >> 16 & 0xff;
+            if main == PX4_MAIN_AUTO { custom_mode & 0xffff0000 } else { main << 16 }
+        } else {
+            custom_mode
+        };
+        self.modes().iter().find(|&&(mode, _)| mode == custom_mode).map(|&(_, name)| name)
+    }
+
+    /// The `custom_mode` of a mode name, ignoring case.
+    ///
+    /// For PX4, generic names such as `"RTL"` or `"MISSION"` are accepted as well.
+    pub fn custom_mode(&self, name: &str) -> Option<u32> {
+        let mut name = name.to_uppercase();
+        if *self == Firmware::Px4 {
+            if let Some(&(_, px4_name)) = PX4_ALIASES.iter().find(|&&(alias, _)| alias == name) {
+                name = px4_name.to_string();
+            }
+        }
+        self.modes().iter().find(|&&(_, n)| n == name).map(|&(mode, _)| mode)
+    }
+
+    /// A `SET_MODE` message switching `target_system` to the named mode.
+    pub fn set_mode_message(&self, target_system: u8, name: &str) -> Option<MavMessage> {
+        self.custom_mode(name).map(|custom_mode| {
+            MavMessage::SET_MODE(common::SET_MODE_DATA {
+                custom_mode: custom_mode,
+                target_system: target_system,
+                base_mode: MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8,
+            })
+        })
+    }
+
+    /// A `MAV_CMD_DO_SET_MODE` switching to the named mode.
+    ///
+    /// PX4 takes its main and sub mode as separate parameters; ArduPilot takes the mode number.
+    pub fn set_mode_command(&self, name: &str) -> Option<Command> {
+        let mode = MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8 as f32;
+        self.custom_mode(name).map(|custom_mode| {
+            if *self == Firmware::Px4 {
+                Command::DoSetMode {
+                    mode: mode,
+                    custom_mode: (custom_mode >> 16 & 0xff) as f32,
+                    custom_sub_mode: (custom_mode >> 24) as f32,
+                }
+            } else {
+                Command::DoSetMode {
+                    mode: mode,
+                    custom_mode: custom_mode as f32,
+                    custom_sub_mode: 0.0,
+                }
+            }
+        })
+    }
+}
+
+/// A readable name for the mode reported by a `HEARTBEAT`.
+///
+/// Custom modes are named according to the firmware. Otherwise the mode is described by the
+/// most specific flag of `base_mode`, as `"AUTO"`, `"GUIDED"`, `"STABILIZE"` or `"MANUAL"`.
+pub fn mode_name(heartbeat: &common::HEARTBEAT_DATA) -> Option<&'static str> {
+    use common::MAV_MODE_FLAG::*;
+    let base_mode = heartbeat.base_mode;
+    if base_mode & MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8 != 0 {
+        if let Some(firmware) = Firmware::from_heartbeat(heartbeat) {
+            return firmware.mode_name(heartbeat.custom_mode);
+        }
+    }
+    [(MAV_MODE_FLAG_AUTO_ENABLED, "AUTO"), (MAV_MODE_FLAG_GUIDED_ENABLED, "GUIDED"),
+     (MAV_MODE_FLAG_STABILIZE_ENABLED, "STABILIZE"), (MAV_MODE_FLAG_MANUAL_INPUT_ENABLED, "MANUAL")]
+        .iter()
+        .find(|&&(flag, _)| base_mode & flag as u8 != 0)
+        .map(|&(_, name)| name)
+}
+
+#[cfg(test)]
+mod test_mode {
+    use super::*;
+
+    fn heartbeat(autopilot: MAV_AUTOPILOT, mavtype: MAV_TYPE, base_mode: u8, custom_mode: u32)
+                 -> common::HEARTBEAT_DATA {
+        common::HEARTBEAT_DATA {
+            custom_mode: custom_mode,
+            mavtype: mavtype as u8,
+            autopilot: autopilot as u8,
+            base_mode: base_mode,
+            system_status: 4,
+            mavlink_version: 3,
+        }
+    }
+
+    #[test]
+    pub fn test_decode() {
+        let custom = MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8;
+        let ardupilot = MAV_AUTOPILOT::MAV_AUTOPILOT_ARDUPILOTMEGA;
+        assert_eq!(mode_name(&heartbeat(ardupilot, MAV_TYPE::MAV_TYPE_QUADROTOR, custom, 4)), Some("GUIDED"));
+        assert_eq!(mode_name(&heartbeat(ardupilot, MAV_TYPE::MAV_TYPE_FIXED_WING, custom, 4)), Some("ACRO"));
+        assert_eq!(mode_name(&heartbeat(ardupilot, MAV_TYPE::MAV_TYPE_VTOL_QUADROTOR, custom, 21)), Some("QRTL"));
+        assert_eq!(mode_name(&heartbeat(ardupilot, MAV_TYPE::MAV_TYPE_GROUND_ROVER, custom, 4)), Some("HOLD"));
+        assert_eq!(mode_name(&heartbeat(ardupilot, MAV_TYPE::MAV_TYPE_QUADROTOR, custom, 8)), None);
+
+        let px4 = MAV_AUTOPILOT::MAV_AUTOPILOT_PX4;
+        assert_eq!(mode_name(&heartbeat(px4, MAV_TYPE::MAV_TYPE_QUADROTOR, custom, 0x04040000)),
+                   Some("AUTO.MISSION"));
+        // Sub modes of other main modes are ignored.
+        assert_eq!(mode_name(&heartbeat(px4, MAV_TYPE::MAV_TYPE_QUADROTOR, custom, 0x01030000)), Some("POSCTL"));
+
+        let auto = MAV_MODE_FLAG::MAV_MODE_FLAG_AUTO_ENABLED as u8 | MAV_MODE_FLAG::MAV_MODE_FLAG_STABILIZE_ENABLED as u8;
+        let generic = MAV_AUTOPILOT::MAV_AUTOPILOT_GENERIC;
+        assert_eq!(mode_name(&heartbeat(generic, MAV_TYPE::MAV_TYPE_QUADROTOR, auto, 0)), Some("AUTO"));
+        assert_eq!(mode_name(&heartbeat(generic, MAV_TYPE::MAV_TYPE_QUADROTOR, custom | auto, 7)), Some("AUTO"));
+        assert_eq!(mode_name(&heartbeat(generic, MAV_TYPE::MAV_TYPE_QUADROTOR, 0, 0)), None);
+    }
+
+    #[test]
+    pub fn test_encode() {
+        assert_eq!(Firmware::ArduCopter.custom_mode("rtl"), Some(6));
+        assert_eq!(Firmware::ArduPlane.custom_mode("RTL"), Some(11));
+        assert_eq!(Firmware::Px4.custom_mode("RTL"), Some(0x05040000));
+        assert_eq!(Firmware::ArduRover.custom_mode("POSHOLD"), None);
+        for &firmware in &[Firmware::ArduCopter, Firmware::ArduPlane, Firmware::ArduRover, Firmware::Px4] {
+            for &(mode, name) in firmware.modes() {
+                assert_eq!(firmware.mode_name(mode), Some(name));
+                assert_eq!(firmware.custom_mode(name), Some(mode));
+            }
+        }
+
+        match Firmware::ArduCopter.set_mode_message(1, "GUIDED") {
+            Some(MavMessage::SET_MODE(ref m)) => assert_eq!((m.target_system, m.base_mode, m.custom_mode), (1, 1, 4)),
+            m => panic!("unexpected message {:?}", m),
+        }
+        match Firmware::Px4.set_mode_command("mission") {
+            Some(Command::DoSetMode { mode, custom_mode, custom_sub_mode }) => {
+                assert_eq!((mode, custom_mode, custom_sub_mode), (1.0, 4.0, 4.0))
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert!(Firmware::ArduCopter.set_mode_command("MISSION").is_none());
+    }
+}
@@ -0,0 +1,466 @@
+use common::{self, MavMessage, MAV_FRAME};
+use connection::MavConnection;
+use geo::distance;
+use periodic::PeriodicSender;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// `type_mask` bits of `SET_POSITION_TARGET_*`, set for each value the vehicle should ignore.
+const IGNORE_POSITION: u16 = 0x7;
+const IGNORE_VELOCITY: u16 = 0x38;
+const IGNORE_ACCELERATION: u16 = 0x1c0;
+const IGNORE_YAW: u16 = 0x400;
+const IGNORE_YAW_RATE: u16 = 0x800;
+
+/// `type_mask` bits of `SET_ATTITUDE_TARGET`.
+const IGNORE_BODY_RATES: u8 = 0x7;
+const IGNORE_THRUST: u8 = 0x40;
+const IGNORE_ATTITUDE: u8 = 0x80;
+
+/// The values of a position target. Values left out are ignored by the vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PositionTarget {
+    /// North, east and down in meters for local setpoints, or latitude and longitude in
+    /// degrees and altitude in meters for global ones.
+    pub position: Option<(f64, f64, f32)>,
+    /// North, east and down in m/s.
+    pub velocity: Option<(f32, f32, f32)>,
+    /// North, east and down in m/s².
+    pub acceleration: Option<(f32, f32, f32)>,
+    /// Radians.
+    pub yaw: Option<f32>,
+    /// Radians per second.
+    pub yaw_rate: Option<f32>,
+}
+
+impl PositionTarget {
+    pub fn type_mask(&self) -> u16 {
+        let mut mask = 0;
+        if self.position.is_none() {
+            mask |= IGNORE_POSITION;
+        }
+        if self.velocity.is_none() {
+            mask |= IGNORE_VELOCITY;
+        }
+        if self.acceleration.is_none() {
+            mask |= IGNORE_ACCELERATION;
+        }
+        if self.yaw.is_none() {
+            mask |= IGNORE_YAW;
+        }
+        if self.yaw_rate.is_none() {
+            mask |= IGNORE_YAW_RATE;
+        }
+        mask
+    }
+}
+
+/// The values of an attitude target. Values left out are ignored by the vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AttitudeTarget {
+    /// Quaternion as w, x, y, z.
+    pub attitude: Option<[f32; 4]>,
+    /// Roll, pitch and yaw rates in radians per second.
+    pub body_rates: Option<(f32, f32, f32)>,
+    /// From 0 to 1.
+    pub thrust: Option<f32>,
+}
+
+impl AttitudeTarget {
+    pub fn type_mask(&self) -> u8 {
+        let mut mask = 0;
+        if self.body_rates.is_none() {
+            mask |= IGNORE_BODY_RATES;
+        }
+        if self.thrust.is_none() {
+            mask |= IGNORE_THRUST;
+        }
+        if self.attitude.is_none() {
+            mask |= IGNORE_ATTITUDE;
+        }
+        mask
+    }
+}
+
+/// A setpoint for a vehicle under offboard or guided control.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setpoint {
+    /// Sent as `SET_POSITION_TARGET_LOCAL_NED`, usually in `MAV_FRAME_LOCAL_NED` or
+    /// `MAV_FRAME_BODY_NED`.
+    Local(MAV_FRAME, PositionTarget),
+    /// Sent as `SET_POSITION_TARGET_GLOBAL_INT`, usually in `MAV_FRAME_GLOBAL_RELATIVE_ALT_INT`.
+    Global(MAV_FRAME, PositionTarget),
+    /// Sent as `SET_ATTITUDE_TARGET`.
+    Attitude(AttitudeTarget),
+}
+
+impl Setpoint {
+    pub fn message(&self, target_system: u8, target_component: u8, time_boot_ms: u32) -> MavMessage {
+        match *self {
+            Setpoint::Local(frame, ref target) => {
+                let (x, y, z) = target.position.unwrap_or((0.0, 0.0, 0.0));
+                let (vx, vy, vz) = target.velocity.unwrap_or((0.0, 0.0, 0.0));
+                let (afx, afy, afz) = target.acceleration.unwrap_or((0.0, 0.0, 0.0));
+                MavMessage::SET_POSITION_TARGET_LOCAL_NED(common::SET_POSITION_TARGET_LOCAL_NED_DATA {
+                    time_boot_ms: time_boot_ms,
+                    x: x as f32,
+                    y: y as f32,
+                    z: z,
+                    vx: vx,
+                    vy: vy,
+                    vz: vz,
+                    afx: afx,
+                    afy: afy,
+                    afz: afz,
+                    yaw: target.yaw.unwrap_or(0.0),
+                    yaw_rate: target.yaw_rate.unwrap_or(0.0),
+                    type_mask: target.type_mask(),
+                    target_system: target_system,
+                    target_component: target_component,
+                    coordinate_frame: frame as u8,
+                })
+            }
+            Setpoint::Global(frame, ref target) => {
+                let (lat, lon, alt) = target.position.unwrap_or((0.0, 0.0, 0.0));
+                let (vx, vy, vz) = target.velocity.unwrap_or((0.0, 0.0, 0.0));
+                let (afx, afy, afz) = target.acceleration.unwrap_or((0.0, 0.0, 0.0));
+                MavMessage::SET_POSITION_TARGET_GLOBAL_INT(common::SET_POSITION_TARGET_GLOBAL_INT_DATA {
+                    time_boot_ms: time_boot_ms,
+                    lat_int: (lat * 1e7).round() as i32,
+                    lon_int: (lon * 1e7).round() as i32,
+                    alt: alt,
+                    vx: vx,
+                    vy: vy,
+                    vz: vz,
+                    afx: afx,
+                    afy: afy,
+                    afz: afz,
+                    yaw: target.yaw.unwrap_or(0.0),
+                    yaw_rate: target.yaw_rate.unwrap_or(0.0),
+                    type_mask: target.type_mask(),
+                    target_system: target_system,
+                    target_component: target_component,
+                    coordinate_frame: frame as u8,
+                })
+            }
+            Setpoint::Attitude(ref target) => {
+                let (roll, pitch, yaw) = target.body_rates.unwrap_or((0.0, 0.0, 0.0));
+                MavMessage::SET_ATTITUDE_TARGET(common::SET_ATTITUDE_TARGET_DATA {
+                    time_boot_ms: time_boot_ms,
+                    q: target.attitude.unwrap_or([1.0, 0.0, 0.0, 0.0]).to_vec(),
+                    body_roll_rate: roll,
+                    body_pitch_rate: pitch,
+                    body_yaw_rate: yaw,
+                    thrust: target.thrust.unwrap_or(0.0),
+                    target_system: target_system,
+                    target_component: target_component,
+                    type_mask: target.type_mask(),
+                })
+            }
+        }
+    }
+}
+
+/// How the target reported by the vehicle differs from the setpoint being sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    /// The vehicle reports a different frame.
+    Frame { sent: u8, reported: u8 },
+    /// The vehicle ignores different values than the ones sent, e.g. because it does not
+    /// support the combination.
+    TypeMask { sent: u16, reported: u16 },
+    /// A value differs by more than the tolerance. A global position is compared as the
+    /// horizontal distance in meters between the two, reported as `position` with `sent` 0.
+    Value { field: &'static str, sent: f32, reported: f32 },
+}
+
+fn compare(field: &'static str, sent: f32, reported: f32, tolerance: f32) -> Result<(), Divergence> {
+    if (sent - reported).abs() > tolerance {
+        Err(Divergence::Value { field: field, sent: sent, reported: reported })
+    } else {
+        Ok(())
+    }
+}
+
+/// Compare the values set in `target` with `reported`, which has every value set.
+fn compare_position(target: &PositionTarget, reported: &PositionTarget, global: bool, tolerance: f32)
+                    -> Result<(), Divergence> {
+    let position = reported.position.unwrap();
+    let velocity = reported.velocity.unwrap();
+    let acceleration = reported.acceleration.unwrap();
+    if let Some((x, y, z)) = target.position {
+        if global {
+            try!(compare("position", 0.0, distance(x, y, position.0, position.1) as f32, tolerance));
+            try!(compare("alt", z, position.2, tolerance));
+        } else {
+            try!(compare("x", x as f32, position.0 as f32, tolerance));
+            try!(compare("y", y as f32, position.1 as f32, tolerance));
+            try!(compare("z", z, position.2, tolerance));
+        }
+    }
+    if let Some((vx, vy, vz)) = target.velocity {
+        try!(compare("vx", vx, velocity.0, tolerance));
+        try!(compare("vy", vy, velocity.1, tolerance));
+        try!(compare("vz", vz, velocity.2, tolerance));
+    }
+    if let Some((afx, afy, afz)) = target.acceleration {
+        try!(compare("afx", afx, acceleration.0, tolerance));
+        try!(compare("afy", afy, acceleration.1, tolerance));
+        try!(compare("afz", afz, acceleration.2, tolerance));
+    }
+    if let Some(sent) = target.yaw {
+        try!(compare("yaw", sent, reported.yaw.unwrap(), tolerance));
+    }
+    if let Some(sent) = target.yaw_rate {
+        try!(compare("yaw_rate", sent, reported.yaw_rate.unwrap(), tolerance));
+    }
+    Ok(())
+}
+
+fn compare_attitude(target: &AttitudeTarget, reported: &common::ATTITUDE_TARGET_DATA, tolerance: f32)
+                    -> Result<(), Divergence> {
+    // Bits 3 to 5 are reserved and thrust is not reported.
+    let relevant = IGNORE_BODY_RATES | IGNORE_ATTITUDE;
+    let sent_mask = target.type_mask() & relevant;
+    if reported.type_mask & relevant != sent_mask {
+        return Err(Divergence::TypeMask { sent: sent_mask as u16, reported: (reported.type_mask & relevant) as u16 });
+    }
+    if let Some(q) = target.attitude {
+        if reported.q.len() == 4 {
+            // q and -q are the same attitude.
+            let sign = if q.iter().zip(&reported.q).map(|(a, b)| a * b).sum::<f32>() < 0.0 { -1.0 } else { 1.0 };
+            let fields = ["q[0]", "q[1]", "q[2]", "q[3]"];
+            for i in 0..4 {
+                try!(compare(fields[i], q[i], sign * reported.q[i], tolerance));
+            }
+        }
+    }
+    if let Some((roll, pitch, yaw)) = target.body_rates {
+        try!(compare("body_roll_rate", roll, reported.body_roll_rate, tolerance));
+        try!(compare("body_pitch_rate", pitch, reported.body_pitch_rate, tolerance));
+        try!(compare("body_yaw_rate", yaw, reported.body_yaw_rate, tolerance));
+    }
+    Ok(())
+}
+
+impl Setpoint {
+    /// Compare the setpoint with the target the vehicle reports in `POSITION_TARGET_LOCAL_NED`,
+    /// `POSITION_TARGET_GLOBAL_INT` or `ATTITUDE_TARGET`. Other messages, and reports of a
+    /// different kind of setpoint, are not compared.
+    pub fn compare(&self, msg: &MavMessage, tolerance: f32) -> Result<(), Divergence> {
+        match (self, msg) {
+            (&Setpoint::Local(frame, ref target), &MavMessage::POSITION_TARGET_LOCAL_NED(ref data)) => {
+                try!(compare_frame_and_mask(frame, target, data.coordinate_frame, data.type_mask));
+                let reported = PositionTarget {
+                    position: Some((data.x as f64, data.y as f64, data.z)),
+                    velocity: Some((data.vx, data.vy, data.vz)),
+                    acceleration: Some((data.afx, data.afy, data.afz)),
+                    yaw: Some(data.yaw),
+                    yaw_rate: Some(data.yaw_rate),
+                };
+                compare_position(target, &reported, false, tolerance)
+            }
+            (&Setpoint::Global(frame, ref target), &MavMessage::POSITION_TARGET_GLOBAL_INT(ref data)) => {
+                try!(compare_frame_and_mask(frame, target, data.coordinate_frame, data.type_mask));
+                let reported = PositionTarget {
+                    position: Some((data.lat_int as f64 / 1e7, data.lon_int as f64 / 1e7, data.alt)),
+                    velocity: Some((data.vx, data.vy, data.vz)),
+                    acceleration: Some((data.afx, data.afy, data.afz)),
+                    yaw: Some(data.yaw),
+                    yaw_rate: Some(data.yaw_rate),
+                };
+                compare_position(target, &reported, true, tolerance)
+            }
+            (&Setpoint::Attitude(ref target), &MavMessage::ATTITUDE_TARGET(ref data)) => {
+                compare_attitude(target, data, tolerance)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn compare_frame_and_mask(frame: MAV_FRAME, target: &PositionTarget, reported_frame: u8, reported_mask: u16)
+                          -> Result<(), Divergence> {
+    if reported_frame != frame as u8 {
+        return Err(Divergence::Frame { sent: frame as u8, reported: reported_frame });
+    }
+    // Bit 9 selects force instead of acceleration and is never set here; the rest are reserved.
+    let relevant = IGNORE_POSITION | IGNORE_VELOCITY | IGNORE_ACCELERATION | IGNORE_YAW | IGNORE_YAW_RATE;
+    if reported_mask & relevant != target.type_mask() {
+        return Err(Divergence::TypeMask { sent: target.type_mask(), reported: reported_mask & relevant });
+    }
+    Ok(())
+}
+
+/// Sends a setpoint at a steady rate from a background thread until dropped.
+///
+/// Offboard and guided modes fall back to a failsafe when setpoints stop arriving, so the
+/// streamer keeps repeating the latest one. It does not read from the connection: pass it
+/// the vehicle's `POSITION_TARGET_*` and `ATTITUDE_TARGET` messages with `check` to find out
+/// whether the vehicle follows the setpoint.
+pub struct SetpointStreamer {
+    /// The largest difference between a sent and a reported value that `check` accepts.
+    /// Positions are compared in meters.
+    pub tolerance: f32,
+    sender: PeriodicSender<Option<Setpoint>>,
+}
+
+impl SetpointStreamer {
+    /// Start sending setpoints to the target every `interval`. Nothing is sent until the first
+    /// call to `set`.
+    pub fn start(conn: Arc<MavConnection + Sync + Send>,
+                 target_system: u8,
+                 target_component: u8,
+                 interval: Duration)
+                 -> SetpointStreamer {
+        let boot = Instant::now();
+        let next = move |setpoint: &mut Option<Setpoint>, stopping: bool| match *setpoint {
+            Some(setpoint) if !stopping => {
+                let elapsed = boot.elapsed();
+                let time_boot_ms = elapsed.as_secs() as u32 * 1000 + elapsed.subsec_nanos() / 1_000_000;
+                Some(setpoint.message(target_system, target_component, time_boot_ms))
+            }
+            _ => None,
+        };
+        SetpointStreamer {
+            tolerance: 0.1,
+            sender: PeriodicSender::start(None, interval, next, move |msg| conn.send(msg).is_ok()),
+        }
+    }
+
+    /// The setpoint currently being sent.
+    pub fn setpoint(&self) -> Option<Setpoint> {
+        self.sender.with(|setpoint| *setpoint)
+    }
+
+    /// Change the setpoint, sending it straight away.
+    pub fn set(&self, setpoint: Setpoint) {
+        self.sender.update_now(|current| *current = Some(setpoint));
+    }
+
+    /// Stop sending setpoints until the next call to `set`.
+    pub fn clear(&self) {
+        self.sender.update_now(|current| *current = None);
+    }
+
+    /// Compare the current setpoint with the target the vehicle reports in `msg`.
+    pub fn check(&self, msg: &MavMessage) -> Result<(), Divergence> {
+        match self.setpoint() {
+            Some(setpoint) => setpoint.compare(msg, self.tolerance),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_offboard {
+    use super::*;
+    use loopback::loopback;
+
+    #[test]
+    pub fn test_type_mask() {
+        let velocity = PositionTarget { velocity: Some((1.0, 0.0, -0.5)), ..PositionTarget::default() };
+        assert_eq!(velocity.type_mask(), 0xdc7);
+        let position_yaw = PositionTarget {
+            position: Some((10.0, 5.0, -2.0)),
+            yaw: Some(1.5),
+            ..PositionTarget::default()
+        };
+        assert_eq!(position_yaw.type_mask(), 0x9f8);
+        assert_eq!(PositionTarget::default().type_mask(), 0xdff);
+
+        let attitude = AttitudeTarget { attitude: Some([1.0, 0.0, 0.0, 0.0]), thrust: Some(0.5), body_rates: None };
+        assert_eq!(attitude.type_mask(), 0x07);
+        let rates = AttitudeTarget { body_rates: Some((0.1, 0.0, 0.0)), ..AttitudeTarget::default() };
+        assert_eq!(rates.type_mask(), 0xc0);
+    }
+
+    #[test]
+    pub fn test_stream_and_check() {
+        let (gcs, vehicle) = loopback();
+        let gcs: Arc<MavConnection + Sync + Send> = Arc::new(gcs);
+        let mut streamer = SetpointStreamer::start(gcs.clone(), 1, 1, Duration::from_millis(20));
+        drop(gcs);
+
+        let velocity = PositionTarget { velocity: Some((1.0, 0.0, -0.5)), ..PositionTarget::default() };
+        streamer.set(Setpoint::Local(MAV_FRAME::MAV_FRAME_LOCAL_NED, velocity));
+        let mut sent = Vec::new();
+        while sent.len() < 3 {
+            match vehicle.recv().unwrap() {
+                MavMessage::SET_POSITION_TARGET_LOCAL_NED(data) => sent.push(data),
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        assert!(sent.iter().all(|data| data.type_mask == 0xdc7 && data.vx == 1.0 && data.vz == -0.5));
+        assert_eq!(sent[0].target_system, 1);
+
+        let global = PositionTarget { position: Some((47.3977, 8.5456, 10.0)), ..PositionTarget::default() };
+        streamer.set(Setpoint::Global(MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, global));
+        loop {
+            if let MavMessage::SET_POSITION_TARGET_GLOBAL_INT(data) = vehicle.recv().unwrap() {
+                assert_eq!((data.lat_int, data.lon_int, data.alt), (473977000, 85456000, 10.0));
+                assert_eq!(data.type_mask, 0xdf8);
+                break;
+            }
+        }
+
+        // The vehicle reports the target it follows, here a little off and then ignoring altitude.
+        let mut report = common::POSITION_TARGET_GLOBAL_INT_DATA {
+            time_boot_ms: 0,
+            lat_int: 473977003,
+            lon_int: 85456000,
+            alt: 10.05,
+            vx: 0.0,
+            vy: 0.0,
+            vz: 0.0,
+            afx: 0.0,
+            afy: 0.0,
+            afz: 0.0,
+            yaw: 0.0,
+            yaw_rate: 0.0,
+            type_mask: 0xdf8,
+            coordinate_frame: MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT as u8,
+        };
+        assert_eq!(streamer.check(&MavMessage::POSITION_TARGET_GLOBAL_INT(report.clone())), Ok(()));
+        report.alt = 12.0;
+        match streamer.check(&MavMessage::POSITION_TARGET_GLOBAL_INT(report.clone())) {
+            Err(Divergence::Value { field: "alt", .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        streamer.tolerance = 5.0;
+        assert_eq!(streamer.check(&MavMessage::POSITION_TARGET_GLOBAL_INT(report.clone())), Ok(()));
+        report.type_mask = 0xdfc;
+        assert_eq!(streamer.check(&MavMessage::POSITION_TARGET_GLOBAL_INT(report)),
+                   Err(Divergence::TypeMask { sent: 0xdf8, reported: 0xdfc }));
+
+        streamer.clear();
+        while vehicle.recv_frame_timeout(Duration::from_millis(100)).is_ok() {}
+        drop(streamer);
+        assert!(vehicle.recv().is_err());
+    }
+
+    #[test]
+    pub fn test_nan_setpoint_keeps_rate() {
+        let (gcs, vehicle) = loopback();
+        let gcs: Arc<MavConnection + Sync + Send> = Arc::new(gcs);
+        let streamer = SetpointStreamer::start(gcs.clone(), 1, 1, Duration::from_secs(10));
+        drop(gcs);
+
+        // NaN never equals itself, which must not make the setpoint look new on every wakeup.
+        let target = PositionTarget {
+            position: Some((0.0, 0.0, -5.0)),
+            yaw: Some(::std::f32::NAN),
+            ..PositionTarget::default()
+        };
+        streamer.set(Setpoint::Local(MAV_FRAME::MAV_FRAME_LOCAL_NED, target));
+        assert!(vehicle.recv_frame_timeout(Duration::from_secs(1)).is_ok());
+        assert!(vehicle.recv_frame_timeout(Duration::from_millis(200)).is_err());
+
+        // Setting it again is still sent at once.
+        streamer.set(Setpoint::Local(MAV_FRAME::MAV_FRAME_LOCAL_NED, target));
+        assert!(vehicle.recv_frame_timeout(Duration::from_secs(1)).is_ok());
+        drop(streamer);
+        assert!(vehicle.recv().is_err());
+    }
+}
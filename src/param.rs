@@ -0,0 +1,427 @@
+use common::{self, MavMessage, MAV_PARAM_TYPE, MAV_AUTOPILOT};
+use connection::MavConnection;
+
+use std::time::{Duration, Instant};
+use std::io;
+
+/// Length of the NUL-padded `param_id` field.
+const PARAM_ID_LEN: usize = 16;
+
+/// Convert a parameter name to the NUL-padded `param_id` field.
+pub fn param_id(name: &str) -> Vec<u8> {
+    let mut id: Vec<u8> = name.bytes().take(PARAM_ID_LEN).collect();
+    id.resize(PARAM_ID_LEN, 0);
+    id
+}
+
+/// Convert a NUL-padded `param_id` field to the parameter name.
+pub fn param_name(id: &[u8]) -> String {
+    let end = id.iter().position(|&c| c == 0).unwrap_or(id.len());
+    String::from_utf8_lossy(&id[..end]).into_owned()
+}
+
+/// How integer parameters are carried in the float `param_value` field.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParamEncoding {
+    /// The bytes of the integer are stored in the float, as PX4 does.
+    Bytewise,
+    /// The integer is converted to a float, as ArduPilot does.
+    Cast,
+}
+
+impl ParamEncoding {
+    /// The encoding used by the given `MAV_AUTOPILOT`, as reported in `HEARTBEAT.autopilot`.
+    pub fn for_autopilot(autopilot: u8) -> ParamEncoding {
+        if autopilot == MAV_AUTOPILOT::MAV_AUTOPILOT_PX4 as u8 {
+            ParamEncoding::Bytewise
+        } else {
+            ParamEncoding::Cast
+        }
+    }
+}
+
+/// A typed parameter value.
+///
+/// 64-bit types do not fit in `param_value` and are not supported by the parameter protocol.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ParamValue {
+    UInt8(u8),
+    Int8(i8),
+    UInt16(u16),
+    Int16(i16),
+    UInt32(u32),
+    Int32(i32),
+    Real32(f32),
+}
+
+impl ParamValue {
+    /// Decode `param_value` according to its `MAV_PARAM_TYPE`.
+    pub fn decode(value: f32, param_type: u8, encoding: ParamEncoding) -> Option<ParamValue> {
+        use common::MAV_PARAM_TYPE::*;
+        let param_type = match MAV_PARAM_TYPE::from_u32(param_type as u32) {
+            Some(t) => t,
+            None => return None,
+        };
+
+        if param_type == MAV_PARAM_TYPE_REAL32 {
+            return Some(ParamValue::Real32(value));
+        }
+
+        let bits = match encoding {
+            ParamEncoding::Bytewise => value.to_bits(),
+            ParamEncoding::Cast => value.round() as i64 as u32,
+        };
+
+        match param_type {
+            MAV_PARAM_TYPE_UINT8 => Some(ParamValue::UInt8(bits as u8)),
+            MAV_PARAM_TYPE_INT8 => Some(ParamValue::Int8(bits as u8 as i8)),
+            MAV_PARAM_TYPE_UINT16 => Some(ParamValue::UInt16(bits as u16)),
+            MAV_PARAM_TYPE_INT16 => Some(ParamValue::Int16(bits as u16 as i16)),
+            MAV_PARAM_TYPE_UINT32 => Some(ParamValue::UInt32(bits)),
+            MAV_PARAM_TYPE_INT32 => Some(ParamValue::Int32(bits as i32)),
+            _ => None,
+        }
+    }
+
+    /// Encode into `param_value` and the `MAV_PARAM_TYPE`.
+    pub fn encode(&self, encoding: ParamEncoding) -> (f32, u8) {
+        let (bits, cast) = match *self {
+            ParamValue::Real32(v) => return (v, self.param_type() as u8),
+            ParamValue::UInt8(v) => (v as u32, v as f32),
+            ParamValue::Int8(v) => (v as u8 as u32, v as f32),
+            ParamValue::UInt16(v) => (v as u32, v as f32),
+            ParamValue::Int16(v) => (v as u16 as u32, v as f32),
+            ParamValue::UInt32(v) => (v, v as f32),
+            ParamValue::Int32(v) => (v as u32, v as f32),
+        };
+        let value = match encoding {
+            ParamEncoding::Bytewise => f32::from_bits(bits),
+            ParamEncoding::Cast => cast,
+        };
+        (value, self.param_type() as u8)
+    }
+
+    pub fn param_type(&self) -> MAV_PARAM_TYPE {
+        use common::MAV_PARAM_TYPE::*;
+        match *self {
+            ParamValue::UInt8(..) => MAV_PARAM_TYPE_UINT8,
+            ParamValue::Int8(..) => MAV_PARAM_TYPE_INT8,
+            ParamValue::UInt16(..) => MAV_PARAM_TYPE_UINT16,
+            ParamValue::Int16(..) => MAV_PARAM_TYPE_INT16,
+            ParamValue::UInt32(..) => MAV_PARAM_TYPE_UINT32,
+            ParamValue::Int32(..) => MAV_PARAM_TYPE_INT32,
+            ParamValue::Real32(..) => MAV_PARAM_TYPE_REAL32,
+        }
+    }
+
+    /// The value as a float, regardless of its type.
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            ParamValue::UInt8(v) => v as f64,
+            ParamValue::Int8(v) => v as f64,
+            ParamValue::UInt16(v) => v as f64,
+            ParamValue::Int16(v) => v as f64,
+            ParamValue::UInt32(v) => v as f64,
+            ParamValue::Int32(v) => v as f64,
+            ParamValue::Real32(v) => v as f64,
+        }
+    }
+}
+
+/// A parameter reported by the vehicle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub index: u16,
+    pub value: ParamValue,
+}
+
+/// Client for the parameter protocol of a single component.
+pub struct ParamClient<'a> {
+    conn: &'a MavConnection,
+    pub target_system: u8,
+    pub target_component: u8,
+    pub encoding: ParamEncoding,
+    /// How long to wait for a reply before re-sending a request.
+    pub timeout: Duration,
+    /// How many times a request is re-sent without a reply before giving up.
+    pub retries: usize,
+}
+
+impl<'a> ParamClient<'a> {
+    pub fn new(conn: &'a MavConnection, target_system: u8, target_component: u8) -> ParamClient<'a> {
+        ParamClient {
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+            encoding: ParamEncoding::Cast,
+            timeout: Duration::from_millis(1000),
+            retries: 5,
+        }
+    }
+
+    /// Wait up to `timeout` for a `PARAM_VALUE` from the target, returning `None` for values
+    /// that cannot be decoded.
+    fn recv_value(&self) -> io::Result<(u16, Option<Param>)> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply to parameter request"));
+            }
+            let (header, msg) = try!(self.conn.recv_frame_timeout(deadline - now));
+            if header.system_id != self.target_system ||
+               (self.target_component != 0 && header.component_id != self.target_component) {
+                continue;
+            }
+            if let MavMessage::PARAM_VALUE(v) = msg {
+                let param = ParamValue::decode(v.param_value, v.param_type, self.encoding).map(|value| {
+                    Param {
+                        name: param_name(&v.param_id),
+                        index: v.param_index,
+                        value: value,
+                    }
+                });
+                return Ok((v.param_count, param));
+            }
+        }
+    }
+
+    fn request_list(&self) -> io::Result<()> {
+        self.conn.send(&MavMessage::PARAM_REQUEST_LIST(common::PARAM_REQUEST_LIST_DATA {
+            target_system: self.target_system,
+            target_component: self.target_component,
+        }))
+    }
+
+    fn request_read(&self, name: &str, index: i16) -> io::Result<()> {
+        self.conn.send(&MavMessage::PARAM_REQUEST_READ(common::PARAM_REQUEST_READ_DATA {
+            param_index: index,
+            target_system: self.target_system,
+            target_component: self.target_component,
+            param_id: param_id(name),
+        }))
+    }
+
+    /// Download the complete parameter table, ordered by index.
+    ///
+    /// Parameters missing from the streamed list are re-requested individually until the table
+    /// is complete or `retries` rounds pass without progress. A value with a `param_count` of 0
+    /// gives an empty table, but a component without parameters that does not answer at all
+    /// cannot be told from one that is not there, so that ends in a `TimedOut` error.
+    pub fn fetch_all(&self) -> io::Result<Vec<Param>> {
+        try!(self.request_list());
+
+        let mut params: Vec<Option<Param>> = Vec::new();
+        let mut count = None;
+        let mut retries = 0;
+
+        loop {
+            match self.recv_value() {
+                Ok((param_count, param)) => {
+                    if count.is_none() {
+                        count = Some(param_count);
+                        params.resize(param_count as usize, None);
+                    }
+                    if let Some(param) = param {
+                        let index = param.index as usize;
+                        if index < params.len() && params[index].is_none() {
+                            params[index] = Some(param);
+                            retries = 0;
+                        }
+                    }
+                    if params.iter().all(|p| p.is_some()) {
+                        return Ok(params.into_iter().map(|p| p.unwrap()).collect());
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut && retries < self.retries => {
+                    retries += 1;
+                    if count.is_none() {
+                        try!(self.request_list());
+                    }
+                    for (index, _) in params.iter().enumerate().filter(|&(_, p)| p.is_none()) {
+                        try!(self.request_read("", index as i16));
+                    }
+                }
+                Err(e) => {
+                    let missing = params.iter().filter(|p| p.is_none()).count();
+                    return Err(if count.is_some() {
+                        io::Error::new(e.kind(), format!("{} of {} parameters missing", missing, params.len()))
+                    } else {
+                        e
+                    });
+                }
+            }
+        }
+    }
+
+    fn fetch_matching<F: Fn(&Param) -> bool>(&self, name: &str, index: i16, matches: F) -> io::Result<Param> {
+        for _ in 0..self.retries + 1 {
+            try!(self.request_read(name, index));
+            loop {
+                match self.recv_value() {
+                    Ok((_, Some(ref param))) if matches(param) => return Ok(param.clone()),
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no reply to parameter request"))
+    }
+
+    /// Read a single parameter by name.
+    pub fn fetch(&self, name: &str) -> io::Result<Param> {
+        self.fetch_matching(name, -1, |p| p.name == name)
+    }
+
+    /// Read a single parameter by index.
+    pub fn fetch_index(&self, index: u16) -> io::Result<Param> {
+        self.fetch_matching("", index as i16, |p| p.index == index)
+    }
+
+    /// Set a parameter, waiting for the vehicle to confirm the new value.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the vehicle reports a different value back, which
+    /// is how autopilots reject a change.
+    pub fn set(&self, name: &str, value: ParamValue) -> io::Result<Param> {
+        let (param_value, param_type) = value.encode(self.encoding);
+        let mut reported = None;
+        for _ in 0..self.retries + 1 {
+            try!(self.conn.send(&MavMessage::PARAM_SET(common::PARAM_SET_DATA {
+                param_value: param_value,
+                target_system: self.target_system,
+                target_component: self.target_component,
+                param_id: param_id(name),
+                param_type: param_type,
+            })));
+            loop {
+                match self.recv_value() {
+                    Ok((_, Some(param))) => {
+                        if param.name == name {
+                            if param.value == value {
+                                return Ok(param);
+                            }
+                            // Possibly a stale value still in flight, so keep waiting for the echo
+                            reported = Some(param.value);
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            if let Some(reported) = reported {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{} was not changed, vehicle reports {:?}", name, reported)));
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no reply to parameter set"))
+    }
+}
+
+#[cfg(test)]
+mod test_param {
+    use super::*;
+    use loopback::{loopback_with, LoopbackConfig};
+    use Header;
+    use std::thread;
+
+    #[test]
+    pub fn test_encoding() {
+        for &encoding in &[ParamEncoding::Bytewise, ParamEncoding::Cast] {
+            for &value in &[ParamValue::UInt8(200), ParamValue::Int8(-3), ParamValue::Int16(-1234),
+                            ParamValue::UInt32(70000), ParamValue::Int32(-5), ParamValue::Real32(1.5)] {
+                let (v, t) = value.encode(encoding);
+                assert_eq!(ParamValue::decode(v, t, encoding), Some(value));
+            }
+        }
+        assert_eq!(ParamValue::Int32(3).encode(ParamEncoding::Cast).0, 3.0);
+        assert_eq!(ParamValue::Int32(3).encode(ParamEncoding::Bytewise).0.to_bits(), 3);
+        assert_eq!(param_name(&param_id("RATE_RLL_P")), "RATE_RLL_P");
+    }
+
+    /// A vehicle with `n` parameters named `P<index>`, served until the client hangs up.
+    fn serve(conn: &MavConnection, n: u16) {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut values: Vec<f32> = (0..n).map(|i| i as f32).collect();
+        let value = |index: u16, values: &Vec<f32>| {
+            MavMessage::PARAM_VALUE(common::PARAM_VALUE_DATA {
+                param_value: values[index as usize],
+                param_count: n,
+                param_index: index,
+                param_id: param_id(&format!("P{}", index)),
+                param_type: MAV_PARAM_TYPE::MAV_PARAM_TYPE_REAL32 as u8,
+            })
+        };
+        let index_of = |id: &[u8]| param_name(id)[1..].parse::<u16>().unwrap();
+
+        while let Ok(msg) = conn.recv() {
+            match msg {
+                MavMessage::PARAM_REQUEST_LIST(..) if n == 0 => {
+                    // Without parameters, answer with a lone value that only carries the count.
+                    conn.send_frame(header, &MavMessage::PARAM_VALUE(common::PARAM_VALUE_DATA {
+                        param_value: 0.0,
+                        param_count: 0,
+                        param_index: ::std::u16::MAX,
+                        param_id: param_id(""),
+                        param_type: MAV_PARAM_TYPE::MAV_PARAM_TYPE_REAL32 as u8,
+                    })).ok();
+                }
+                MavMessage::PARAM_REQUEST_LIST(..) => {
+                    for i in 0..n {
+                        conn.send_frame(header, &value(i, &values)).ok();
+                    }
+                }
+                MavMessage::PARAM_REQUEST_READ(r) => {
+                    let index = if r.param_index >= 0 { r.param_index as u16 } else { index_of(&r.param_id) };
+                    conn.send_frame(header, &value(index, &values)).ok();
+                }
+                MavMessage::PARAM_SET(s) => {
+                    let index = index_of(&s.param_id);
+                    if index != 0 {
+                        values[index as usize] = s.param_value;
+                    }
+                    conn.send_frame(header, &value(index, &values)).ok();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_fetch_and_set() {
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || serve(&vehicle, 50));
+
+        {
+            let mut client = ParamClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let params = client.fetch_all().unwrap();
+            assert_eq!(params.len(), 50);
+            for (i, p) in params.iter().enumerate() {
+                assert_eq!(p.name, format!("P{}", i));
+                assert_eq!(p.value, ParamValue::Real32(i as f32));
+            }
+
+            assert_eq!(client.fetch("P7").unwrap().value, ParamValue::Real32(7.0));
+            assert_eq!(client.fetch_index(8).unwrap().name, "P8");
+            assert_eq!(client.set("P3", ParamValue::Real32(0.5)).unwrap().value, ParamValue::Real32(0.5));
+            assert_eq!(client.set("P0", ParamValue::Real32(0.5)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        drop(gcs);
+        vehicle.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_fetch_empty() {
+        let (gcs, vehicle) = loopback_with(LoopbackConfig::default());
+        let vehicle = thread::spawn(move || serve(&vehicle, 0));
+        assert!(ParamClient::new(&gcs, 1, 1).fetch_all().unwrap().is_empty());
+        drop(gcs);
+        vehicle.join().unwrap();
+    }
+}
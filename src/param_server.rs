@@ -0,0 +1,406 @@
+use common::{self, MavMessage};
+use param::{ParamEncoding, ParamValue, param_id, param_name};
+use Header;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Persistent storage for the values of a `ParamServer`.
+pub trait ParamStorage {
+    /// Load the stored values by name.
+    fn load(&mut self) -> io::Result<Vec<(String, ParamValue)>>;
+
+    /// Store a changed value.
+    fn save(&mut self, name: &str, value: ParamValue) -> io::Result<()>;
+}
+
+/// Storage that keeps values in memory only, so they are lost when it is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    pub values: BTreeMap<String, ParamValue>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl ParamStorage for MemoryStorage {
+    fn load(&mut self) -> io::Result<Vec<(String, ParamValue)>> {
+        Ok(self.values.iter().map(|(name, &value)| (name.clone(), value)).collect())
+    }
+
+    fn save(&mut self, name: &str, value: ParamValue) -> io::Result<()> {
+        self.values.insert(name.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Storage in a text file with one `NAME TYPE VALUE` line per parameter, where `TYPE` is the
+/// `MAV_PARAM_TYPE` number. Integers are written as integers so that 32-bit values keep every
+/// digit. The whole file is rewritten on every change.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    path: PathBuf,
+    values: BTreeMap<String, ParamValue>,
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileStorage {
+        FileStorage {
+            path: path.into(),
+            values: BTreeMap::new(),
+        }
+    }
+}
+
+impl ParamStorage for FileStorage {
+    /// A missing file holds no values. Lines that cannot be parsed are skipped.
+    fn load(&mut self) -> io::Result<Vec<(String, ParamValue)>> {
+        self.values.clear();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                continue;
+            }
+            if let Some(value) = parse_value(fields[1], fields[2]) {
+                self.values.insert(fields[0].to_string(), value);
+            }
+        }
+        Ok(self.values.iter().map(|(name, &value)| (name.clone(), value)).collect())
+    }
+
+    fn save(&mut self, name: &str, value: ParamValue) -> io::Result<()> {
+        self.values.insert(name.to_string(), value);
+        let mut file = try!(File::create(&self.path));
+        for (name, value) in &self.values {
+            let text = match *value {
+                ParamValue::UInt8(v) => v.to_string(),
+                ParamValue::Int8(v) => v.to_string(),
+                ParamValue::UInt16(v) => v.to_string(),
+                ParamValue::Int16(v) => v.to_string(),
+                ParamValue::UInt32(v) => v.to_string(),
+                ParamValue::Int32(v) => v.to_string(),
+                ParamValue::Real32(v) => v.to_string(),
+            };
+            try!(writeln!(file, "{} {} {}", name, value.param_type() as u8, text));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a value written by `FileStorage::save`, parsing integer types as integers.
+fn parse_value(param_type: &str, value: &str) -> Option<ParamValue> {
+    use common::MAV_PARAM_TYPE::*;
+    let param_type = match param_type.parse::<u32>().ok().and_then(common::MAV_PARAM_TYPE::from_u32) {
+        Some(param_type) => param_type,
+        None => return None,
+    };
+    match param_type {
+        MAV_PARAM_TYPE_UINT8 => value.parse().ok().map(ParamValue::UInt8),
+        MAV_PARAM_TYPE_INT8 => value.parse().ok().map(ParamValue::Int8),
+        MAV_PARAM_TYPE_UINT16 => value.parse().ok().map(ParamValue::UInt16),
+        MAV_PARAM_TYPE_INT16 => value.parse().ok().map(ParamValue::Int16),
+        MAV_PARAM_TYPE_UINT32 => value.parse().ok().map(ParamValue::UInt32),
+        MAV_PARAM_TYPE_INT32 => value.parse().ok().map(ParamValue::Int32),
+        MAV_PARAM_TYPE_REAL32 => value.parse().ok().map(ParamValue::Real32),
+        _ => None,
+    }
+}
+
+/// The vehicle side of the parameter protocol, serving a table of typed parameters.
+///
+/// Pass every received frame to `handle` and send back the reply it returns, and call `poll`
+/// regularly to stream the table after a `PARAM_REQUEST_LIST`, one value per `interval` so
+/// that slow links are not flooded. Replies are returned rather than sent so that the caller
+/// can send them with its own system id and sequence numbers.
+pub struct ParamServer<S: ParamStorage> {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub encoding: ParamEncoding,
+    /// Time between two values streamed in answer to `PARAM_REQUEST_LIST`.
+    pub interval: Duration,
+    params: Vec<(String, ParamValue)>,
+    storage: S,
+    stored: BTreeMap<String, ParamValue>,
+    /// Index of the next value to stream, if a list was requested.
+    next: Option<usize>,
+    last_sent: Option<Instant>,
+}
+
+impl<S: ParamStorage> ParamServer<S> {
+    /// Create a server without parameters, loading the stored values from `storage`.
+    pub fn new(system_id: u8, component_id: u8, mut storage: S) -> io::Result<ParamServer<S>> {
+        let stored = try!(storage.load()).into_iter().collect();
+        Ok(ParamServer {
+            system_id: system_id,
+            component_id: component_id,
+            encoding: ParamEncoding::Cast,
+            interval: Duration::from_millis(10),
+            params: Vec::new(),
+            storage: storage,
+            stored: stored,
+            next: None,
+            last_sent: None,
+        })
+    }
+
+    /// Add a parameter and return its index.
+    ///
+    /// Its value is the stored one if there is a stored value of the same type, else `default`.
+    pub fn add(&mut self, name: &str, default: ParamValue) -> u16 {
+        let value = match self.stored.get(name) {
+            Some(&stored) if stored.param_type() == default.param_type() => stored,
+            _ => default,
+        };
+        match self.index_of(name) {
+            Some(index) => {
+                self.params[index].1 = value;
+                index as u16
+            }
+            None => {
+                self.params.push((name.to_string(), value));
+                (self.params.len() - 1) as u16
+            }
+        }
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.params.iter().position(|&(ref n, _)| n == name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ParamValue> {
+        self.index_of(name).map(|index| self.params[index].1)
+    }
+
+    /// Change a parameter locally and store it.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if there is no such parameter or the type differs.
+    pub fn set(&mut self, name: &str, value: ParamValue) -> io::Result<()> {
+        let index = match self.index_of(name) {
+            Some(index) if self.params[index].1.param_type() == value.param_type() => index,
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} has a different type", name))),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no parameter {}", name))),
+        };
+        try!(self.storage.save(name, value));
+        self.params[index].1 = value;
+        Ok(())
+    }
+
+    pub fn params(&self) -> Vec<(String, ParamValue)> {
+        self.params.clone()
+    }
+
+    fn value_message(&self, index: usize) -> MavMessage {
+        let (ref name, value) = self.params[index];
+        let (param_value, param_type) = value.encode(self.encoding);
+        MavMessage::PARAM_VALUE(common::PARAM_VALUE_DATA {
+            param_value: param_value,
+            param_count: self.params.len() as u16,
+            param_index: index as u16,
+            param_id: param_id(name),
+            param_type: param_type,
+        })
+    }
+
+    fn is_target(&self, target_system: u8, target_component: u8) -> bool {
+        target_system == self.system_id && (target_component == 0 || target_component == self.component_id)
+    }
+
+    /// Handle a received message, returning the `PARAM_VALUE` to send back, if any.
+    ///
+    /// A `PARAM_SET` with the wrong type, or an integer value out of range, is refused by
+    /// echoing the unchanged value. Fails only if the new value cannot be stored, in which case
+    /// it is not applied either.
+    pub fn handle(&mut self, _header: &Header, msg: &MavMessage) -> io::Result<Option<MavMessage>> {
+        match *msg {
+            MavMessage::PARAM_REQUEST_LIST(ref r) if self.is_target(r.target_system, r.target_component) => {
+                self.next = Some(0);
+                Ok(None)
+            }
+            MavMessage::PARAM_REQUEST_READ(ref r) if self.is_target(r.target_system, r.target_component) => {
+                let index = if r.param_index >= 0 {
+                    if (r.param_index as usize) < self.params.len() { Some(r.param_index as usize) } else { None }
+                } else {
+                    self.index_of(&param_name(&r.param_id))
+                };
+                Ok(index.map(|index| self.value_message(index)))
+            }
+            MavMessage::PARAM_SET(ref s) if self.is_target(s.target_system, s.target_component) => {
+                let index = match self.index_of(&param_name(&s.param_id)) {
+                    Some(index) => index,
+                    None => return Ok(None),
+                };
+                let current = self.params[index].1;
+                if s.param_type == current.param_type() as u8 {
+                    if let Some(value) = ParamValue::decode(s.param_value, s.param_type, self.encoding) {
+                        // Cast integers wrap when decoded, so check that nothing was lost.
+                        let in_range = match value {
+                            ParamValue::Real32(..) => true,
+                            _ => self.encoding == ParamEncoding::Bytewise ||
+                                value.as_f64() == (s.param_value as f64).round(),
+                        };
+                        if in_range && value != current {
+                            let name = self.params[index].0.clone();
+                            try!(self.storage.save(&name, value));
+                            self.params[index].1 = value;
+                        }
+                    }
+                }
+                Ok(Some(self.value_message(index)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The next value to stream in answer to `PARAM_REQUEST_LIST`, if one is due.
+    pub fn poll(&mut self) -> Option<MavMessage> {
+        let index = match self.next {
+            Some(index) if index < self.params.len() => index,
+            _ => {
+                self.next = None;
+                return None;
+            }
+        };
+        if self.last_sent.map_or(false, |t| t.elapsed() < self.interval) {
+            return None;
+        }
+        self.last_sent = Some(Instant::now());
+        self.next = Some(index + 1);
+        Some(self.value_message(index))
+    }
+
+    /// Whether a list is still being streamed.
+    pub fn is_streaming(&self) -> bool {
+        self.next.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test_param_server {
+    use super::*;
+    use common::MAV_PARAM_TYPE;
+    use connection::MavConnection;
+    use loopback::{loopback_with, LoopbackConfig};
+    use param::ParamClient;
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    fn server<S: ParamStorage>(storage: S) -> ParamServer<S> {
+        let mut server = ParamServer::new(1, 1, storage).unwrap();
+        for i in 0..40 {
+            server.add(&format!("P{}", i), ParamValue::Real32(i as f32));
+        }
+        server.add("MODE", ParamValue::UInt8(3));
+        server.interval = Duration::from_millis(1);
+        server
+    }
+
+    /// Serve parameters until the client hangs up.
+    fn serve<S: ParamStorage>(conn: &MavConnection, server: &mut ParamServer<S>) {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        loop {
+            match conn.recv_frame_timeout(Duration::from_millis(1)) {
+                Ok((h, msg)) => {
+                    if let Some(reply) = server.handle(&h, &msg).unwrap() {
+                        conn.send_frame(header, &reply).ok();
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(_) => return,
+            }
+            if let Some(value) = server.poll() {
+                conn.send_frame(header, &value).ok();
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_serve_client() {
+        let (gcs, vehicle) = loopback_with(LoopbackConfig { loss: 0.2, ..LoopbackConfig::default() });
+        let vehicle = thread::spawn(move || {
+            let mut server = server(MemoryStorage::new());
+            serve(&vehicle, &mut server);
+            server
+        });
+
+        {
+            let mut client = ParamClient::new(&gcs, 1, 1);
+            client.timeout = Duration::from_millis(50);
+            client.retries = 20;
+
+            let params = client.fetch_all().unwrap();
+            assert_eq!(params.len(), 41);
+            assert_eq!(params[5].name, "P5");
+            assert_eq!(params[40].value, ParamValue::UInt8(3));
+
+            // Drain the rest of the streamed list, so old values cannot be mistaken for refusals.
+            while gcs.recv_frame_timeout(Duration::from_millis(100)).is_ok() {}
+
+            assert_eq!(client.fetch("P7").unwrap().value, ParamValue::Real32(7.0));
+            assert_eq!(client.fetch_index(40).unwrap().name, "MODE");
+            assert_eq!(client.set("P3", ParamValue::Real32(0.5)).unwrap().value, ParamValue::Real32(0.5));
+            assert_eq!(client.set("MODE", ParamValue::UInt8(7)).unwrap().value, ParamValue::UInt8(7));
+
+            // Wrong types and out of range integers are refused.
+            assert_eq!(client.set("MODE", ParamValue::Real32(5.0)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(client.set("MODE", ParamValue::UInt16(300)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        drop(gcs);
+        let server = vehicle.join().unwrap();
+        assert_eq!(server.storage.values.get("P3"), Some(&ParamValue::Real32(0.5)));
+        assert_eq!(server.get("MODE"), Some(ParamValue::UInt8(7)));
+
+        // An integer that does not fit is refused even when the type field matches.
+        let mut server = server;
+        let set = MavMessage::PARAM_SET(common::PARAM_SET_DATA {
+            param_value: 300.0,
+            target_system: 1,
+            target_component: 1,
+            param_id: param_id("MODE"),
+            param_type: MAV_PARAM_TYPE::MAV_PARAM_TYPE_UINT8 as u8,
+        });
+        let header = Header { sequence: 0, system_id: 255, component_id: 0 };
+        assert!(server.handle(&header, &set).unwrap().is_some());
+        assert_eq!(server.get("MODE"), Some(ParamValue::UInt8(7)));
+    }
+
+    #[test]
+    pub fn test_file_storage() {
+        let path = env::temp_dir().join(format!("mavlink-params-{}.txt", ::std::process::id()));
+        {
+            let mut server = server(FileStorage::new(path.clone()));
+            server.set("P1", ParamValue::Real32(-2.5)).unwrap();
+            server.set("MODE", ParamValue::UInt8(9)).unwrap();
+            assert!(server.set("MODE", ParamValue::Int32(9)).is_err());
+            assert!(server.set("NOPE", ParamValue::Int32(9)).is_err());
+        }
+
+        let server = server(FileStorage::new(path.clone()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(server.get("P1"), Some(ParamValue::Real32(-2.5)));
+        assert_eq!(server.get("P2"), Some(ParamValue::Real32(2.0)));
+        assert_eq!(server.get("MODE"), Some(ParamValue::UInt8(9)));
+
+        // Integers beyond the precision of a float survive.
+        let mut storage = FileStorage::new(path.clone());
+        storage.save("ID", ParamValue::Int32(16_777_217)).unwrap();
+        storage.save("MASK", ParamValue::UInt32(0xffff_ffff)).unwrap();
+        let loaded = FileStorage::new(path.clone()).load().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, vec![("ID".to_string(), ParamValue::Int32(16_777_217)),
+                                ("MASK".to_string(), ParamValue::UInt32(0xffff_ffff))]);
+    }
+}
@@ -0,0 +1,92 @@
+use common::MavMessage;
+
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+struct Shared<S> {
+    state: S,
+    /// Bumped by `update_now` to cut the current interval short.
+    generation: u64,
+    running: bool,
+}
+
+/// Sends a message at a fixed interval from a background thread until dropped.
+///
+/// Each time round, the message is made from the state while holding its lock and sent after
+/// releasing it, so a slow connection never holds up the owner changing the state.
+pub struct PeriodicSender<S> {
+    shared: Arc<(Mutex<Shared<S>>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<S: Send + 'static> PeriodicSender<S> {
+    /// Every `interval`, send what `next` makes from the state, if anything, with `send`.
+    ///
+    /// `send` returns false once the connection is gone, which stops the thread quietly. When
+    /// the sender is dropped, `next` is called a last time with `stopping` set.
+    pub fn start<N, F>(state: S, interval: Duration, mut next: N, mut send: F) -> PeriodicSender<S>
+        where N: FnMut(&mut S, bool) -> Option<MavMessage> + Send + 'static,
+              F: FnMut(&MavMessage) -> bool + Send + 'static
+    {
+        let shared = Arc::new((Mutex::new(Shared { state: state, generation: 0, running: true }),
+                               Condvar::new()));
+        let thread = thread::spawn({
+            let shared = shared.clone();
+            move || {
+                let &(ref lock, ref cvar) = &*shared;
+                loop {
+                    let (msg, generation, running) = {
+                        let mut guard = lock.lock().unwrap();
+                        let running = guard.running;
+                        (next(&mut guard.state, !running), guard.generation, running)
+                    };
+                    if let Some(msg) = msg {
+                        if !send(&msg) {
+                            return;
+                        }
+                    }
+                    if !running {
+                        return;
+                    }
+                    let deadline = Instant::now() + interval;
+                    let mut guard = lock.lock().unwrap();
+                    while guard.running && guard.generation == generation {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        guard = cvar.wait_timeout(guard, deadline - now).unwrap().0;
+                    }
+                }
+            }
+        });
+        PeriodicSender {
+            shared: shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Read or change the state. A change is sent with the next message.
+    pub fn with<R, F: FnOnce(&mut S) -> R>(&self, f: F) -> R {
+        f(&mut (self.shared.0).lock().unwrap().state)
+    }
+
+    /// Change the state and send straight away rather than at the end of the interval.
+    pub fn update_now<F: FnOnce(&mut S)>(&self, f: F) {
+        let mut guard = (self.shared.0).lock().unwrap();
+        f(&mut guard.state);
+        guard.generation += 1;
+        self.shared.1.notify_all();
+    }
+}
+
+impl<S> Drop for PeriodicSender<S> {
+    fn drop(&mut self) {
+        (self.shared.0).lock().unwrap().running = false;
+        self.shared.1.notify_all();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
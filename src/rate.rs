@@ -0,0 +1,283 @@
+use common::{self, Command, MavMessage, MAV_DATA_STREAM, MAV_RESULT};
+use command::{CommandClient, CommandError};
+use connection::MavConnection;
+use Header;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::io;
+
+/// The `REQUEST_DATA_STREAM` stream that carries a message on ArduPilot, for autopilots that
+/// don't support `MAV_CMD_SET_MESSAGE_INTERVAL`.
+pub fn stream_for_message(message_id: u8) -> Option<MAV_DATA_STREAM> {
+    use common::MAV_DATA_STREAM::*;
+    match message_id {
+        // RAW_IMU, SCALED_PRESSURE, SCALED_IMU2, SCALED_IMU3, SCALED_PRESSURE2
+        27 | 29 | 116 | 129 | 137 => Some(MAV_DATA_STREAM_RAW_SENSORS),
+        // SYS_STATUS, GPS_RAW_INT, MISSION_CURRENT, NAV_CONTROLLER_OUTPUT, GPS2_RAW, POWER_STATUS
+        1 | 24 | 42 | 62 | 124 | 125 => Some(MAV_DATA_STREAM_EXTENDED_STATUS),
+        // RC_CHANNELS_RAW, SERVO_OUTPUT_RAW, RC_CHANNELS
+        35 | 36 | 65 => Some(MAV_DATA_STREAM_RC_CHANNELS),
+        // LOCAL_POSITION_NED, GLOBAL_POSITION_INT
+        32 | 33 => Some(MAV_DATA_STREAM_POSITION),
+        // ATTITUDE
+        30 => Some(MAV_DATA_STREAM_EXTRA1),
+        // VFR_HUD
+        74 => Some(MAV_DATA_STREAM_EXTRA2),
+        // SYSTEM_TIME, OPTICAL_FLOW, BATTERY_STATUS, VIBRATION
+        2 | 100 | 147 | 241 => Some(MAV_DATA_STREAM_EXTRA3),
+        _ => None,
+    }
+}
+
+/// Outcome of `RateClient::set_message_rate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateRequest {
+    /// The vehicle acknowledged `MAV_CMD_SET_MESSAGE_INTERVAL` with this result.
+    Interval(MAV_RESULT),
+    /// The command is not supported, so the rate of the whole stream carrying the message was
+    /// requested instead. There is no acknowledgement; use `measure_rate` to check it.
+    Stream(MAV_DATA_STREAM),
+}
+
+/// Client for the message rates of a single component.
+pub struct RateClient {
+    conn: Arc<MavConnection + Sync + Send>,
+    /// Sends the interval commands; its `timeout` and `retries` apply to them.
+    pub commands: CommandClient,
+    pub target_system: u8,
+    pub target_component: u8,
+}
+
+impl RateClient {
+    pub fn new(conn: Arc<MavConnection + Sync + Send>, target_system: u8, target_component: u8) -> RateClient {
+        RateClient {
+            commands: CommandClient::new(conn.clone()),
+            conn: conn,
+            target_system: target_system,
+            target_component: target_component,
+        }
+    }
+
+    fn is_target(&self, header: &Header) -> bool {
+        header.system_id == self.target_system &&
+            (self.target_component == 0 || header.component_id == self.target_component)
+    }
+
+    /// Set the interval between messages with `MAV_CMD_SET_MESSAGE_INTERVAL`.
+    ///
+    /// An interval of -1 disables the message and 0 restores its default rate.
+    pub fn set_message_interval(&self, message_id: u8, interval_us: i32) -> Result<MAV_RESULT, CommandError> {
+        let command = Command::SetMessageInterval {
+            message_id: message_id as f32,
+            interval: interval_us as f32,
+        };
+        self.commands.send(self.target_system, self.target_component, &command)
+    }
+
+    /// Query the interval between messages with `MAV_CMD_GET_MESSAGE_INTERVAL`.
+    ///
+    /// As reported in `MESSAGE_INTERVAL`, -1 means the message is disabled and 0 that it is not
+    /// available. A rejection of the command is returned as `Ok(Err(result))`.
+    pub fn get_message_interval(&self, message_id: u8) -> Result<Result<i32, MAV_RESULT>, CommandError> {
+        let command = Command::GetMessageInterval { message_id: message_id as f32 };
+        self.commands.send_for_reply(self.target_system, self.target_component, &command, move |m| match *m {
+            MavMessage::MESSAGE_INTERVAL(ref i) if i.message_id == message_id as u16 => Some(i.interval_us),
+            _ => None,
+        })
+    }
+
+    /// Request a message at `hz` times per second, or disable it if `hz` is zero.
+    ///
+    /// Falls back to `REQUEST_DATA_STREAM` only if the vehicle answers `MAV_RESULT_UNSUPPORTED`
+    /// and the stream carrying the message is known. Streams only take whole rates, so `hz` is
+    /// rounded up there, to at least 1. Disabling never falls back, since that would stop every
+    /// message of the stream.
+    pub fn set_message_rate(&self, message_id: u8, hz: f32) -> Result<RateRequest, CommandError> {
+        let interval = if hz > 0.0 { (1e6 / hz).round() as i32 } else { -1 };
+        let result = try!(self.set_message_interval(message_id, interval));
+        match stream_for_message(message_id) {
+            Some(stream) if result == MAV_RESULT::MAV_RESULT_UNSUPPORTED && hz > 0.0 => {
+                try!(self.request_data_stream(stream, hz.ceil().max(1.0) as u16));
+                Ok(RateRequest::Stream(stream))
+            }
+            _ => Ok(RateRequest::Interval(result)),
+        }
+    }
+
+    /// Start a stream at `hz` times per second, or stop it if `hz` is zero.
+    pub fn request_data_stream(&self, stream: MAV_DATA_STREAM, hz: u16) -> io::Result<()> {
+        self.conn.send(&MavMessage::REQUEST_DATA_STREAM(common::REQUEST_DATA_STREAM_DATA {
+            req_message_rate: hz,
+            target_system: self.target_system,
+            target_component: self.target_component,
+            req_stream_id: stream as u8,
+            start_stop: (hz > 0) as u8,
+        }))
+    }
+
+    /// Count a message from the target for `duration` and return its rate in messages per second.
+    pub fn measure_rate(&self, message_id: u8, duration: Duration) -> io::Result<f64> {
+        let mut meter = RateMeter::new(duration);
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match self.conn.recv_frame_timeout(deadline - now) {
+                Ok((header, msg)) => if self.is_target(&header) { meter.handle(&header, &msg) },
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(meter.rate(self.target_system, self.target_component, message_id).unwrap_or(0.0))
+    }
+}
+
+/// Measures the rate at which each message arrives from each system and component.
+pub struct RateMeter {
+    /// Messages older than this are forgotten.
+    pub window: Duration,
+    arrivals: HashMap<(u8, u8, u8), VecDeque<Instant>>,
+}
+
+impl RateMeter {
+    pub fn new(window: Duration) -> RateMeter {
+        RateMeter {
+            window: window,
+            arrivals: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) {
+        let now = Instant::now();
+        let window = self.window;
+        let arrivals = self.arrivals
+            .entry((header.system_id, header.component_id, msg.message_id()))
+            .or_insert_with(VecDeque::new);
+        arrivals.push_back(now);
+        while arrivals.front().map_or(false, |&t| now.duration_since(t) > window) {
+            arrivals.pop_front();
+        }
+    }
+
+    /// Messages per second within the window, measured between the first and last arrival.
+    pub fn rate(&self, system_id: u8, component_id: u8, message_id: u8) -> Option<f64> {
+        let arrivals = match self.arrivals.get(&(system_id, component_id, message_id)) {
+            Some(arrivals) if arrivals.len() >= 2 => arrivals,
+            _ => return None,
+        };
+        let span = arrivals[arrivals.len() - 1].duration_since(arrivals[0]);
+        let secs = span.as_secs() as f64 + span.subsec_nanos() as f64 * 1e-9;
+        if secs > 0.0 { Some((arrivals.len() - 1) as f64 / secs) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod test_rate {
+    use super::*;
+    use loopback::loopback;
+    use std::thread;
+
+    fn attitude() -> MavMessage {
+        MavMessage::ATTITUDE(common::ATTITUDE_DATA {
+            time_boot_ms: 0,
+            roll: 0.0,
+            pitch: 0.0,
+            yaw: 0.0,
+            rollspeed: 0.0,
+            pitchspeed: 0.0,
+            yawspeed: 0.0,
+        })
+    }
+
+    /// A vehicle sending `ATTITUDE` at the requested interval, until the client hangs up.
+    ///
+    /// With `legacy` set it only understands `REQUEST_DATA_STREAM`.
+    fn serve(conn: &MavConnection, legacy: bool) {
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut interval: Option<Duration> = None;
+        let mut next = Instant::now();
+        loop {
+            let msg = match conn.recv_frame_timeout(Duration::from_millis(1)) {
+                Ok((_, msg)) => Some(msg),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => None,
+                Err(_) => return,
+            };
+            let ack = |command: u16, result: MAV_RESULT| MavMessage::COMMAND_ACK(common::COMMAND_ACK_DATA {
+                command: command,
+                result: result as u8,
+            });
+            match msg.map(|m| (Command::from_message(&m), m)) {
+                Some((Some(Command::SetMessageInterval { .. }), _)) if legacy => {
+                    conn.send_frame(header, &ack(511, MAV_RESULT::MAV_RESULT_UNSUPPORTED)).ok();
+                }
+                Some((Some(Command::SetMessageInterval { message_id, interval: i }), _)) => {
+                    let result = if message_id == 30.0 {
+                        interval = if i > 0.0 { Some(Duration::new(0, i as u32 * 1000)) } else { None };
+                        MAV_RESULT::MAV_RESULT_ACCEPTED
+                    } else {
+                        MAV_RESULT::MAV_RESULT_DENIED
+                    };
+                    conn.send_frame(header, &ack(511, result)).ok();
+                }
+                Some((Some(Command::GetMessageInterval { .. }), _)) => {
+                    conn.send_frame(header, &MavMessage::MESSAGE_INTERVAL(common::MESSAGE_INTERVAL_DATA {
+                        interval_us: interval.map_or(-1, |i| i.subsec_nanos() as i32 / 1000),
+                        message_id: 30,
+                    })).ok();
+                }
+                Some((_, MavMessage::REQUEST_DATA_STREAM(ref r))) if r.req_stream_id == 10 => {
+                    interval = Some(Duration::new(0, 1_000_000_000 / r.req_message_rate as u32));
+                }
+                _ => (),
+            }
+            if let Some(interval) = interval {
+                if Instant::now() >= next {
+                    conn.send_frame(header, &attitude()).ok();
+                    next += interval;
+                }
+            } else {
+                next = Instant::now();
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_message_interval() {
+        let (gcs, vehicle) = loopback();
+        let vehicle = thread::spawn(move || serve(&vehicle, false));
+        {
+            let client = RateClient::new(Arc::new(gcs), 1, 1);
+            assert_eq!(client.get_message_interval(30).unwrap(), Ok(-1));
+            assert_eq!(client.set_message_rate(30, 50.0).unwrap(), RateRequest::Interval(MAV_RESULT::MAV_RESULT_ACCEPTED));
+            assert_eq!(client.get_message_interval(30).unwrap(), Ok(20000));
+            let rate = client.measure_rate(30, Duration::from_millis(300)).unwrap();
+            assert!(rate > 40.0 && rate < 60.0, "rate {}", rate);
+
+            assert_eq!(client.set_message_interval(33, 100000).unwrap(), MAV_RESULT::MAV_RESULT_DENIED);
+        }
+        vehicle.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_stream_fallback() {
+        let (gcs, vehicle) = loopback();
+        let vehicle = thread::spawn(move || serve(&vehicle, true));
+        {
+            let client = RateClient::new(Arc::new(gcs), 1, 1);
+            assert_eq!(client.set_message_rate(30, 25.0).unwrap(), RateRequest::Stream(MAV_DATA_STREAM::MAV_DATA_STREAM_EXTRA1));
+            let rate = client.measure_rate(30, Duration::from_millis(300)).unwrap();
+            assert!(rate > 20.0 && rate < 30.0, "rate {}", rate);
+
+            // Slower than the stream can go is rounded up to 1 Hz; a rate of 0 would fail the vehicle.
+            assert_eq!(client.set_message_rate(30, 0.2).unwrap(), RateRequest::Stream(MAV_DATA_STREAM::MAV_DATA_STREAM_EXTRA1));
+
+            // Disabling one message must not stop the whole stream.
+            assert_eq!(client.set_message_rate(30, 0.0).unwrap(), RateRequest::Interval(MAV_RESULT::MAV_RESULT_UNSUPPORTED));
+        }
+        vehicle.join().unwrap();
+    }
+}
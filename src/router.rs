@@ -0,0 +1,166 @@
+use connection::MavConnection;
+use RawFrame;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::io;
+
+/// Forwards MAVLink traffic between several connections.
+///
+/// The router learns which system and component ids are reachable through each link from the
+/// headers of frames received on it. Messages addressed to a particular system or component are
+/// forwarded only to the links it has been seen on, everything else is broadcast, and a frame is
+/// never sent back out of the link it arrived on. Forwarded frames keep their original header.
+///
+/// To take part in the routed network from the same process, add one end of a `loopback()`
+/// pair as a link and use the other end.
+pub struct Router {
+    links: Vec<Box<MavConnection + Sync + Send>>,
+    seen: Mutex<Vec<HashSet<(u8, u8)>>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            links: Vec::new(),
+            seen: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add a link to the router, returning its index.
+    pub fn add_link(&mut self, link: Box<MavConnection + Sync + Send>) -> usize {
+        self.links.push(link);
+        self.seen.lock().unwrap().push(HashSet::new());
+        self.links.len() - 1
+    }
+
+    /// The links on which frames from the given system and component have been received.
+    pub fn links_for(&self, system_id: u8, component_id: u8) -> Vec<usize> {
+        self.seen.lock().unwrap().iter()
+            .enumerate()
+            .filter(|&(_, ids)| ids.contains(&(system_id, component_id)))
+            .map(|(link, _)| link)
+            .collect()
+    }
+
+    /// Record a frame received on link `from` and forward it to the links it is destined for.
+    ///
+    /// `run` calls this for every received frame; it is public for callers that drive the links
+    /// themselves. Frames of messages that cannot be decoded are broadcast. Errors sending to
+    /// individual links are ignored, since a broken link is reported by its own receive loop.
+    pub fn forward(&self, from: usize, frame: &RawFrame) {
+        let targets = {
+            let mut seen = self.seen.lock().unwrap();
+            seen[from].insert((frame.header.system_id, frame.header.component_id));
+
+            let msg = frame.decode();
+            let target_system = msg.as_ref().and_then(|m| m.target_system()).unwrap_or(0);
+            let target_component = msg.as_ref().and_then(|m| m.target_component()).unwrap_or(0);
+
+            seen.iter()
+                .enumerate()
+                .filter(|&(link, ids)| {
+                    link != from && (target_system == 0 || ids.iter().any(|&(sys, comp)| {
+                        sys == target_system && (target_component == 0 || comp == target_component)
+                    }))
+                })
+                .map(|(link, _)| link)
+                .collect::<Vec<_>>()
+        };
+
+        for link in targets {
+            self.links[link].send_raw(frame).ok();
+        }
+    }
+
+    /// Route frames between all links until every one of them has failed.
+    ///
+    /// Each link is served by its own thread. Returns the error from the last link to fail.
+    pub fn run(self) -> io::Result<()> {
+        let router = Arc::new(self);
+        let threads: Vec<_> = (0..router.links.len()).map(|link| {
+            let router = router.clone();
+            thread::spawn(move || -> io::Error {
+                loop {
+                    match router.links[link].recv_raw() {
+                        Ok(frame) => router.forward(link, &frame),
+                        Err(e) => return e,
+                    }
+                }
+            })
+        }).collect();
+
+        let mut result = Ok(());
+        for thread in threads {
+            let err = try!(thread.join().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "router thread panicked")
+            }));
+            result = Err(err);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test_router {
+    use super::*;
+    use common;
+    use common::MavMessage;
+    use connection::MavConnection;
+    use loopback::loopback;
+    use {Header, RawFrame};
+    use std::thread;
+
+    fn header(system_id: u8, component_id: u8) -> Header {
+        Header { sequence: 7, system_id: system_id, component_id: component_id }
+    }
+
+    fn param_request(target_system: u8, target_component: u8) -> MavMessage {
+        common::MavMessage::PARAM_REQUEST_LIST(common::PARAM_REQUEST_LIST_DATA {
+            target_system: target_system,
+            target_component: target_component,
+        })
+    }
+
+    #[test]
+    pub fn test_routing() {
+        let mut router = Router::new();
+        let (gcs, link) = loopback();
+        router.add_link(Box::new(link));
+        let (vehicle1, link) = loopback();
+        router.add_link(Box::new(link));
+        let (vehicle2, link) = loopback();
+        router.add_link(Box::new(link));
+        let router = thread::spawn(move || router.run());
+
+        // Broadcasts reach everyone but the sender, with the original header intact.
+        vehicle1.send_frame(header(1, 1), &::heartbeat_message()).unwrap();
+        assert_eq!(gcs.recv_frame().unwrap().0, header(1, 1));
+        assert_eq!(vehicle2.recv_frame().unwrap().0, header(1, 1));
+        vehicle2.send_frame(header(2, 1), &::heartbeat_message()).unwrap();
+        assert_eq!(gcs.recv_frame().unwrap().0, header(2, 1));
+        assert_eq!(vehicle1.recv_frame().unwrap().0, header(2, 1));
+
+        // Targeted messages only reach the link the target was seen on.
+        gcs.send_frame(header(255, 0), &param_request(2, 1)).unwrap();
+        gcs.send_frame(header(255, 0), &param_request(1, 0)).unwrap();
+        match vehicle1.recv().unwrap() {
+            common::MavMessage::PARAM_REQUEST_LIST(p) => assert_eq!(p.target_system, 1),
+            m => panic!("unexpected message {:?}", m),
+        }
+        match vehicle2.recv().unwrap() {
+            common::MavMessage::PARAM_REQUEST_LIST(p) => assert_eq!(p.target_system, 2),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // Frames of unknown messages are passed through untouched.
+        let mut unknown = RawFrame::new(header(1, 1), &::heartbeat_message());
+        unknown.msgid = 200;
+        vehicle1.send_raw(&unknown).unwrap();
+        assert_eq!(gcs.recv_raw().unwrap(), unknown);
+
+        drop((gcs, vehicle1, vehicle2));
+        assert!(router.join().unwrap().is_err());
+    }
+}
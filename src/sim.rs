@@ -0,0 +1,590 @@
+use common::{self, MavMessage, MAV_AUTOPILOT, MAV_CMD, MAV_FRAME, MAV_LANDED_STATE, MAV_MISSION_RESULT,
+             MAV_MODE_FLAG, MAV_RESULT, MAV_STATE, MAV_TYPE};
+use common::Command;
+use command::FORCE_DISARM;
+use connection::MavConnection;
+use fence::AllowedArea;
+use geo::METERS_PER_DEGREE;
+use heartbeat::Heartbeat;
+use mission::{is_global_frame, MissionItem};
+use mission_server::MissionServer;
+use mode::{COPTER_AUTO, COPTER_GUIDED, COPTER_LAND, COPTER_LOITER, COPTER_RTL, COPTER_STABILIZE};
+use param::ParamValue;
+use param_server::{MemoryStorage, ParamServer};
+use Header;
+
+use std::f64::consts::PI;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// A simple simulated multicopter that speaks MAVLink like ArduCopter.
+///
+/// It sends `HEARTBEAT` and telemetry, serves its parameters and mission, and answers
+/// commands to arm, take off, land, return to launch, change mode, start the mission and
+/// enable the fence, and reports the allowed area it is given without enforcing it. It
+/// flies straight lines at constant speed, which is enough to test ground software end to end.
+/// Speeds are read from the `SIM_SPEED`, `SIM_CLIMB_RATE`, `SIM_RTL_ALT` and `SIM_WP_RADIUS`
+/// parameters.
+///
+/// Use `run` to serve a connection, or feed frames to `handle` and call `update` regularly,
+/// sending the messages they return.
+pub struct SimVehicle {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub params: ParamServer<MemoryStorage>,
+    pub mission: MissionServer,
+    pub heartbeat_interval: Duration,
+    pub telemetry_interval: Duration,
+    /// How long `run` waits for a frame before stepping the simulation.
+    pub step: Duration,
+    /// Latitude and longitude in degrees and altitude above mean sea level in meters.
+    home: (f64, f64, f32),
+    lat: f64,
+    lon: f64,
+    /// Altitude above home in meters.
+    alt: f32,
+    heading: f32,
+    velocity: (f32, f32, f32),
+    armed: bool,
+    custom_mode: u32,
+    landing: bool,
+    /// Target of guided flight: latitude, longitude and altitude above home.
+    guided: Option<(f64, f64, f32)>,
+    allowed_area: Option<AllowedArea>,
+    fence_enabled: bool,
+    boot: Instant,
+    last_step: Instant,
+    last_heartbeat: Option<Instant>,
+    last_telemetry: Option<Instant>,
+    flight_time: Duration,
+    sequence: u8,
+}
+
+impl SimVehicle {
+    /// A disarmed vehicle on the ground at the given position, in stabilize mode.
+    pub fn new(system_id: u8, component_id: u8, lat: f64, lon: f64, alt: f32) -> SimVehicle {
+        let mut params = ParamServer::new(system_id, component_id, MemoryStorage::new()).unwrap();
+        params.add("SYSID_THISMAV", ParamValue::Int16(system_id as i16));
+        params.add("SIM_SPEED", ParamValue::Real32(5.0));
+        params.add("SIM_CLIMB_RATE", ParamValue::Real32(2.5));
+        params.add("SIM_RTL_ALT", ParamValue::Real32(15.0));
+        params.add("SIM_WP_RADIUS", ParamValue::Real32(2.0));
+
+        let now = Instant::now();
+        SimVehicle {
+            system_id: system_id,
+            component_id: component_id,
+            params: params,
+            mission: MissionServer::new(system_id, component_id),
+            heartbeat_interval: Duration::from_secs(1),
+            telemetry_interval: Duration::from_millis(200),
+            step: Duration::from_millis(10),
+            home: (lat, lon, alt),
+            lat: lat,
+            lon: lon,
+            alt: 0.0,
+            heading: 0.0,
+            velocity: (0.0, 0.0, 0.0),
+            armed: false,
+            custom_mode: COPTER_STABILIZE,
+            landing: false,
+            guided: None,
+            allowed_area: None,
+            fence_enabled: false,
+            boot: now,
+            last_step: now,
+            last_heartbeat: None,
+            last_telemetry: None,
+            flight_time: Duration::from_secs(0),
+            sequence: 0,
+        }
+    }
+
+    /// Latitude and longitude in degrees and altitude above home in meters.
+    pub fn position(&self) -> (f64, f64, f32) {
+        (self.lat, self.lon, self.alt)
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    /// The ArduCopter mode number.
+    pub fn custom_mode(&self) -> u32 {
+        self.custom_mode
+    }
+
+    /// The area set with `SAFETY_SET_ALLOWED_AREA`. The simulator does not enforce it.
+    pub fn allowed_area(&self) -> Option<AllowedArea> {
+        self.allowed_area
+    }
+
+    pub fn fence_enabled(&self) -> bool {
+        self.fence_enabled
+    }
+
+    fn param(&self, name: &str) -> f32 {
+        self.params.get(name).map_or(0.0, |v| v.as_f64() as f32)
+    }
+
+    fn on_ground(&self) -> bool {
+        self.alt <= 0.0
+    }
+
+    /// Change mode, announcing it with the next heartbeat straight away.
+    fn set_mode(&mut self, custom_mode: u32) -> MAV_RESULT {
+        match custom_mode {
+            COPTER_STABILIZE | COPTER_AUTO | COPTER_GUIDED |
+            COPTER_LOITER | COPTER_RTL | COPTER_LAND => (),
+            _ => return MAV_RESULT::MAV_RESULT_UNSUPPORTED,
+        }
+        if custom_mode == COPTER_AUTO && self.mission.mission().is_empty() {
+            return MAV_RESULT::MAV_RESULT_FAILED;
+        }
+        self.custom_mode = custom_mode;
+        self.landing = custom_mode == COPTER_LAND;
+        self.guided = None;
+        self.last_heartbeat = None;
+        MAV_RESULT::MAV_RESULT_ACCEPTED
+    }
+
+    fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+        self.landing = false;
+        self.last_heartbeat = None;
+    }
+
+    fn command(&mut self, cmd: &common::COMMAND_LONG_DATA) -> MAV_RESULT {
+        let command = match Command::from_command_long(cmd) {
+            Some(command) => command,
+            None => return MAV_RESULT::MAV_RESULT_UNSUPPORTED,
+        };
+        match command {
+            Command::ComponentArmDisarm { arm } => {
+                if arm == 1.0 {
+                    if !self.armed && !self.on_ground() {
+                        return MAV_RESULT::MAV_RESULT_FAILED;
+                    }
+                    self.set_armed(true);
+                } else {
+                    if !self.on_ground() && cmd.param2 != FORCE_DISARM {
+                        return MAV_RESULT::MAV_RESULT_FAILED;
+                    }
+                    // A forced disarm in flight drops the vehicle.
+                    self.alt = 0.0;
+                    self.set_armed(false);
+                }
+                MAV_RESULT::MAV_RESULT_ACCEPTED
+            }
+            Command::DoSetMode { mode, custom_mode, .. } => {
+                if mode as u8 & MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8 == 0 {
+                    return MAV_RESULT::MAV_RESULT_UNSUPPORTED;
+                }
+                self.set_mode(custom_mode as u32)
+            }
+            Command::NavTakeoff { alt, .. } => {
+                if self.custom_mode != COPTER_GUIDED || !self.armed || alt <= 0.0 {
+                    return MAV_RESULT::MAV_RESULT_FAILED;
+                }
+                self.guided = Some((self.lat, self.lon, alt));
+                MAV_RESULT::MAV_RESULT_ACCEPTED
+            }
+            Command::NavLand { .. } => self.set_mode(COPTER_LAND),
+            Command::NavReturnToLaunch => self.set_mode(COPTER_RTL),
+            Command::MissionStart { first_item, .. } => {
+                if !self.armed {
+                    return MAV_RESULT::MAV_RESULT_FAILED;
+                }
+                let result = self.set_mode(COPTER_AUTO);
+                if result == MAV_RESULT::MAV_RESULT_ACCEPTED {
+                    self.mission.set_current(first_item as u16);
+                }
+                result
+            }
+            Command::DoFenceEnable { enable } => {
+                self.fence_enabled = enable == 1.0;
+                MAV_RESULT::MAV_RESULT_ACCEPTED
+            }
+            _ => MAV_RESULT::MAV_RESULT_UNSUPPORTED,
+        }
+    }
+
+    fn guided_item(&mut self, header: &Header, item: MissionItem) -> MavMessage {
+        let result = if self.custom_mode == COPTER_GUIDED && self.armed && !self.on_ground() {
+            let alt = self.relative_alt(&item);
+            self.guided = Some((item.x, item.y, alt));
+            MAV_MISSION_RESULT::MAV_MISSION_ACCEPTED
+        } else {
+            MAV_MISSION_RESULT::MAV_MISSION_ERROR
+        };
+        MavMessage::MISSION_ACK(common::MISSION_ACK_DATA {
+            target_system: header.system_id,
+            target_component: header.component_id,
+            mavtype: result as u8,
+        })
+    }
+
+    /// Handle a received message, returning the replies to send.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Vec<MavMessage> {
+        let mut replies = Vec::new();
+        match *msg {
+            MavMessage::COMMAND_LONG(ref cmd) if cmd.target_system == self.system_id &&
+                (cmd.target_component == 0 || cmd.target_component == self.component_id) => {
+                let result = self.command(cmd);
+                replies.push(MavMessage::COMMAND_ACK(common::COMMAND_ACK_DATA {
+                    command: cmd.command,
+                    result: result as u8,
+                }));
+            }
+            // ArduPilot's guided mode waypoint, flown at once rather than stored.
+            MavMessage::MISSION_ITEM_INT(ref item) if item.current == 2 && item.target_system == self.system_id => {
+                replies.push(self.guided_item(header, MissionItem::from_item_int(item)));
+            }
+            MavMessage::MISSION_ITEM(ref item) if item.current == 2 && item.target_system == self.system_id => {
+                replies.push(self.guided_item(header, MissionItem::from_item(item)));
+            }
+            MavMessage::SAFETY_SET_ALLOWED_AREA(ref area) if area.target_system == self.system_id => {
+                let area = AllowedArea {
+                    frame: area.frame,
+                    p1: (area.p1x, area.p1y, area.p1z),
+                    p2: (area.p2x, area.p2y, area.p2z),
+                };
+                self.allowed_area = Some(area);
+                replies.push(area.message());
+            }
+            MavMessage::SET_MODE(ref m) if m.target_system == self.system_id => {
+                if m.base_mode & MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8 != 0 {
+                    self.set_mode(m.custom_mode);
+                }
+            }
+            _ => {
+                if let Ok(Some(reply)) = self.params.handle(header, msg) {
+                    replies.push(reply);
+                }
+                if let Some(reply) = self.mission.handle(header, msg) {
+                    replies.push(reply);
+                }
+            }
+        }
+        replies
+    }
+
+    /// Fly towards the target at the configured speeds, returning the remaining horizontal and
+    /// vertical distance in meters.
+    fn fly_to(&mut self, lat: f64, lon: f64, alt: f32, dt: f32) -> (f32, f32) {
+        let scale = (self.lat * PI / 180.0).cos();
+        let north = ((lat - self.lat) * METERS_PER_DEGREE) as f32;
+        let east = ((lon - self.lon) * METERS_PER_DEGREE * scale) as f32;
+        let distance = (north * north + east * east).sqrt();
+
+        let step = distance.min(self.param("SIM_SPEED") * dt);
+        if distance > 0.0 {
+            let (vn, ve) = (north / distance * step, east / distance * step);
+            self.lat += vn as f64 / METERS_PER_DEGREE;
+            self.lon += ve as f64 / (METERS_PER_DEGREE * scale);
+            self.heading = ve.atan2(vn);
+            self.velocity.0 = vn / dt;
+            self.velocity.1 = ve / dt;
+        }
+
+        let climb = (alt - self.alt).max(-self.param("SIM_CLIMB_RATE") * dt).min(self.param("SIM_CLIMB_RATE") * dt);
+        self.alt += climb;
+        self.velocity.2 = -climb / dt;
+        (distance - step, (alt - self.alt).abs())
+    }
+
+    /// Descend, disarming on touchdown.
+    fn descend(&mut self, dt: f32) -> bool {
+        let (lat, lon) = (self.lat, self.lon);
+        self.fly_to(lat, lon, 0.0, dt);
+        if self.on_ground() {
+            self.alt = 0.0;
+            self.set_armed(false);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The altitude of an item above home.
+    fn relative_alt(&self, item: &MissionItem) -> f32 {
+        if item.frame == MAV_FRAME::MAV_FRAME_GLOBAL as u8 || item.frame == MAV_FRAME::MAV_FRAME_GLOBAL_INT as u8 {
+            item.z - self.home.2
+        } else {
+            item.z
+        }
+    }
+
+    /// Fly the current mission item, returning whether it was reached.
+    fn fly_mission(&mut self, dt: f32) -> bool {
+        let item = match self.mission.current_item() {
+            Some(item) => item.clone(),
+            None => return true,
+        };
+        let alt = self.relative_alt(&item);
+        let (lat, lon) = if is_global_frame(item.frame) && (item.x != 0.0 || item.y != 0.0) {
+            (item.x, item.y)
+        } else {
+            (self.lat, self.lon)
+        };
+
+        match MAV_CMD::from_u32(item.command as u32) {
+            Some(MAV_CMD::MAV_CMD_NAV_TAKEOFF) => {
+                let (lat, lon) = (self.lat, self.lon);
+                self.fly_to(lat, lon, alt, dt).1 < 0.1
+            }
+            Some(MAV_CMD::MAV_CMD_NAV_WAYPOINT) => {
+                let (horizontal, vertical) = self.fly_to(lat, lon, alt, dt);
+                horizontal <= self.param("SIM_WP_RADIUS") && vertical < 1.0
+            }
+            Some(MAV_CMD::MAV_CMD_NAV_LAND) => {
+                if self.fly_to(lat, lon, self.alt, dt).0 > 0.0 {
+                    false
+                } else {
+                    self.descend(dt)
+                }
+            }
+            Some(MAV_CMD::MAV_CMD_NAV_RETURN_TO_LAUNCH) => {
+                self.set_mode(COPTER_RTL);
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Advance the simulation by `dt` seconds, returning mission progress messages.
+    fn advance(&mut self, dt: f32) -> Vec<MavMessage> {
+        self.velocity = (0.0, 0.0, 0.0);
+        if !self.armed || dt <= 0.0 {
+            return Vec::new();
+        }
+        if !self.on_ground() {
+            self.flight_time += Duration::new(dt as u64, (dt.fract() * 1e9) as u32);
+        }
+
+        let mut messages = Vec::new();
+        if self.landing {
+            self.descend(dt);
+            return messages;
+        }
+        match self.custom_mode {
+            COPTER_AUTO => {
+                if self.fly_mission(dt) && self.custom_mode == COPTER_AUTO {
+                    let current = self.mission.current();
+                    messages.extend(self.mission.reached());
+                    // The mission is over once the current item stops advancing.
+                    if self.mission.current() == current {
+                        self.custom_mode = COPTER_LOITER;
+                        self.last_heartbeat = None;
+                    }
+                }
+            }
+            COPTER_GUIDED => {
+                if let Some((lat, lon, alt)) = self.guided {
+                    self.fly_to(lat, lon, alt, dt);
+                }
+            }
+            COPTER_RTL => {
+                let alt = self.alt.max(self.param("SIM_RTL_ALT"));
+                let (lat, lon) = (self.home.0, self.home.1);
+                if self.fly_to(lat, lon, alt, dt).0 <= 0.0 {
+                    self.landing = true;
+                }
+            }
+            _ => (),
+        }
+        messages
+    }
+
+    fn heartbeat(&self) -> MavMessage {
+        let mut base_mode = MAV_MODE_FLAG::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as u8 |
+            MAV_MODE_FLAG::MAV_MODE_FLAG_STABILIZE_ENABLED as u8;
+        if self.armed {
+            base_mode |= MAV_MODE_FLAG::MAV_MODE_FLAG_SAFETY_ARMED as u8;
+        }
+        Heartbeat {
+            mavtype: MAV_TYPE::MAV_TYPE_QUADROTOR,
+            autopilot: MAV_AUTOPILOT::MAV_AUTOPILOT_ARDUPILOTMEGA,
+            base_mode: base_mode,
+            custom_mode: self.custom_mode,
+            system_status: if self.armed { MAV_STATE::MAV_STATE_ACTIVE } else { MAV_STATE::MAV_STATE_STANDBY },
+        }.message()
+    }
+
+    fn telemetry(&self) -> Vec<MavMessage> {
+        let elapsed = self.boot.elapsed();
+        let time_boot_ms = (elapsed.as_secs() * 1000) as u32 + elapsed.subsec_nanos() / 1_000_000;
+        // A 20 minute battery.
+        let remaining = (100.0 - self.flight_time.as_secs() as f32 / 12.0).max(0.0);
+        let landed_state = if self.on_ground() {
+            MAV_LANDED_STATE::MAV_LANDED_STATE_ON_GROUND
+        } else {
+            MAV_LANDED_STATE::MAV_LANDED_STATE_IN_AIR
+        };
+
+        vec![
+            MavMessage::SYS_STATUS(common::SYS_STATUS_DATA {
+                onboard_control_sensors_present: 0,
+                onboard_control_sensors_enabled: 0,
+                onboard_control_sensors_health: 0,
+                load: 0,
+                voltage_battery: (10200.0 + remaining * 24.0) as u16,
+                current_battery: if self.armed { 1000 } else { 0 },
+                drop_rate_comm: 0,
+                errors_comm: 0,
+                errors_count1: 0,
+                errors_count2: 0,
+                errors_count3: 0,
+                errors_count4: 0,
+                battery_remaining: remaining as i8,
+            }),
+            MavMessage::ATTITUDE(common::ATTITUDE_DATA {
+                time_boot_ms: time_boot_ms,
+                roll: 0.0,
+                pitch: 0.0,
+                yaw: self.heading,
+                rollspeed: 0.0,
+                pitchspeed: 0.0,
+                yawspeed: 0.0,
+            }),
+            MavMessage::GLOBAL_POSITION_INT(common::GLOBAL_POSITION_INT_DATA {
+                time_boot_ms: time_boot_ms,
+                lat: (self.lat * 1e7).round() as i32,
+                lon: (self.lon * 1e7).round() as i32,
+                alt: ((self.home.2 + self.alt) * 1000.0) as i32,
+                relative_alt: (self.alt * 1000.0) as i32,
+                vx: (self.velocity.0 * 100.0) as i16,
+                vy: (self.velocity.1 * 100.0) as i16,
+                vz: (self.velocity.2 * 100.0) as i16,
+                hdg: ((self.heading.to_degrees() + 360.0) % 360.0 * 100.0) as u16,
+            }),
+            MavMessage::EXTENDED_SYS_STATE(common::EXTENDED_SYS_STATE_DATA {
+                vtol_state: 0,
+                landed_state: landed_state as u8,
+            }),
+            self.mission.current_message(),
+        ].into_iter().chain(self.allowed_area.map(|area| area.message())).collect()
+    }
+
+    /// Step the simulation to the present, returning the messages that are due.
+    pub fn update(&mut self) -> Vec<MavMessage> {
+        let now = Instant::now();
+        let elapsed = now - self.last_step;
+        self.last_step = now;
+        let dt = (elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9).min(1.0);
+
+        let mut messages = self.advance(dt);
+        messages.extend(self.params.poll());
+        messages.extend(self.mission.poll());
+        if self.last_heartbeat.map_or(true, |t| now - t >= self.heartbeat_interval) {
+            self.last_heartbeat = Some(now);
+            messages.push(self.heartbeat());
+        }
+        if self.last_telemetry.map_or(true, |t| now - t >= self.telemetry_interval) {
+            self.last_telemetry = Some(now);
+            messages.extend(self.telemetry());
+        }
+        messages
+    }
+
+    fn send(&mut self, conn: &MavConnection, msg: &MavMessage) -> io::Result<()> {
+        let header = Header {
+            sequence: self.sequence,
+            system_id: self.system_id,
+            component_id: self.component_id,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        conn.send_frame(header, msg)
+    }
+
+    /// Serve `conn` until it fails.
+    pub fn run(&mut self, conn: &MavConnection) -> io::Result<()> {
+        loop {
+            match conn.recv_frame_timeout(self.step) {
+                Ok((header, msg)) => {
+                    for reply in self.handle(&header, &msg) {
+                        try!(self.send(conn, &reply));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+            for msg in self.update() {
+                try!(self.send(conn, &msg));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sim {
+    use super::*;
+    use command::CommandClient;
+    use loopback::loopback;
+    use mission::{MissionClient, MissionItem};
+    use param::ParamClient;
+    use vehicle::Vehicle;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn test_fly_mission() {
+        let (gcs, sim) = loopback();
+        let gcs = Arc::new(gcs);
+        let sim = thread::spawn(move || {
+            let mut vehicle = SimVehicle::new(1, 1, 47.0, 8.5, 500.0);
+            vehicle.telemetry_interval = Duration::from_millis(20);
+            vehicle.run(&sim).unwrap_err();
+            vehicle
+        });
+
+        let params = ParamClient::new(&*gcs, 1, 1);
+        params.set("SIM_SPEED", ParamValue::Real32(100.0)).unwrap();
+        params.set("SIM_CLIMB_RATE", ParamValue::Real32(50.0)).unwrap();
+
+        let frame = MAV_FRAME::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT as u8;
+        let mission: Vec<MissionItem> = vec![
+            Command::NavTakeoff { minimum_pitch: 0.0, yaw_angle: 0.0, lat: 0.0, lon: 0.0, alt: 20.0 },
+            Command::NavWaypoint {
+                hold_time: 0.0, acceptance_radius: 0.0, pass_through: 0.0, yaw_angle: 0.0,
+                lat: 47.001, lon: 8.5, alt: 30.0,
+            },
+            Command::NavLand { abort_alt: 0.0, yaw_angle: 0.0, lat: 0.0, lon: 0.0, alt: 0.0 },
+        ].iter().enumerate().map(|(seq, c)| c.to_mission_item(seq as u16, frame)).collect();
+        MissionClient::new(&*gcs, 1, 1).upload(&mission).unwrap();
+
+        let commands = CommandClient::new(gcs.clone());
+        assert_eq!(commands.send(1, 1, &Command::NavTakeoff {
+            minimum_pitch: 0.0, yaw_angle: 0.0, lat: 0.0, lon: 0.0, alt: 10.0,
+        }).unwrap(), MAV_RESULT::MAV_RESULT_FAILED);
+        assert_eq!(commands.send(1, 1, &Command::ComponentArmDisarm { arm: 1.0 }).unwrap(),
+                   MAV_RESULT::MAV_RESULT_ACCEPTED);
+        assert_eq!(commands.send(1, 1, &Command::MissionStart { first_item: 0.0, last_item: 0.0 }).unwrap(),
+                   MAV_RESULT::MAV_RESULT_ACCEPTED);
+
+        // Follow the flight until the vehicle disarms after landing.
+        let mut vehicle = Vehicle::new(1, 1);
+        let mut reached = Vec::new();
+        let mut max_alt = 0.0f32;
+        let start = Instant::now();
+        while vehicle.armed() != Some(false) || reached.len() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            let (_, msg) = vehicle.recv(&*gcs).unwrap();
+            if let MavMessage::MISSION_ITEM_REACHED(ref r) = msg {
+                reached.push(r.seq);
+            }
+            max_alt = max_alt.max(vehicle.relative_alt().unwrap_or(0.0));
+        }
+        assert_eq!(reached, vec![0, 1, 2]);
+        assert!(max_alt > 25.0);
+
+        drop(commands);
+        drop(gcs);
+        let sim = sim.join().unwrap();
+        let (lat, lon, alt) = sim.position();
+        // Landed where the waypoint was reached, within its acceptance radius.
+        assert!((lat - 47.001).abs() < 3e-5 && (lon - 8.5).abs() < 1e-6);
+        assert_eq!(alt, 0.0);
+        assert!(!sim.armed());
+    }
+}
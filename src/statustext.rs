@@ -0,0 +1,217 @@
+use common::{self, MavMessage, MAV_SEVERITY};
+use Header;
+use log::Level;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Length of the NUL-padded `text` field of `STATUSTEXT`.
+pub const STATUSTEXT_LEN: usize = 50;
+
+/// The log level for a `MAV_SEVERITY`. Unknown severities are logged as info.
+pub fn severity_level(severity: u8) -> Level {
+    match MAV_SEVERITY::from_u32(severity as u32) {
+        Some(MAV_SEVERITY::MAV_SEVERITY_EMERGENCY) |
+        Some(MAV_SEVERITY::MAV_SEVERITY_ALERT) |
+        Some(MAV_SEVERITY::MAV_SEVERITY_CRITICAL) |
+        Some(MAV_SEVERITY::MAV_SEVERITY_ERROR) => Level::Error,
+        Some(MAV_SEVERITY::MAV_SEVERITY_WARNING) => Level::Warn,
+        Some(MAV_SEVERITY::MAV_SEVERITY_DEBUG) => Level::Debug,
+        _ => Level::Info,
+    }
+}
+
+/// `STATUSTEXT` messages carrying `text`, split into as many as needed.
+pub fn statustext(severity: MAV_SEVERITY, text: &str) -> Vec<MavMessage> {
+    let bytes = text.as_bytes();
+    let mut messages = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + STATUSTEXT_LEN).min(bytes.len());
+        let mut chunk = bytes[start..end].to_vec();
+        chunk.resize(STATUSTEXT_LEN, 0);
+        messages.push(MavMessage::STATUSTEXT(common::STATUSTEXT_DATA {
+            severity: severity as u8,
+            text: chunk,
+        }));
+        // A text that fills the last chunk exactly ends with an empty one, so the receiver
+        // knows it is complete.
+        if end - start < STATUSTEXT_LEN {
+            return messages;
+        }
+        start = end;
+    }
+}
+
+/// A complete status text from a system and component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusText {
+    pub system_id: u8,
+    pub component_id: u8,
+    /// `MAV_SEVERITY` value.
+    pub severity: u8,
+    pub text: String,
+}
+
+impl StatusText {
+    pub fn level(&self) -> Level {
+        severity_level(self.severity)
+    }
+
+    /// Log the text at its severity's level, with the sender's `system/component` as target.
+    pub fn log(&self) {
+        let target = format!("{}/{}", self.system_id, self.component_id);
+        log!(target: &target, self.level(), "{}", self.text);
+    }
+}
+
+struct Pending {
+    severity: u8,
+    text: Vec<u8>,
+    updated: Instant,
+}
+
+/// Reassembles `STATUSTEXT` messages into complete texts.
+///
+/// The text field is NUL-padded and has no terminator when full. The message in this dialect
+/// has no chunk ids, so, as autopilots splitting long texts do, a chunk that fills the whole
+/// field is taken to continue in the next `STATUSTEXT` of the same severity from the same
+/// sender. A text is complete with a chunk that has padding, or once no further chunk arrives
+/// within the timeout; call `check` regularly to find those.
+pub struct StatusTextAssembler {
+    /// How long to wait for the next chunk of a text.
+    pub timeout: Duration,
+    /// The most chunks to join into one text, in case a sender never pads a chunk.
+    pub max_chunks: usize,
+    pending: HashMap<(u8, u8), Pending>,
+}
+
+impl Default for StatusTextAssembler {
+    fn default() -> StatusTextAssembler {
+        StatusTextAssembler::new()
+    }
+}
+
+impl StatusTextAssembler {
+    pub fn new() -> StatusTextAssembler {
+        StatusTextAssembler {
+            timeout: Duration::from_millis(500),
+            max_chunks: 8,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn finish(system_id: u8, component_id: u8, pending: Pending) -> StatusText {
+        StatusText {
+            system_id: system_id,
+            component_id: component_id,
+            severity: pending.severity,
+            text: String::from_utf8_lossy(&pending.text).into_owned(),
+        }
+    }
+
+    /// Handle a received message, returning the texts it completes.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> Vec<StatusText> {
+        let data = match *msg {
+            MavMessage::STATUSTEXT(ref data) => data,
+            _ => return Vec::new(),
+        };
+        let key = (header.system_id, header.component_id);
+        let mut complete = Vec::new();
+
+        let mut pending = match self.pending.remove(&key) {
+            Some(pending) => {
+                if pending.severity == data.severity {
+                    pending
+                } else {
+                    complete.push(StatusTextAssembler::finish(key.0, key.1, pending));
+                    Pending { severity: data.severity, text: Vec::new(), updated: Instant::now() }
+                }
+            }
+            None => Pending { severity: data.severity, text: Vec::new(), updated: Instant::now() },
+        };
+
+        let (chunk, padded) = match data.text.iter().position(|&c| c == 0) {
+            Some(len) => (&data.text[..len], true),
+            None => (&data.text[..], false),
+        };
+        pending.text.extend_from_slice(chunk);
+        pending.updated = Instant::now();
+
+        if padded || pending.text.len() >= self.max_chunks * STATUSTEXT_LEN {
+            complete.push(StatusTextAssembler::finish(key.0, key.1, pending));
+        } else {
+            self.pending.insert(key, pending);
+        }
+        complete
+    }
+
+    /// The texts whose next chunk did not arrive within the timeout.
+    pub fn check(&mut self) -> Vec<StatusText> {
+        let timeout = self.timeout;
+        let stale: Vec<(u8, u8)> = self.pending
+            .iter()
+            .filter(|&(_, pending)| pending.updated.elapsed() >= timeout)
+            .map(|(&key, _)| key)
+            .collect();
+        stale.into_iter()
+            .map(|key| {
+                let pending = self.pending.remove(&key).unwrap();
+                StatusTextAssembler::finish(key.0, key.1, pending)
+            })
+            .collect()
+    }
+
+    /// Like `handle`, logging each completed text.
+    pub fn log(&mut self, header: &Header, msg: &MavMessage) {
+        for text in self.handle(header, msg) {
+            text.log();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_statustext {
+    use super::*;
+    use std::thread;
+
+    fn header(system_id: u8) -> Header {
+        Header { sequence: 0, system_id: system_id, component_id: 1 }
+    }
+
+    #[test]
+    pub fn test_reassemble() {
+        let mut assembler = StatusTextAssembler::new();
+        let short = &statustext(MAV_SEVERITY::MAV_SEVERITY_CRITICAL, "PreArm: Compass not calibrated")[0];
+        let texts = assembler.handle(&header(1), short);
+        assert_eq!(texts, vec![StatusText {
+            system_id: 1,
+            component_id: 1,
+            severity: MAV_SEVERITY::MAV_SEVERITY_CRITICAL as u8,
+            text: "PreArm: Compass not calibrated".to_string(),
+        }]);
+        assert_eq!(texts[0].level(), Level::Error);
+
+        // Chunks from two senders interleave.
+        let long: String = (0..120).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let chunks = statustext(MAV_SEVERITY::MAV_SEVERITY_WARNING, &long);
+        assert_eq!(chunks.len(), 3);
+        assert!(assembler.handle(&header(1), &chunks[0]).is_empty());
+        assert!(assembler.handle(&header(2), &chunks[0]).is_empty());
+        assert!(assembler.handle(&header(1), &chunks[1]).is_empty());
+        let texts = assembler.handle(&header(1), &chunks[2]);
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[0].text, long);
+
+        // A different severity starts a new text, and a lone full chunk times out.
+        let texts = assembler.handle(&header(2), &statustext(MAV_SEVERITY::MAV_SEVERITY_INFO, &long[..50])[0]);
+        assert_eq!(texts.len(), 1);
+        assert_eq!((texts[0].system_id, &texts[0].text[..]), (2, &long[..50]));
+        assert!(assembler.check().is_empty());
+        assembler.timeout = Duration::from_millis(10);
+        thread::sleep(Duration::from_millis(20));
+        let texts = assembler.check();
+        assert_eq!(texts.len(), 1);
+        assert_eq!((texts[0].level(), &texts[0].text[..]), (Level::Info, &long[..50]));
+    }
+}
@@ -0,0 +1,206 @@
+use common::{self, MavMessage};
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of outstanding requests whose timestamps are remembered.
+const MAX_PENDING: usize = 16;
+
+/// Samples averaged with equal weight before switching to exponential smoothing.
+const WARMUP_SAMPLES: u64 = 8;
+
+/// Estimates the offset between our clock and a peer's with `TIMESYNC`, and answers its requests.
+///
+/// Our side of the exchange uses wall-clock time in nanoseconds since 1970, so the offset
+/// converts the peer's timestamps, usually time since boot, onto the wall clock. Call `request`
+/// periodically and send the message it returns, and pass every received message to `handle`,
+/// sending back any reply.
+///
+/// Samples with a round trip longer than `max_rtt` are dropped, since the offset is only known
+/// to within half the round trip. Samples further than `max_deviation` from the estimate are
+/// treated as outliers, unless `max_outliers` of them arrive in a row, which means the peer's
+/// clock has jumped and the estimate is restarted.
+pub struct TimeSync {
+    pub max_rtt: Duration,
+    pub max_deviation: Duration,
+    pub max_outliers: usize,
+    /// Weight of a new sample in the smoothed offset and round trip time.
+    pub alpha: f64,
+    pending: VecDeque<i64>,
+    offset_ns: f64,
+    rtt_ns: f64,
+    samples: u64,
+    outliers: usize,
+}
+
+fn now_ns() -> i64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since_epoch.as_secs() as i64 * 1_000_000_000 + since_epoch.subsec_nanos() as i64
+}
+
+impl TimeSync {
+    pub fn new() -> TimeSync {
+        TimeSync {
+            max_rtt: Duration::from_millis(50),
+            max_deviation: Duration::from_millis(20),
+            max_outliers: 5,
+            alpha: 0.05,
+            pending: VecDeque::new(),
+            offset_ns: 0.0,
+            rtt_ns: 0.0,
+            samples: 0,
+            outliers: 0,
+        }
+    }
+
+    /// A `TIMESYNC` request to send to the peer.
+    pub fn request(&mut self) -> MavMessage {
+        self.request_at(now_ns())
+    }
+
+    fn request_at(&mut self, now: i64) -> MavMessage {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(now);
+        MavMessage::TIMESYNC(common::TIMESYNC_DATA { tc1: 0, ts1: now })
+    }
+
+    /// Handle a received message, returning the reply to a `TIMESYNC` request from the peer.
+    ///
+    /// Replies to our own requests update the estimate; other messages are ignored.
+    pub fn handle(&mut self, msg: &MavMessage) -> Option<MavMessage> {
+        self.handle_at(msg, now_ns())
+    }
+
+    fn handle_at(&mut self, msg: &MavMessage, now: i64) -> Option<MavMessage> {
+        let sync = match *msg {
+            MavMessage::TIMESYNC(ref sync) => sync,
+            _ => return None,
+        };
+        if sync.tc1 == 0 {
+            return Some(MavMessage::TIMESYNC(common::TIMESYNC_DATA { tc1: now, ts1: sync.ts1 }));
+        }
+
+        // Only replies to requests still outstanding, so a duplicate cannot count twice.
+        match self.pending.iter().position(|&sent| sent == sync.ts1) {
+            Some(i) => { self.pending.remove(i); }
+            None => return None,
+        }
+        let rtt = now - sync.ts1;
+        if rtt < 0 || rtt as u64 > duration_ns(self.max_rtt) {
+            return None;
+        }
+        self.add_sample((sync.tc1 + rtt / 2 - now) as f64, rtt as f64);
+        None
+    }
+
+    fn add_sample(&mut self, offset: f64, rtt: f64) {
+        if self.samples > 0 && (offset - self.offset_ns).abs() > duration_ns(self.max_deviation) as f64 {
+            self.outliers += 1;
+            if self.outliers < self.max_outliers {
+                return;
+            }
+            self.samples = 0;
+        }
+        self.outliers = 0;
+        self.samples += 1;
+
+        let alpha = if self.samples <= WARMUP_SAMPLES { 1.0 / self.samples as f64 } else { self.alpha };
+        self.offset_ns += alpha * (offset - self.offset_ns);
+        self.rtt_ns += alpha * (rtt - self.rtt_ns);
+    }
+
+    /// The estimated offset in nanoseconds, such that peer time = our time + offset.
+    pub fn offset_ns(&self) -> Option<i64> {
+        if self.samples > 0 { Some(self.offset_ns.round() as i64) } else { None }
+    }
+
+    /// The smoothed round trip time.
+    pub fn rtt(&self) -> Option<Duration> {
+        if self.samples > 0 {
+            let ns = self.rtt_ns.max(0.0) as u64;
+            Some(Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Convert a peer timestamp in microseconds, like `time_usec`, to wall-clock time.
+    pub fn to_local_usec(&self, time_usec: u64) -> Option<SystemTime> {
+        self.offset_ns().and_then(|offset| {
+            let local = time_usec as i64 * 1000 - offset;
+            if local < 0 {
+                None
+            } else {
+                Some(UNIX_EPOCH + Duration::new((local / 1_000_000_000) as u64, (local % 1_000_000_000) as u32))
+            }
+        })
+    }
+
+    /// Convert a peer timestamp in milliseconds since boot, like `time_boot_ms`, to wall-clock
+    /// time. The peer's timesync clock has to be its boot time, as it is for common autopilots.
+    pub fn to_local_ms(&self, time_boot_ms: u32) -> Option<SystemTime> {
+        self.to_local_usec(time_boot_ms as u64 * 1000)
+    }
+}
+
+fn duration_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod test_timesync {
+    use super::*;
+
+    /// A peer whose clock runs `offset` nanoseconds ahead of ours, answering after `rtt`.
+    fn exchange(sync: &mut TimeSync, now: i64, offset: i64, rtt: i64) {
+        let request = sync.request_at(now);
+        let mut peer = TimeSync::new();
+        let reply = peer.handle_at(&request, now + rtt / 2 + offset).unwrap();
+        assert!(sync.handle_at(&reply, now + rtt).is_none());
+    }
+
+    #[test]
+    pub fn test_offset_estimate() {
+        let mut sync = TimeSync::new();
+        assert_eq!(sync.offset_ns(), None);
+
+        let offset = -1_500_000_000_000_000_000;
+        let mut now = 1_500_000_000_000_000_000;
+        for i in 0..50 {
+            // The peer timestamps its replies with some jitter.
+            exchange(&mut sync, now, offset + (i % 3 - 1) * 100_000, 2_000_000);
+            now += 100_000_000;
+        }
+        assert!((sync.offset_ns().unwrap() - offset).abs() < 100_000);
+        let rtt = sync.rtt().unwrap();
+        assert!(rtt > Duration::new(0, 1_900_000) && rtt < Duration::new(0, 2_100_000));
+
+        // Slow round trips and isolated outliers are ignored.
+        exchange(&mut sync, now, offset + 40_000_000, 200_000_000);
+        exchange(&mut sync, now, offset + 40_000_000, 2_000_000);
+        assert!((sync.offset_ns().unwrap() - offset).abs() < 100_000);
+
+        // A persistent jump of the peer's clock restarts the estimate.
+        for _ in 0..5 {
+            exchange(&mut sync, now, offset + 1_000_000_000, 2_000_000);
+        }
+        assert!((sync.offset_ns().unwrap() - offset - 1_000_000_000).abs() < 100_000);
+
+        // One second after boot on the peer is when our clock read 1.5e18 ns, minus the jump.
+        let time = sync.to_local_ms(1000).unwrap();
+        let expected = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let error = time.duration_since(expected).unwrap_or_else(|e| e.duration());
+        assert!(error < Duration::from_millis(1));
+    }
+
+    #[test]
+    pub fn test_unsolicited_replies() {
+        let mut sync = TimeSync::new();
+        let reply = MavMessage::TIMESYNC(common::TIMESYNC_DATA { tc1: 5, ts1: 1234 });
+        assert!(sync.handle(&reply).is_none());
+        assert_eq!(sync.offset_ns(), None);
+        assert!(sync.handle(&::heartbeat_message()).is_none());
+    }
+}
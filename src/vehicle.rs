@@ -0,0 +1,221 @@
+use common::{self, MavMessage, MAV_LANDED_STATE, MAV_MODE_FLAG};
+use connection::MavConnection;
+use Header;
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std::io;
+
+/// A received value together with the time it arrived.
+#[derive(Debug, Clone)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub received: Instant,
+}
+
+impl<T> Timestamped<T> {
+    fn now(value: T) -> Timestamped<T> {
+        Timestamped {
+            value: value,
+            received: Instant::now(),
+        }
+    }
+
+    /// How long ago the value was received.
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+}
+
+/// The latest known state of a vehicle, built from the telemetry it sends.
+///
+/// Each field holds the most recent message of its kind, or `None` if none has arrived yet.
+/// Pass received frames to `handle`, or let `recv` do it while reading from a connection.
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    pub system_id: u8,
+    /// The component whose telemetry is tracked, usually the autopilot.
+    pub component_id: u8,
+    pub heartbeat: Option<Timestamped<common::HEARTBEAT_DATA>>,
+    pub attitude: Option<Timestamped<common::ATTITUDE_DATA>>,
+    pub attitude_quaternion: Option<Timestamped<common::ATTITUDE_QUATERNION_DATA>>,
+    pub global_position: Option<Timestamped<common::GLOBAL_POSITION_INT_DATA>>,
+    pub local_position: Option<Timestamped<common::LOCAL_POSITION_NED_DATA>>,
+    pub gps: Option<Timestamped<common::GPS_RAW_INT_DATA>>,
+    pub sys_status: Option<Timestamped<common::SYS_STATUS_DATA>>,
+    /// `BATTERY_STATUS` by battery id.
+    pub batteries: BTreeMap<u8, Timestamped<common::BATTERY_STATUS_DATA>>,
+    pub vfr_hud: Option<Timestamped<common::VFR_HUD_DATA>>,
+    pub home_position: Option<Timestamped<common::HOME_POSITION_DATA>>,
+    pub extended_sys_state: Option<Timestamped<common::EXTENDED_SYS_STATE_DATA>>,
+}
+
+impl Vehicle {
+    pub fn new(system_id: u8, component_id: u8) -> Vehicle {
+        Vehicle {
+            system_id: system_id,
+            component_id: component_id,
+            heartbeat: None,
+            attitude: None,
+            attitude_quaternion: None,
+            global_position: None,
+            local_position: None,
+            gps: None,
+            sys_status: None,
+            batteries: BTreeMap::new(),
+            vfr_hud: None,
+            home_position: None,
+            extended_sys_state: None,
+        }
+    }
+
+    /// Update the state from a received message, returning whether it was used.
+    ///
+    /// Messages from other systems or components are ignored.
+    pub fn handle(&mut self, header: &Header, msg: &MavMessage) -> bool {
+        if header.system_id != self.system_id || header.component_id != self.component_id {
+            return false;
+        }
+
+        match *msg {
+            MavMessage::HEARTBEAT(ref m) => self.heartbeat = Some(Timestamped::now(m.clone())),
+            MavMessage::ATTITUDE(ref m) => self.attitude = Some(Timestamped::now(m.clone())),
+            MavMessage::ATTITUDE_QUATERNION(ref m) => {
+                self.attitude_quaternion = Some(Timestamped::now(m.clone()))
+            }
+            MavMessage::GLOBAL_POSITION_INT(ref m) => {
+                self.global_position = Some(Timestamped::now(m.clone()))
+            }
+            MavMessage::LOCAL_POSITION_NED(ref m) => {
+                self.local_position = Some(Timestamped::now(m.clone()))
+            }
+            MavMessage::GPS_RAW_INT(ref m) => self.gps = Some(Timestamped::now(m.clone())),
+            MavMessage::SYS_STATUS(ref m) => self.sys_status = Some(Timestamped::now(m.clone())),
+            MavMessage::BATTERY_STATUS(ref m) => {
+                self.batteries.insert(m.id, Timestamped::now(m.clone()));
+            }
+            MavMessage::VFR_HUD(ref m) => self.vfr_hud = Some(Timestamped::now(m.clone())),
+            MavMessage::HOME_POSITION(ref m) => {
+                self.home_position = Some(Timestamped::now(m.clone()))
+            }
+            MavMessage::EXTENDED_SYS_STATE(ref m) => {
+                self.extended_sys_state = Some(Timestamped::now(m.clone()))
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Receive a frame from the connection, update the state from it and return it.
+    pub fn recv(&mut self, conn: &MavConnection) -> io::Result<(Header, MavMessage)> {
+        let (header, msg) = try!(conn.recv_frame());
+        self.handle(&header, &msg);
+        Ok((header, msg))
+    }
+
+    /// Whether the last heartbeat reported the vehicle as armed.
+    pub fn armed(&self) -> Option<bool> {
+        self.heartbeat.as_ref().map(|h| {
+            h.value.base_mode & MAV_MODE_FLAG::MAV_MODE_FLAG_SAFETY_ARMED as u8 != 0
+        })
+    }
+
+    /// The autopilot specific mode from the last heartbeat.
+    pub fn custom_mode(&self) -> Option<u32> {
+        self.heartbeat.as_ref().map(|h| h.value.custom_mode)
+    }
+
+    pub fn landed_state(&self) -> Option<MAV_LANDED_STATE> {
+        self.extended_sys_state.as_ref()
+            .and_then(|s| MAV_LANDED_STATE::from_u32(s.value.landed_state as u32))
+    }
+
+    /// Latitude and longitude in degrees and altitude above mean sea level in meters.
+    pub fn position(&self) -> Option<(f64, f64, f32)> {
+        self.global_position.as_ref().map(|p| {
+            (p.value.lat as f64 / 1e7, p.value.lon as f64 / 1e7, p.value.alt as f32 / 1000.0)
+        })
+    }
+
+    /// Altitude above the home position in meters.
+    pub fn relative_alt(&self) -> Option<f32> {
+        self.global_position.as_ref().map(|p| p.value.relative_alt as f32 / 1000.0)
+    }
+
+    /// Altitude of the home position above mean sea level in meters, from `HOME_POSITION` or
+    /// else from the vehicle's absolute and relative altitude.
+    pub fn home_alt(&self) -> Option<f32> {
+        match (&self.home_position, &self.global_position) {
+            (&Some(ref home), _) => Some(home.value.altitude as f32 / 1000.0),
+            (&None, &Some(ref p)) => Some((p.value.alt - p.value.relative_alt) as f32 / 1000.0),
+            (&None, &None) => None,
+        }
+    }
+
+    /// Battery voltage in volts and remaining charge in percent, if reported.
+    pub fn battery(&self) -> Option<(f32, Option<u8>)> {
+        self.sys_status.as_ref().map(|s| {
+            let remaining = if s.value.battery_remaining < 0 {
+                None
+            } else {
+                Some(s.value.battery_remaining as u8)
+            };
+            (s.value.voltage_battery as f32 / 1000.0, remaining)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_vehicle {
+    use super::*;
+    use loopback::loopback;
+    use connection::MavConnection;
+
+    #[test]
+    pub fn test_vehicle_state() {
+        let (gcs, autopilot) = loopback();
+        let header = Header { sequence: 0, system_id: 1, component_id: 1 };
+        let mut vehicle = Vehicle::new(1, 1);
+        assert_eq!(vehicle.armed(), None);
+
+        autopilot.send_frame(header, &MavMessage::HEARTBEAT(common::HEARTBEAT_DATA {
+            custom_mode: 4,
+            mavtype: 2,
+            autopilot: 3,
+            base_mode: MAV_MODE_FLAG::MAV_MODE_FLAG_SAFETY_ARMED as u8 | 1,
+            system_status: 4,
+            mavlink_version: 3,
+        })).unwrap();
+        autopilot.send_frame(header, &MavMessage::GLOBAL_POSITION_INT(common::GLOBAL_POSITION_INT_DATA {
+            time_boot_ms: 1000,
+            lat: 473977419,
+            lon: 85455938,
+            alt: 488000,
+            relative_alt: 20000,
+            vx: 0,
+            vy: 0,
+            vz: 0,
+            hdg: 9000,
+        })).unwrap();
+        autopilot.send_frame(header, &MavMessage::EXTENDED_SYS_STATE(common::EXTENDED_SYS_STATE_DATA {
+            vtol_state: 0,
+            landed_state: MAV_LANDED_STATE::MAV_LANDED_STATE_IN_AIR as u8,
+        })).unwrap();
+        // A heartbeat from another component of the same system is not the vehicle's.
+        let camera = Header { component_id: 100, ..header };
+        autopilot.send_frame(camera, &::heartbeat_message()).unwrap();
+
+        for _ in 0..4 {
+            vehicle.recv(&gcs).unwrap();
+        }
+
+        assert_eq!(vehicle.armed(), Some(true));
+        assert_eq!(vehicle.custom_mode(), Some(4));
+        assert_eq!(vehicle.landed_state(), Some(MAV_LANDED_STATE::MAV_LANDED_STATE_IN_AIR));
+        assert_eq!(vehicle.position(), Some((47.3977419, 8.5455938, 488.0)));
+        assert_eq!(vehicle.relative_alt(), Some(20.0));
+        assert_eq!(vehicle.home_alt(), Some(468.0));
+        assert!(vehicle.attitude.is_none());
+        assert!(vehicle.global_position.unwrap().age() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,303 @@
+use common::MavMessage;
+use connection::{MavConnection, remaining};
+use {Header, RawFrame, read_raw, write_raw};
+
+use tungstenite;
+use tungstenite::Message;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::io;
+
+/// How often a listening connection checks for new peers.
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Read timeout of peer sockets, which bounds how long a reader thread holds a socket when a
+/// frame has only partly arrived.
+const READ_TIMEOUT_MS: u64 = 1;
+
+/// How long a new peer may take to complete the WebSocket handshake.
+const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+type Socket = tungstenite::WebSocket<TcpStream>;
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+fn is_timeout(e: &tungstenite::Error) -> bool {
+    match *e {
+        tungstenite::Error::Io(ref e) => {
+            e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+        }
+        _ => false,
+    }
+}
+
+struct Shared {
+    peers: Mutex<Vec<Arc<Mutex<Socket>>>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    /// Start reading binary messages from a newly connected peer.
+    ///
+    /// The reader thread waits for data on a clone of the stream and only locks the socket to
+    /// read once some has arrived, so that senders are not kept waiting by an idle peer.
+    fn add_peer(shared: &Arc<Shared>, socket: Socket, incoming: Sender<Vec<u8>>) -> io::Result<()> {
+        try!(socket.get_ref().set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))));
+        let probe = try!(socket.get_ref().try_clone());
+        let peer = Arc::new(Mutex::new(socket));
+        shared.peers.lock().unwrap().push(peer.clone());
+
+        let shared = shared.clone();
+        thread::spawn(move || {
+            while !shared.closed.load(Ordering::SeqCst) {
+                let msg = peer.lock().unwrap().read();
+                match msg {
+                    Ok(Message::Binary(data)) => {
+                        if incoming.send(data).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => (),
+                    Err(ref e) if is_timeout(e) => {
+                        // Nothing buffered; wait for more without holding the socket.
+                        while !shared.closed.load(Ordering::SeqCst) {
+                            match probe.peek(&mut [0]) {
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                                              e.kind() == io::ErrorKind::TimedOut => (),
+                                _ => break,
+                            }
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            shared.peers.lock().unwrap().retain(|p| !Arc::ptr_eq(p, &peer));
+        });
+        Ok(())
+    }
+}
+
+/// WebSocket MAVLink connection
+///
+/// Each MAVLink frame is carried in its own binary WebSocket message. A listening connection
+/// accepts any number of peers: messages from all of them are received, and sent messages are
+/// delivered to every peer currently connected.
+pub struct WebSocket {
+    shared: Arc<Shared>,
+    incoming: Mutex<Receiver<Vec<u8>>>,
+    sequence: Mutex<u8>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl WebSocket {
+    /// Connect to a WebSocket server, e.g. `ws("127.0.0.1:5760/mavlink")`.
+    pub fn ws(address: &str) -> io::Result<WebSocket> {
+        let (host, path) = match address.find('/') {
+            Some(i) => (&address[..i], &address[i..]),
+            None => (address, "/"),
+        };
+        let stream = try!(TcpStream::connect(host));
+        let url = format!("ws://{}{}", host, path);
+        let (socket, _) = try!(tungstenite::client(url, stream).map_err(|e| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string())
+        }));
+
+        let (tx, rx) = mpsc::channel();
+        let conn = WebSocket::new(rx, None);
+        try!(Shared::add_peer(&conn.shared, socket, tx));
+        Ok(conn)
+    }
+
+    /// Listen for WebSocket peers on the given address.
+    ///
+    /// Each peer completes its handshake on its own thread, so a peer that never does cannot
+    /// keep others from connecting.
+    pub fn wsin<T: ToSocketAddrs>(address: T) -> io::Result<WebSocket> {
+        let listener = try!(TcpListener::bind(address));
+        try!(listener.set_nonblocking(true));
+
+        let (tx, rx) = mpsc::channel();
+        let conn = WebSocket::new(rx, Some(try!(listener.local_addr())));
+        let shared = conn.shared.clone();
+        thread::spawn(move || {
+            while !shared.closed.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let shared = shared.clone();
+                        let tx = tx.clone();
+                        thread::spawn(move || {
+                            let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+                            if stream.set_nonblocking(false).is_err() ||
+                               stream.set_read_timeout(Some(timeout)).is_err() {
+                                return;
+                            }
+                            if let Ok(socket) = tungstenite::accept(stream) {
+                                Shared::add_peer(&shared, socket, tx).ok();
+                            }
+                        });
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(conn)
+    }
+
+    fn new(incoming: Receiver<Vec<u8>>, local_addr: Option<SocketAddr>) -> WebSocket {
+        WebSocket {
+            shared: Arc::new(Shared {
+                peers: Mutex::new(Vec::new()),
+                closed: AtomicBool::new(false),
+            }),
+            incoming: Mutex::new(incoming),
+            sequence: Mutex::new(0),
+            local_addr: local_addr,
+        }
+    }
+
+    /// The address a listening connection is bound to.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        for peer in self.shared.peers.lock().unwrap().iter() {
+            peer.lock().unwrap().close(None).ok();
+        }
+    }
+}
+
+impl MavConnection for WebSocket {
+    fn recv_raw(&self) -> io::Result<RawFrame> {
+        let incoming = self.incoming.lock().unwrap();
+        loop {
+            let data = try!(incoming.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket closed")
+            }));
+            if let Ok(frame) = read_raw(&mut &data[..]) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn recv_raw_timeout(&self, timeout: Duration) -> io::Result<RawFrame> {
+        let deadline = Instant::now() + timeout;
+        let incoming = self.incoming.lock().unwrap();
+        loop {
+            let data = try!(incoming.recv_timeout(try!(remaining(Some(deadline))).unwrap()).map_err(|e| {
+                match e {
+                    RecvTimeoutError::Timeout => {
+                        io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a frame")
+                    }
+                    RecvTimeoutError::Disconnected => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket closed")
+                    }
+                }
+            }));
+            if let Ok(frame) = read_raw(&mut &data[..]) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let header = Header {
+            sequence: *sequence,
+            system_id: 255,
+            component_id: 0,
+        };
+
+        *sequence = sequence.wrapping_add(1);
+
+        self.send_raw(&RawFrame::new(header, data))
+    }
+
+    fn send_raw(&self, frame: &RawFrame) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_raw(&mut buf, frame));
+
+        // A listening connection keeps serving the remaining peers when one of them fails; its
+        // reader thread will notice and drop it.
+        let peers = self.shared.peers.lock().unwrap().clone();
+        for peer in peers {
+            let res = peer.lock().unwrap().send(Message::Binary(buf.clone()));
+            if let Err(e) = res {
+                if self.local_addr.is_none() {
+                    return Err(ws_error(e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_websocket {
+    use super::*;
+    use common;
+
+    #[test]
+    pub fn test_client_server() {
+        let server = WebSocket::wsin("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = WebSocket::ws(&format!("{}/mavlink", addr)).unwrap();
+
+        client.send(&::heartbeat_message()).unwrap();
+        match server.recv().unwrap() {
+            common::MavMessage::HEARTBEAT(..) => (),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        server.send(&::request_parameters()).unwrap();
+        match client.recv().unwrap() {
+            common::MavMessage::PARAM_REQUEST_LIST(..) => (),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        drop(server);
+        assert_eq!(client.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    pub fn test_stalled_handshake() {
+        let server = WebSocket::wsin("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // A peer that connects but never sends its handshake does not hold up the next one.
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let client = WebSocket::ws(&format!("{}/mavlink", addr)).unwrap();
+        client.send(&::heartbeat_message()).unwrap();
+        match server.recv_frame_timeout(Duration::from_millis(1000)).unwrap().1 {
+            common::MavMessage::HEARTBEAT(..) => (),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // Sending goes through while the reader threads wait for data.
+        for _ in 0..100 {
+            server.send(&::request_parameters()).unwrap();
+        }
+        for _ in 0..100 {
+            client.recv_frame_timeout(Duration::from_millis(1000)).unwrap();
+        }
+    }
+}